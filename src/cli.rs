@@ -0,0 +1,82 @@
+use crate::config::{config_file_path, load_config, AppConfig};
+use crate::errors::GQGMCMQTTError;
+use clap::{Parser, Subcommand};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref ARGS: Cli = Cli::parse();
+}
+
+/// Command-line options. Each one given wins over the config file and the environment.
+#[derive(Parser, Debug)]
+#[command(version, about = "Publishes GQ GMC geiger counter readings to MQTT for Home Assistant.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file to read instead of $CONFIG_FILE_PATH or ./config.yaml.
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Replaces `serial_port` from the config file.
+    #[arg(long)]
+    pub serial_port: Option<String>,
+    /// Replaces `mqtt_server_addr`; has no effect when `mqtt_brokers` is set.
+    #[arg(long)]
+    pub mqtt_server: Option<String>,
+    /// Log filter such as `debug` or `gqgmcmqtt=trace`, instead of $RUST_LOG.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Publish a single poll, then exit.
+    #[arg(long, conflicts_with = "duration")]
+    pub once: bool,
+    /// Run for this many seconds, then shut down as on SIGTERM.
+    #[arg(long, value_name = "SECS")]
+    pub duration: Option<u64>,
+    /// Sets `purge_discovery_on_exit`.
+    #[arg(long)]
+    pub purge_discovery: bool,
+    /// Poll the unit as usual but print every message to stdout instead of publishing it.
+    /// No broker is needed.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Check the config file and summarise what it sets up.
+    Validate,
+    /// Poll the unit once and print a Lovelace card for its entities.
+    PrintLovelace,
+}
+
+impl Cli {
+    pub fn config_path(&self) -> String {
+        self.config.clone().unwrap_or_else(config_file_path)
+    }
+
+    /// The config file with the command-line overrides applied, checked as a whole.
+    pub fn load_config(&self) -> Result<AppConfig, GQGMCMQTTError> {
+        let mut config = load_config(&self.config_path())?;
+        if let Some(port) = &self.serial_port {
+            config.serial_port = Some(port.clone());
+        }
+        if let Some(server) = &self.mqtt_server {
+            config.mqtt_server_addr = server.clone();
+        }
+        if self.purge_discovery {
+            config.purge_discovery_on_exit = Some(true);
+        }
+        let mut problems = config.validate();
+        if !self.dry_run {
+            problems.extend(config.validate_brokers());
+        }
+        if !problems.is_empty() {
+            return Err(GQGMCMQTTError::Config(format!(
+                "{} has {} problem(s):\n  - {}",
+                self.config_path(),
+                problems.len(),
+                problems.join("\n  - ")
+            )));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,337 @@
+use crate::config::AppConfig;
+use crate::consts::{ALARM_CPM_MAX, ALARM_CPM_MIN, ALARM_CPM_POINT, NVM_MIN_WRITE_INTERVAL_SECS, POLL_NOW_POINT, RAW_COMMAND_POINT, RESET_PEAK_POINT, SWITCH_OFF, SWITCH_ON};
+use crate::device::{
+    get_alarm_cpm, log_interval_secs, read_config_byte, set_alarm_cpm, save_data_type_for, write_config_byte, ConfigNumber, ConfigSwitch,
+    GeigerDevice, RawResponse, CFG_SAVE_DATA_TYPE,
+};
+use crate::ipc::{IPCMessage, InboundMessage, PublishMessage};
+use crate::payload::{log_interval_state, number_state, switch_state, DeviceIdentity, Payload, StatePayload};
+use crate::poll_state::PollState;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+/// Spaces out NVM writes per point and caps them overall. Every config write erases and
+/// rewrites the whole config sector, so a flapping automation or a double-tap in the UI
+/// mustn't hammer it.
+#[derive(Default)]
+pub struct NvmGuard {
+    last_write: HashMap<String, Instant>,
+    recent: VecDeque<Instant>,
+    pub max_per_min: usize,
+}
+
+impl NvmGuard {
+    pub fn new(max_per_min: usize) -> Self {
+        NvmGuard { max_per_min, ..Default::default() }
+    }
+
+    /// Records the write and returns Ok if it may go ahead, otherwise why not.
+    pub fn allow(&mut self, point: &str) -> Result<(), String> {
+        let now = Instant::now();
+        if let Some(last) = self.last_write.get(point) {
+            if now.duration_since(*last) < Duration::from_secs(NVM_MIN_WRITE_INTERVAL_SECS) {
+                return Err(format!("last {point} write was under {NVM_MIN_WRITE_INTERVAL_SECS}s ago"));
+            }
+        }
+        while self.recent.front().is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(60)) {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.max_per_min {
+            return Err(format!("already {} config writes in the last minute", self.recent.len()));
+        }
+        self.recent.push_back(now);
+        self.last_write.insert(point.to_string(), now);
+        Ok(())
+    }
+}
+
+/// Pulls the serial and point name out of a `gqgmcmqtt/{serial}/{point}/set` topic.
+pub fn parse_command_topic(topic: &str, payload: &[u8]) -> Option<InboundMessage> {
+    let parts = topic.split('/').collect::<Vec<&str>>();
+    match parts.as_slice() {
+        ["gqgmcmqtt", serial, point, "set"] => Some(InboundMessage {
+            serial_number: serial.to_string(),
+            model: String::new(),
+            point_name: point.to_string(),
+            payload: String::from_utf8_lossy(payload).trim().to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Whether a command is for this unit. The command filter subscribes to every serial, so
+/// another gateway's unit on the same broker must not be driven from here.
+pub fn addressed_to(msg: &InboundMessage, identity: Option<&DeviceIdentity>) -> bool {
+    identity.is_some_and(|identity| identity.serial == msg.serial_number)
+}
+
+pub async fn handle_inbound<D: GeigerDevice>(
+    gmc: &mut D,
+    config: &AppConfig,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    state: &mut PollState,
+    msg: InboundMessage,
+) {
+    if let Some(switch) = ConfigSwitch::by_point(&msg.point_name) {
+        return handle_config_switch(gmc, mqtt_tx, guard, switch, msg).await;
+    }
+    if let Some(number) = ConfigNumber::by_point(&msg.point_name) {
+        return handle_config_number(gmc, mqtt_tx, guard, number, msg).await;
+    }
+    if msg.point_name == ALARM_CPM_POINT {
+        return handle_alarm_cpm(gmc, mqtt_tx, guard, msg).await;
+    }
+    if msg.point_name == "log_interval" && config.log_interval_control.unwrap_or(false) {
+        return handle_log_interval(gmc, mqtt_tx, guard, msg).await;
+    }
+    if msg.point_name == POLL_NOW_POINT {
+        // nothing to do here, every handled command is followed by a poll
+        debug!("Polling now as asked for over MQTT");
+        return;
+    }
+    if msg.point_name == RESET_PEAK_POINT {
+        // the poll that follows publishes the new peak
+        info!("Resetting peak CPM from {:?} as asked for over MQTT", state.peak_cpm);
+        state.peak_cpm = state.last_reading.map(|(cpm, _)| cpm);
+        return;
+    }
+    if msg.point_name == RAW_COMMAND_POINT && config.enable_raw_command.unwrap_or(false) {
+        return handle_raw_command(gmc, mqtt_tx, guard, msg).await;
+    }
+    warn!("Received command for unknown point {}", msg.point_name);
+}
+
+async fn handle_config_switch<D: GeigerDevice>(
+    gmc: &mut D,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    switch: &ConfigSwitch,
+    msg: InboundMessage,
+) {
+    let point = switch.point;
+    let on = match msg.payload.as_str() {
+        SWITCH_ON => true,
+        SWITCH_OFF => false,
+        other => {
+            warn!("Ignoring {point} command with unexpected payload {other:?}");
+            return;
+        }
+    };
+    match guard.allow(point) {
+        Ok(()) => {
+            if let Err(e) = switch.set(gmc, on).await {
+                error!("Couldn't set {point}: {e}");
+            }
+        }
+        Err(reason) => {
+            warn!("Ignoring {point} command, {reason}");
+        }
+    }
+    // Publish whatever the device reports now, not what was asked for, so the HA switch
+    // can't drift from the real setting if the write didn't stick or was refused.
+    match switch.get(gmc).await {
+        Ok(actual) => {
+            if actual != on {
+                warn!("Device reports {point} {actual} after setting it to {on}");
+            }
+            publish_state(mqtt_tx, format!("gqgmcmqtt/{}/{point}", msg.serial_number), switch_state(actual)).await;
+        }
+        Err(e) => {
+            error!("Couldn't read back {point} state: {e}");
+        }
+    }
+}
+
+async fn handle_config_number<D: GeigerDevice>(
+    gmc: &mut D,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    number: &ConfigNumber,
+    msg: InboundMessage,
+) {
+    let point = number.point;
+    let value = match msg.payload.parse::<f64>() {
+        Ok(v) if v >= number.min as f64 && v <= number.max as f64 => v as u8,
+        _ => {
+            warn!("Ignoring {point} command {:?}, expected {}-{}", msg.payload, number.min, number.max);
+            return;
+        }
+    };
+    match guard.allow(point) {
+        Ok(()) => {
+            if let Err(e) = number.set(gmc, value).await {
+                error!("Couldn't set {point}: {e}");
+            }
+        }
+        Err(reason) => {
+            warn!("Ignoring {point} command, {reason}");
+        }
+    }
+    match number.get(gmc).await {
+        Ok(actual) => {
+            if actual != value {
+                warn!("Device reports {point} {actual} after setting it to {value}");
+            }
+            publish_state(mqtt_tx, format!("gqgmcmqtt/{}/{point}", msg.serial_number), number_state(actual)).await;
+        }
+        Err(e) => {
+            error!("Couldn't read back {point}: {e}");
+        }
+    }
+}
+
+async fn handle_alarm_cpm<D: GeigerDevice>(
+    gmc: &mut D,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    msg: InboundMessage,
+) {
+    let Ok(requested) = msg.payload.parse::<f64>() else {
+        warn!("Ignoring {ALARM_CPM_POINT} command {:?}, expected a CPM", msg.payload);
+        return;
+    };
+    let value = requested.round().clamp(ALARM_CPM_MIN as f64, ALARM_CPM_MAX as f64) as u16;
+    if requested < ALARM_CPM_MIN as f64 || requested > ALARM_CPM_MAX as f64 {
+        warn!("{ALARM_CPM_POINT} {requested} is outside {ALARM_CPM_MIN}-{ALARM_CPM_MAX}, setting {value}");
+    }
+    match guard.allow(ALARM_CPM_POINT) {
+        Ok(()) => {
+            if let Err(e) = set_alarm_cpm(gmc, value).await {
+                error!("Couldn't set {ALARM_CPM_POINT}: {e}");
+            }
+        }
+        Err(reason) => {
+            warn!("Ignoring {ALARM_CPM_POINT} command, {reason}");
+        }
+    }
+    match get_alarm_cpm(gmc).await {
+        Ok(actual) => {
+            if actual != value {
+                warn!("Device reports {ALARM_CPM_POINT} {actual} after setting it to {value}");
+            }
+            publish_state(mqtt_tx, format!("gqgmcmqtt/{}/{ALARM_CPM_POINT}", msg.serial_number), number_state(actual)).await;
+        }
+        Err(e) => {
+            error!("Couldn't read back {ALARM_CPM_POINT}: {e}");
+        }
+    }
+}
+
+async fn handle_log_interval<D: GeigerDevice>(
+    gmc: &mut D,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    msg: InboundMessage,
+) {
+    // HA numbers send floats like "60.0" even with a step of 1
+    let value = match msg.payload.parse::<f64>().ok().and_then(|secs| save_data_type_for(secs as u64)) {
+        Some(v) => v,
+        None => {
+            warn!("Ignoring log_interval command {:?}, the unit only logs every 0, 1, 60 or 3600 seconds", msg.payload);
+            return;
+        }
+    };
+    match guard.allow("log_interval") {
+        Ok(()) => {
+            if let Err(e) = write_config_byte(gmc, CFG_SAVE_DATA_TYPE, value).await {
+                error!("Couldn't set log_interval: {e}");
+            }
+        }
+        Err(reason) => {
+            warn!("Ignoring log_interval command, {reason}");
+        }
+    }
+    match read_config_byte(gmc, CFG_SAVE_DATA_TYPE).await.map(log_interval_secs) {
+        Ok(Some(secs)) => {
+            publish_state(mqtt_tx, format!("gqgmcmqtt/{}/log_interval", msg.serial_number), log_interval_state(secs)).await;
+        }
+        Ok(None) => warn!("Device reports an unknown SaveDataType after setting log_interval"),
+        Err(e) => {
+            error!("Couldn't read back log_interval: {e}");
+        }
+    }
+}
+
+async fn handle_raw_command<D: GeigerDevice>(
+    gmc: &mut D,
+    mqtt_tx: &mpsc::Sender<IPCMessage>,
+    guard: &mut NvmGuard,
+    msg: InboundMessage,
+) {
+    // a raw command may well write NVM, so it's limited like one
+    if let Err(reason) = guard.allow(RAW_COMMAND_POINT) {
+        warn!("Ignoring raw command, {reason}");
+        return;
+    }
+    let command = if msg.payload.starts_with('<') {
+        msg.payload.clone()
+    } else {
+        format!("<{}>>", msg.payload)
+    };
+    warn!("Sending raw command {command} to the unit");
+    let reply = match gmc.raw_command(command.as_bytes()).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Raw command {command} failed: {e}");
+            return;
+        }
+    };
+    let response = RawResponse {
+        command,
+        hex: reply.iter().map(|b| format!("{b:02x}")).collect(),
+    };
+    if let Err(e) = mqtt_tx
+        .send(IPCMessage::Outbound(PublishMessage {
+            topic: format!("gqgmcmqtt/{}/raw_response", msg.serial_number),
+            payload: Payload::RawResponse(response),
+            retain: false,
+        }))
+        .await
+    {
+        error!("Couldn't queue raw response publish: {e}");
+    }
+}
+
+async fn publish_state(mqtt_tx: &mpsc::Sender<IPCMessage>, topic: String, state: StatePayload) {
+    if let Err(e) = mqtt_tx
+        .send(IPCMessage::Outbound(PublishMessage {
+            topic,
+            payload: Payload::CurrentState(state),
+            retain: false,
+        }))
+        .await
+    {
+        error!("Couldn't queue command state publish: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{MockDevice, CFG_SPEAKER};
+    use crate::payload::read_identity;
+
+    #[tokio::test]
+    async fn commands_reach_only_their_unit() {
+        let config = AppConfig::default();
+        let mut units = [MockDevice::default(), MockDevice::default()];
+        units[0].serial = "AAAA0001".to_string();
+        units[1].serial = "BBBB0002".to_string();
+        let mut states = [PollState::default(), PollState::default()];
+        for (gmc, state) in units.iter_mut().zip(states.iter_mut()) {
+            state.identity = Some(read_identity(gmc, &config).await.unwrap());
+        }
+        let (mqtt_tx, _mqtt_rx) = mpsc::channel(16);
+        let msg = parse_command_topic("gqgmcmqtt/BBBB0002/click_sound/set", SWITCH_ON.as_bytes()).unwrap();
+        for (gmc, state) in units.iter_mut().zip(states.iter_mut()) {
+            if addressed_to(&msg, state.identity.as_ref()) {
+                handle_inbound(gmc, &config, &mqtt_tx, &mut NvmGuard::new(10), state, msg.clone()).await;
+            }
+        }
+        assert_eq!(units[0].config[CFG_SPEAKER], 0);
+        assert_eq!(units[1].config[CFG_SPEAKER], 1);
+        assert!(!addressed_to(&msg, None));
+    }
+}
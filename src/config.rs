@@ -1,10 +1,1109 @@
+use crate::consts::{DEFAULT_COMMAND_ALLOWLIST, DEFAULT_DISCOVERY_PREFIX, DEFAULT_MAX_NVM_WRITES_PER_MIN, DEFAULT_POLL_INTERVAL_SECS, DEFAULT_SERIAL_BAUD, DEFAULT_SERIAL_PORT, DEFAULT_SERIAL_READ_TIMEOUT_MILLIS, DEFAULT_SERIAL_RECONNECT_AFTER, DEFAULT_STATE_PREFIX, EXPIRES_AFTER_POLLS, H10_PER_AIR_KERMA, HA_STATE_CLASSES, RAW_COMMAND_POINT};
+use crate::errors::GQGMCMQTTError;
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Shape of the message body published to each sensor's state topic.
+///
+/// `Nested` (the default) publishes the full `StatePayload` JSON and points HA at it with
+/// `value_template: {{ value_json.value }}`. `Flat` publishes only the bare value, so no
+/// template is needed and non-HA consumers can read it directly, but the `last_seen`
+/// timestamp and any label/notes are lost.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StateSchema {
+    #[default]
+    #[serde(alias = "json")]
+    Nested,
+    #[serde(alias = "raw")]
+    Flat,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// The reading in this unit, and its symbol.
+    pub fn from_celsius(self, celsius: f32) -> (f32, &'static str) {
+        match self {
+            TemperatureUnit::Celsius => (celsius, "°C"),
+            TemperatureUnit::Fahrenheit => (celsius * 9.0 / 5.0 + 32.0, "°F"),
+        }
+    }
+}
+
+/// Unit for the dose-rate sensor. A roentgen is taken as a rem, as usual for gamma, so
+/// 1 µSv/h reads as 100 µR/h.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DoseUnit {
+    #[default]
+    Microsievert,
+    Microroentgen,
+}
+
+impl DoseUnit {
+    /// The dose rate in this unit per hour, and its symbol.
+    pub fn from_usv(self, usv: f32) -> (f32, &'static str) {
+        match self {
+            DoseUnit::Microsievert => (usv, "µSv/h"),
+            DoseUnit::Microroentgen => (usv * 100.0, "µR/h"),
+        }
+    }
+}
+
+/// The quantity the dose-rate sensor reports. Both come from the same CPM-per-µSv/h
+/// factor, so both are approximate: the factor depends on the tube and is a Cs-137
+/// calibration, and a Geiger tube over- or under-reads at other gamma energies.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DoseQuantity {
+    /// Ambient dose equivalent H*(10), what official monitoring networks report. The
+    /// factor's µSv/h is taken as H*(10) as it stands.
+    AmbientEquivalent,
+    /// Absorbed dose in air: H*(10) over Cs-137's 1.20 Sv/Gy, in µGy/h (µrad/h with
+    /// `dose_unit: microroentgen`).
+    Absorbed,
+}
+
+impl DoseQuantity {
+    /// The dose rate from the factor's µSv/h in `unit`, and its symbol.
+    pub fn from_usv(self, usv: f32, unit: DoseUnit) -> (f32, &'static str) {
+        match (self, unit) {
+            (DoseQuantity::AmbientEquivalent, _) => unit.from_usv(usv),
+            (DoseQuantity::Absorbed, DoseUnit::Microsievert) => (usv / H10_PER_AIR_KERMA, "µGy/h"),
+            (DoseQuantity::Absorbed, DoseUnit::Microroentgen) => (usv / H10_PER_AIR_KERMA * 100.0, "µrad/h"),
+        }
+    }
+
+    /// The sensor's default name, saying which quantity it is.
+    pub fn sensor_name(self) -> &'static str {
+        match self {
+            DoseQuantity::AmbientEquivalent => "Dose Rate H*(10)",
+            DoseQuantity::Absorbed => "Absorbed Dose Rate",
+        }
+    }
+}
+
+/// Picks the units for every sensor with a choice of them; a sensor-specific unit
+/// setting such as `temperature_unit` still wins.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    #[default]
+    Si,
+    Us,
+}
+
+/// One unit in `devices`. Everything not set here comes from the top-level settings.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DeviceConfig {
+    pub serial_port: Option<String>,
+    pub serial_baud: Option<SerialBaud>,
+    /// Shown on the HA device card, to tell the units apart.
+    pub name: Option<String>,
+}
+
+/// One MQTT broker to publish to. Commands are accepted from all of them.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BrokerConfig {
+    pub addr: String,
+    pub port: Option<u16>,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<bool>,
+    /// PEM file of the CA that signed the broker's certificate; without one the
+    /// platform's root store is used.
+    pub ca_cert: Option<String>,
+    /// PEM files for brokers that want a client certificate; give both or neither.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub alpn: Option<Vec<String>>,
+}
+
+/// How the gateway reaches the unit.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Connection {
+    /// A serial port, or one of the special `serial_port` values (`mock`, `unix:`).
+    #[default]
+    Serial,
+    /// A network-attached unit at `device_addr`.
+    Tcp,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialParity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+/// How replies are delimited. Some firmware revisions append an 0xAA marker to every
+/// reply, which shifts each following read by a byte if it isn't consumed.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFraming {
+    /// Watch for a trailing marker after the first reply and settle on either profile.
+    #[default]
+    Auto,
+    Standard,
+    TrailingMarker,
+}
+
+/// Byte order of the 4-byte GETCPM/GETCPS replies of 500 and 600 series units.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpmEndianness {
+    /// Big-endian as documented, unless only the other order gives a believable reading.
+    #[default]
+    Auto,
+    Big,
+    Little,
+}
+
+/// `serial_baud`: a fixed rate, or `auto` to find the unit's rate when opening the port.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum SerialBaud {
+    Rate(u32),
+    Detect(BaudDetect),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BaudDetect {
+    Auto,
+}
+
+impl std::fmt::Display for SerialBaud {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialBaud::Rate(rate) => write!(f, "{rate}"),
+            SerialBaud::Detect(BaudDetect::Auto) => write!(f, "auto-detected"),
+        }
+    }
+}
+
+/// Character framing for the serial link; GMC units use 8N1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialFraming {
+    pub data_bits: u8,
+    pub parity: SerialParity,
+    pub stop_bits: u8,
+}
+
+impl std::fmt::Display for SerialFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parity = match self.parity {
+            SerialParity::None => 'N',
+            SerialParity::Odd => 'O',
+            SerialParity::Even => 'E',
+        };
+        write!(f, "{}{parity}{}", self.data_bits, self.stop_bits)
+    }
+}
+
+/// A daily local-time window, `HH:MM` on both ends. `start` after `end` means the window
+/// spans midnight (e.g. 22:00 to 07:00).
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    fn parse(&self) -> Result<(NaiveTime, NaiveTime), GQGMCMQTTError> {
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| {
+                GQGMCMQTTError::Config(format!("quiet_hours time {s:?} isn't HH:MM: {e}"))
+            })
+        };
+        Ok((parse(&self.start)?, parse(&self.end)?))
+    }
+
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        let Ok((start, end)) = self.parse() else {
+            return false;
+        };
+        if start <= end {
+            t >= start && t < end
+        } else {
+            t >= start || t < end
+        }
+    }
+}
+
+/// Per-sensor settings, keyed in `AppConfig::sensors` by the sensor's short name
+/// (`cpm`, `cph`, ...).
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SensorConfig {
+    pub enabled: Option<bool>,
+    /// Entity name shown in HA; it's prefixed with the device name, so keep it short.
+    pub name: Option<String>,
+    /// Replaces the HA `device_class` the gateway picks, passed through unchecked. Common
+    /// ones here are `temperature`, `signal_strength`, `voltage`, `irradiance` and, for binary
+    /// sensors, `problem` or `safety`.
+    pub device_class: Option<String>,
+    /// Seconds without an update before HA shows the sensor unavailable, at least the poll
+    /// interval; 0 for never.
+    pub expires_after: Option<u64>,
+    /// Decimal places HA rounds the value to in the UI, 0-6. Defaults to 0 for counts and
+    /// 1 for temperature and rates.
+    pub precision: Option<u8>,
+    /// Whether HA enables the entity when first discovered. `seconds_since_last_read`
+    /// starts disabled; everything else enabled.
+    pub enabled_by_default: Option<bool>,
+    /// Replaces the HA `state_class`: `measurement`, `total`, `total_increasing`, or empty
+    /// to keep the sensor out of long-term statistics. Readings default to `measurement`,
+    /// `gateway_uptime` to `total_increasing`, and text sensors have none.
+    pub state_class: Option<String>,
+}
 
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct AppConfig {
+    /// Optional once `mqtt_brokers` is set.
+    #[serde(default)]
     pub mqtt_server_addr: String,
     pub mqtt_server_port: Option<u16>,
     pub mqtt_client_id: Option<String>,
     pub mqtt_username: Option<String>,
     pub mqtt_password: Option<String>,
+    pub mqtt_tls: Option<bool>,
+    /// CA PEM for a broker with a private CA; otherwise the platform's roots are trusted.
+    pub mqtt_ca_cert: Option<String>,
+    /// Client certificate and key (PEM paths) for mutual TLS; give both or neither.
+    pub mqtt_client_cert: Option<String>,
+    pub mqtt_client_key: Option<String>,
+    /// ALPN protocols offered in the TLS handshake. The broker address is sent as SNI, so use
+    /// the hostname rather than an IP. For AWS IoT Core: the account's ATS endpoint as
+    /// mqtt_server_addr, port 443, `mqtt_alpn: [x-amzn-mqtt-ca]`, the Amazon root CA as
+    /// mqtt_ca_cert and the thing's certificate and key as the client cert and key.
+    pub mqtt_alpn: Option<Vec<String>>,
+    /// QoS (0, 1 or 2) for state and the other per-poll publishes; defaults to 1. The
+    /// gateway availability and its last will always go at 1.
+    pub mqtt_qos: Option<u8>,
+    /// QoS for discovery configs, kept apart so HA still gets those with
+    /// `mqtt_qos: 0`; defaults to 1.
+    pub mqtt_discovery_qos: Option<u8>,
+    /// MQTT protocol version for every broker: 4 (v3.1.1, the default) or 5. On v5 the
+    /// broker keeps the session for MQTT_SESSION_EXPIRY_SECS after the gateway drops off,
+    /// so commands sent during a short outage still arrive, and logs its reason codes.
+    pub mqtt_protocol_version: Option<u8>,
+    /// Publish to each of these brokers instead of the single `mqtt_server_*` one. One
+    /// broker being down doesn't hold up the others.
+    pub mqtt_brokers: Option<Vec<BrokerConfig>>,
+    /// Tag such as `prod` put into every topic, state and discovery alike, so gateways for
+    /// several environments can share a broker; see `topics::environment_topic`. Letters,
+    /// digits, `-` and `_` only.
+    pub environment: Option<String>,
+    /// Give every entity the unique_id `{prefix}_{serial}_{point}` and an entity_id to
+    /// match, e.g. `sensor.lab_{serial}_geiger_counter_cpm`, instead of the model-based ids. For
+    /// several identical units, or two gateways, sharing one HA. Changing it gives HA new
+    /// entities, leaving the old ones behind. Lowercase letters, digits and `_` only.
+    pub unique_id_prefix: Option<String>,
+    /// HA's `discovery_prefix`, if changed from `homeassistant`.
+    pub discovery_prefix: Option<String>,
+    /// Root of the gateway's own state and command topics; defaults to `gqgmcmqtt`.
+    pub state_prefix: Option<String>,
+    /// Randomize each broker reconnect delay by up to this percent either way (default 20).
+    /// The delay doubles up to 60s, and the jitter applies on top of that cap.
+    pub reconnect_jitter_pct: Option<u8>,
+    /// State topics whose newest value is kept while a broker is unreachable and published
+    /// as soon as it's back (default 64, 0 to drop them as before).
+    pub state_cache_size: Option<usize>,
+    pub connection: Option<Connection>,
+    /// `host:port` of the unit, required with `connection: tcp`.
+    pub device_addr: Option<String>,
+    /// Seconds from the start of one poll to the next; defaults to 5. A slow USB-serial
+    /// adapter may want 30 or so.
+    pub poll_interval_secs: Option<u64>,
+    /// Failed polls in a row before the unit is closed and reopened, for USB adapters that
+    /// renumerate; defaults to 3. Repeat reopens back off up to a minute apart.
+    pub serial_reconnect_after: Option<u32>,
+    /// Defaults to `/dev/ttyUSB0` (`COM3` on Windows).
+    pub serial_port: Option<String>,
+    /// A rate such as 57600 (the default) or 115200, or `auto` to try the usual ones.
+    pub serial_baud: Option<SerialBaud>,
+    /// Only needed for odd USB bridges or clone firmware; defaults to 8N1.
+    pub serial_data_bits: Option<u8>,
+    pub serial_parity: Option<SerialParity>,
+    pub serial_stop_bits: Option<u8>,
+    /// How long any one command may wait on the unit before it counts as a failed read;
+    /// defaults to 3000. A hung USB adapter otherwise stalls the poll loop for good.
+    pub serial_read_timeout_ms: Option<u64>,
+    /// Discard any unread input before each command during a poll, for adapters that leave
+    /// stale bytes behind and shift later readings. Adds a few ms per command.
+    pub flush_before_read: Option<bool>,
+    /// `auto`, `standard` or `trailing_marker`. Only network and socket connections detect
+    /// the marker; gqgmclib reads fixed-length replies, so on a serial port set
+    /// `trailing_marker` to have the marker flushed before each command instead.
+    pub response_framing: Option<ResponseFraming>,
+    /// `auto`, `big` or `little`, for firmware that sends the 4-byte CPM the wrong way
+    /// round and produces huge readings. Ignored on models with a 2-byte CPM.
+    pub cpm_endianness: Option<CpmEndianness>,
+    /// Send a throwaway GETVER before each poll, for units whose first reply after idling
+    /// is wrong.
+    pub wakeup_command: Option<bool>,
+    /// Publish every reply the unit sent during a poll, hex-encoded, to
+    /// `gqgmcmqtt/{serial}/diagnostics`. For debugging wrong readings; only available over
+    /// `unix:` and TCP connections, where the gateway does the parsing itself.
+    pub capture_raw: Option<bool>,
+    /// Publish the unit's whole config block, decoded where the layout is known, retained
+    /// to `gqgmcmqtt/{serial}/device_config`; see `DeviceConfigPayload`. Sent again
+    /// whenever it changes.
+    pub publish_device_config: Option<bool>,
+    /// Used in place of the unit's serial when it reports an empty one (seen on some clones).
+    pub device_id: Option<String>,
+    /// Minutes between re-reads of the unit's serial, to notice a swapped unit; also
+    /// re-read after any failed poll. Defaults to 60.
+    pub identity_recheck_mins: Option<u64>,
+    /// Shown on the HA device card in place of "GQ Electronics", for rebranded units.
+    pub device_manufacturer: Option<String>,
+    /// Shown on the HA device card in place of the unit's version string. Entity ids
+    /// still use the reported model so overriding this doesn't orphan existing entities.
+    pub device_model: Option<String>,
+    /// Shown on the HA device card in place of "GQ Geiger Counter", e.g. to tell units
+    /// apart by location. The device stays keyed on the serial, so renaming is safe.
+    pub device_name: Option<String>,
+    /// Area HA puts the device in when it's first discovered, e.g. `Basement`.
+    pub device_suggested_area: Option<String>,
+    /// Poll each of these units instead of the single `serial_port` one, each under its
+    /// own serial in the topics. With `state_file`, unit N keeps its history in
+    /// `{state_file}.N`.
+    pub devices: Option<Vec<DeviceConfig>>,
+    /// Extra YAML files merged over this one in order, later files winning. Relative paths
+    /// are resolved against the directory of this file.
+    pub include: Option<Vec<String>>,
+    /// `nested` (or `json`) by default, `flat` (or `raw`) for bare values; also read as
+    /// `state_encoding`.
+    #[serde(alias = "state_encoding")]
+    pub state_schema: Option<StateSchema>,
+    /// Set false to leave `last_seen` out of state payloads, so a steady value doesn't
+    /// produce a new recorder row on every poll.
+    pub publish_last_seen: Option<bool>,
+    /// Publish a retained unknown state to each reading's topic as soon as the unit is
+    /// identified, so HA shows it waiting for data instead of an old value.
+    pub publish_unknown_on_start: Option<bool>,
+    /// Publish unknown states as soon as a poll fails, so automations react right away
+    /// instead of after `expires_after`. Sent once per outage.
+    pub publish_unavailable_on_error: Option<bool>,
+    /// Also publish each reading as flat numeric JSON to `gqgmcmqtt/{serial}/grafana`,
+    /// e.g. `{"cpm":22,"ts":1700000000}`; see `GrafanaPayload` for the fields.
+    pub grafana_output: Option<bool>,
+    /// Also publish each reading to the shared `gqgmc/fleet/{serial}` topic; see
+    /// `FleetPayload`. The unit's position is included when known.
+    pub fleet_topic: Option<bool>,
+    /// A fixed position for the unit; ignored when `gps_device` is set.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// NMEA source for the position of a mobile unit, e.g. `/dev/ttyACM0`. Readings
+    /// go out without a position while there's no recent fix.
+    pub gps_device: Option<String>,
+    /// Publish the extra diagnostics of the GMC-500+/600+: a WiFi signal sensor, when the
+    /// unit answers for it, and a `device_tracker` at the unit's position, from its own GPS
+    /// or else `gps_device`/`latitude`. Off by default; units without them are skipped.
+    pub enable_extended_sensors: Option<bool>,
+    /// Attach the unit's position as `latitude`/`longitude` attributes of the CPM sensor.
+    pub location_attributes: Option<bool>,
+    /// Publish one CPM entity carrying CPS and the other readings (dose rate, voltage, CPH,
+    /// average CPM, corrected CPM, uncertainty, std dev, temperature, WiFi RSSI) as attributes instead
+    /// of an entity each. HA keeps no long-term statistics or history graph for attribute values, so
+    /// leave this off for anything you want charted. Entities announced before switching
+    /// it on stay in HA until deleted there.
+    pub primary_sensor_with_attributes: Option<bool>,
+    /// Publish every reading in one JSON document on `gqgmcmqtt/{serial}/state`, keyed by
+    /// sensor name, instead of a topic per reading. Switches and numbers keep their own
+    /// topics, since commands write their state back there.
+    pub combined_state: Option<bool>,
+    /// On a clean shutdown, send an empty retained config to every discovery topic published
+    /// since startup, so HA drops the entities instead of keeping ghosts of them around.
+    pub purge_discovery_on_exit: Option<bool>,
+    /// CPM per µSv/h for the `usv` dose-rate sensor; depends on the tube. Defaults to 151,
+    /// the M4011's.
+    pub usv_conversion_factor: Option<f32>,
+    /// The tube in the unit, listed by `publish_config_attributes`. Defaults to the one the
+    /// model ships with; set it for a unit that has had its tube swapped.
+    pub tube_type: Option<String>,
+    /// Publish the gateway's own uptime and (on Linux) memory use as diagnostic sensors.
+    pub publish_host_metrics: Option<bool>,
+    /// List the poll interval, reading corrections, conversion factor and tube type in use
+    /// as attributes of the CPM sensor's discovery config, to rule out a setting when a
+    /// reading looks off.
+    pub publish_config_attributes: Option<bool>,
+    /// Serve each cycle's readings as JSON to WebSocket clients on this port, for browser
+    /// dashboards without an MQTT client. Read once at startup.
+    pub websocket_port: Option<u16>,
+    /// Serve `/healthz` on this port for container liveness/readiness probes. Needs the
+    /// `health` build feature; read once at startup.
+    pub health_port: Option<u16>,
+    /// Serve Prometheus metrics (CPM, dose rate, poll errors) on `/metrics` at this port,
+    /// alongside MQTT. Needs the `metrics` build feature; read once at startup.
+    pub metrics_port: Option<u16>,
+    /// Publish a retained event to `gqgmcmqtt/discovered` the first time each unit is seen;
+    /// with `state_file` set, restarts remember which units were already announced.
+    pub announce_discovery: Option<bool>,
+    /// Milliseconds to wait before each discovery config on its first publish after
+    /// connecting, to spread the startup burst for HA on constrained hardware. Delays the
+    /// device's last entities by roughly this times the entity count. Defaults to 0.
+    pub discovery_publish_delay_ms: Option<u64>,
+    /// Remove the unit's HA entities once it hasn't answered for this many minutes, on the
+    /// assumption it was decommissioned. They come back if it answers again.
+    pub remove_after_mins: Option<u64>,
+    /// Give every entity its own availability topic, marked offline when that reading
+    /// goes stale, instead of leaving HA to show the last value of a failing sensor.
+    pub sensor_availability: Option<bool>,
+    /// Announce the flash logging interval as an editable number instead of a read-only
+    /// diagnostic sensor. Accepts 0 (off), 1, 60 or 3600 seconds.
+    pub log_interval_control: Option<bool>,
+    /// Command points (e.g. `click_sound`) the gateway will act on; defaults to the
+    /// standard set. Anything not listed is refused.
+    pub command_allowlist: Option<Vec<String>>,
+    /// Command points refused even when allowed above.
+    pub command_denylist: Option<Vec<String>>,
+    /// Accept `gqgmcmqtt/{serial}/raw_command/set` and send its payload (e.g. `GETVER`)
+    /// to the unit as-is, publishing the reply to `gqgmcmqtt/{serial}/raw_response`.
+    /// Dangerous: anyone who can publish to the broker can then erase or rewrite the
+    /// unit's config, wipe its log or power it off. Network and socket connections only.
+    pub enable_raw_command: Option<bool>,
+    /// Shared secret for signing the readings (nested-schema states and the combined,
+    /// Grafana and fleet payloads), for consumers that can't rely on TLS. See
+    /// `signing::sign_state` for the format.
+    pub hmac_secret: Option<String>,
+    /// How long to keep draining queued publishes after a shutdown signal (default 2000).
+    pub shutdown_grace_ms: Option<u64>,
+    /// A device read error that keeps repeating is logged at most once per this many
+    /// seconds (default 60), with a count of the repeats in between.
+    pub error_log_interval_secs: Option<u64>,
+    /// Also log to this file, rotated daily with the date appended, at the same level as
+    /// the console. If it can't be opened the gateway carries on logging to the console.
+    pub log_file: Option<String>,
+    /// Hand each poll cycle's publishes to the mqtt thread as one channel message.
+    pub batch_publish: Option<bool>,
+    /// Publishes bigger than this (default 256KB) have their attributes dropped, or are
+    /// skipped if that isn't enough, rather than being rejected by the broker.
+    pub max_payload_bytes: Option<usize>,
+    /// MQTT v5 message expiry for state publishes, so the broker drops readings once
+    /// they're stale; discovery configs never expire. Needs `mqtt_protocol_version: 5`, as
+    /// v3.1.1 has no message properties to carry it.
+    pub state_message_expiry_secs: Option<u32>,
+    /// Wait this long after opening the port before talking to the unit (default 0), for
+    /// firmware that isn't ready as soon as the USB-serial device enumerates.
+    pub startup_delay_ms: Option<u64>,
+    pub sensors: Option<HashMap<String, SensorConfig>>,
+    /// Shorthand for `sensors.<name>.precision`, e.g. `display_precision: { usv: 4 }`; a
+    /// precision set under `sensors` wins.
+    pub display_precision: Option<HashMap<String, u8>>,
+    /// Commands that write the unit's config are refused past this many per minute
+    /// (default 6), to spare its flash from a runaway automation.
+    pub max_nvm_writes_per_min: Option<usize>,
+    /// `expires_after` for every sensor that expires at all, unless one sets its own in
+    /// `sensors`. Defaults to three poll intervals, and is never below one.
+    #[serde(alias = "expires_after_secs")]
+    pub expires_after: Option<u64>,
+    /// Publish a rapid-increase binary sensor that turns on when a reading is at least this
+    /// many times the recent average.
+    #[serde(alias = "alert_on_ratio")]
+    pub alert_ratio: Option<f32>,
+    /// Once on, the alert only clears when a reading drops under this many times the
+    /// average, so it doesn't flap around the trigger. Must not exceed `alert_ratio`,
+    /// which it defaults to.
+    pub alert_off_ratio: Option<f32>,
+    /// Publish a radiation alarm binary sensor that turns on above this CPM. Unlike the
+    /// rapid-increase alert it ignores quiet hours.
+    pub alarm_threshold_cpm: Option<u32>,
+    /// Readings in a row needed on the other side of `alarm_threshold_cpm` before the
+    /// alarm changes, so one noisy sample doesn't flap it; defaults to 2.
+    pub alarm_debounce_polls: Option<u32>,
+    /// Check CPS every second between polls and publish to `gqgmcmqtt/{serial}/spike_alert`
+    /// straight away once a sample reaches this. An alert reaches the broker within about a
+    /// second of the sample rather than at the next poll. There's no streaming mode, so
+    /// this needs a unit whose transport answers GETCPS (network and socket connections).
+    pub spike_alert_cps: Option<u32>,
+    /// Replace each CPM reading with the median of the last this-many readings (default 0,
+    /// off) before anything else uses it, so one corrupt read can't spike the averages or
+    /// trip the alert. A real step change then shows up about half a window late.
+    pub median_filter_window: Option<usize>,
+    /// Publish a diagnostic `geiger_counter_frozen` binary sensor that turns on once this
+    /// many raw CPM readings in a row are identical, as from a unit whose firmware has
+    /// stuck. Off by default. It's a heuristic: a quiet enough spot can repeat a low count
+    /// now and then, so pick a window a real background wouldn't hold steady through.
+    pub freeze_detect_samples: Option<usize>,
+    /// Publish `geiger_counter_cpm_avg`, the mean of the last this-many readings, for a
+    /// steadier line than raw CPM. Averages what it has until the window fills.
+    pub cpm_average_window: Option<usize>,
+    /// Good readings needed in a row after any failed poll before the corrected CPM,
+    /// standard deviation and rapid-increase sensors publish again and the baseline takes
+    /// readings. Defaults to 1, i.e. straight away.
+    pub min_valid_samples: Option<u32>,
+    /// Add the reading's Poisson uncertainty (sqrt of the count) to the CPM sensor's
+    /// attributes; the `cpm_uncertainty` sensor publishes it as an entity of its own.
+    pub cpm_uncertainty_attribute: Option<bool>,
+    /// File to keep the reading histories and last reading in across restarts, so the
+    /// baseline, median filter and average carry on where they left off instead of warming
+    /// up again. Written every few minutes and at shutdown.
+    #[serde(alias = "state_path")]
+    pub state_file: Option<String>,
+    /// At startup, read the unit's history log from flash and publish the CPM it logged
+    /// since the last reading in `state_file` (or the last day) before polling, to fill
+    /// the gap while the gateway was down. Needs a log kept per minute or per hour.
+    pub import_history: Option<bool>,
+    /// For this long after the first reading (default 0), the CPM sensor carries a
+    /// `settling: true` attribute so automations can ignore a tube that's still warming up.
+    pub settle_time_secs: Option<u64>,
+    /// Tube dead time in microseconds; when set, a `cpm_corrected` sensor publishes the CPM
+    /// with the non-paralyzable dead-time correction applied.
+    pub dead_time_us: Option<f64>,
+    /// `si` (default) or `us`: µSv/h and °C, or µR/h and °F, for the sensors with a
+    /// choice of units. `dose_unit` and `temperature_unit` still win.
+    pub unit_system: Option<UnitSystem>,
+    /// Unit for the `usv` dose-rate sensor, `microsievert` or `microroentgen`; defaults to
+    /// microsievert, or microroentgen with `unit_system: us`.
+    pub dose_unit: Option<DoseUnit>,
+    /// `ambient_equivalent` or `absorbed`, to report and label the `usv` sensor as H*(10)
+    /// or absorbed dose in air. Unset keeps the plain conversion, the same number as
+    /// `ambient_equivalent` under the name "Dose Rate". Either way it's approximate; see
+    /// `DoseQuantity`.
+    pub dose_quantity: Option<DoseQuantity>,
+    /// Unit for the temperature sensor, for models that have one; defaults to celsius, or
+    /// fahrenheit with `unit_system: us`.
+    pub temperature_unit: Option<TemperatureUnit>,
+    /// Shorthand for `sensors.temperature.enabled`, which wins when both are set. Off by
+    /// default, since models without the sensor answer GETTEMP with garbage.
+    pub enable_temperature: Option<bool>,
+    /// While inside this window the rapid-increase alert stays off; readings still publish.
+    pub quiet_hours: Option<QuietHours>,
+    /// IANA zone name (e.g. `Europe/Berlin`) for time-of-day features; defaults to the
+    /// host's local time.
+    pub timezone: Option<String>,
+    /// Set the unit's clock to the current time in `timezone` at startup, and again once
+    /// a day after that.
+    pub sync_clock_on_start: Option<bool>,
+}
+
+impl AppConfig {
+    pub fn serial_port(&self) -> String {
+        self.serial_port.clone().unwrap_or(DEFAULT_SERIAL_PORT.to_string())
+    }
+
+    /// The fixed rate to open the port at; `auto` is handled when opening the port.
+    pub fn serial_baud(&self) -> u32 {
+        match self.serial_baud {
+            Some(SerialBaud::Rate(rate)) => rate,
+            _ => DEFAULT_SERIAL_BAUD,
+        }
+    }
+
+    pub fn serial_baud_setting(&self) -> SerialBaud {
+        self.serial_baud.unwrap_or(SerialBaud::Rate(DEFAULT_SERIAL_BAUD))
+    }
+
+    pub fn serial_framing(&self) -> SerialFraming {
+        SerialFraming {
+            data_bits: self.serial_data_bits.unwrap_or(8),
+            parity: self.serial_parity.unwrap_or_default(),
+            stop_bits: self.serial_stop_bits.unwrap_or(1),
+        }
+    }
+
+    /// The configured brokers, or the `mqtt_server_*` settings as a one-element list.
+    pub fn brokers(&self) -> Vec<BrokerConfig> {
+        match &self.mqtt_brokers {
+            Some(brokers) if !brokers.is_empty() => brokers.clone(),
+            _ => vec![BrokerConfig {
+                addr: self.mqtt_server_addr.clone(),
+                port: self.mqtt_server_port,
+                client_id: self.mqtt_client_id.clone(),
+                username: self.mqtt_username.clone(),
+                password: self.mqtt_password.clone(),
+                tls: self.mqtt_tls,
+                ca_cert: self.mqtt_ca_cert.clone(),
+                client_cert: self.mqtt_client_cert.clone(),
+                client_key: self.mqtt_client_key.clone(),
+                alpn: self.mqtt_alpn.clone(),
+            }],
+        }
+    }
+
+    pub fn mqtt_protocol_version(&self) -> u8 {
+        self.mqtt_protocol_version.unwrap_or(4)
+    }
+
+    /// One config per unit to poll: these settings with each `devices` entry laid over
+    /// them, or just these settings as a one-element list.
+    pub fn devices(&self) -> Vec<AppConfig> {
+        let devices = match &self.devices {
+            Some(devices) if !devices.is_empty() => devices,
+            _ => return vec![self.clone()],
+        };
+        devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| {
+                let mut config = self.clone();
+                config.devices = None;
+                config.serial_port = device.serial_port.clone().or(config.serial_port);
+                config.serial_baud = device.serial_baud.or(config.serial_baud);
+                config.device_name = device.name.clone().or(config.device_name);
+                config.state_file = self.state_file.as_ref().map(|path| format!("{path}.{index}"));
+                config
+            })
+            .collect()
+    }
+
+    pub fn discovery_prefix(&self) -> String {
+        self.discovery_prefix.clone().unwrap_or(DEFAULT_DISCOVERY_PREFIX.to_string())
+    }
+
+    pub fn state_prefix(&self) -> String {
+        self.state_prefix.clone().unwrap_or(DEFAULT_STATE_PREFIX.to_string())
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs.unwrap_or(DEFAULT_POLL_INTERVAL_SECS))
+    }
+
+    /// Seconds a sensor may go without an update, for `secs` from a sensor's own setting or
+    /// None for the gateway-wide one. Raised to the poll interval, short of which every
+    /// entity would flap unavailable between polls; 0 stays 0, which never expires.
+    pub fn expires_after(&self, secs: Option<u64>) -> u64 {
+        let interval = self.poll_interval().as_secs();
+        match secs.or(self.expires_after).unwrap_or(interval * EXPIRES_AFTER_POLLS) {
+            0 => 0,
+            secs => secs.max(interval),
+        }
+    }
+
+    pub fn serial_reconnect_after(&self) -> u32 {
+        self.serial_reconnect_after.unwrap_or(DEFAULT_SERIAL_RECONNECT_AFTER).max(1)
+    }
+
+    pub fn max_nvm_writes_per_min(&self) -> usize {
+        self.max_nvm_writes_per_min.unwrap_or(DEFAULT_MAX_NVM_WRITES_PER_MIN)
+    }
+
+    pub fn connection(&self) -> Connection {
+        self.connection.unwrap_or_default()
+    }
+
+    pub fn temperature_enabled(&self) -> bool {
+        self.sensor_enabled("temperature", self.enable_temperature.unwrap_or(false))
+    }
+
+    pub fn temperature_unit(&self) -> TemperatureUnit {
+        match (self.temperature_unit, self.unit_system.unwrap_or_default()) {
+            (Some(unit), _) => unit,
+            (None, UnitSystem::Si) => TemperatureUnit::Celsius,
+            (None, UnitSystem::Us) => TemperatureUnit::Fahrenheit,
+        }
+    }
+
+    pub fn dose_unit(&self) -> DoseUnit {
+        match (self.dose_unit, self.unit_system.unwrap_or_default()) {
+            (Some(unit), _) => unit,
+            (None, UnitSystem::Si) => DoseUnit::Microsievert,
+            (None, UnitSystem::Us) => DoseUnit::Microroentgen,
+        }
+    }
+
+    pub fn response_framing(&self) -> ResponseFraming {
+        self.response_framing.unwrap_or_default()
+    }
+
+    pub fn serial_read_timeout(&self) -> Duration {
+        Duration::from_millis(self.serial_read_timeout_ms.unwrap_or(DEFAULT_SERIAL_READ_TIMEOUT_MILLIS))
+    }
+
+    /// Explicitly, or because the marker a serial unit leaves behind has to go somewhere.
+    pub fn flush_before_read(&self) -> bool {
+        self.flush_before_read.unwrap_or(false) || self.response_framing() == ResponseFraming::TrailingMarker
+    }
+
+    /// Settings read once at startup that differ in `other`, so a reload can say they
+    /// need a restart instead of quietly ignoring them.
+    pub fn restart_required(&self, other: &AppConfig) -> Vec<&'static str> {
+        let changed = [
+            ("MQTT broker settings", self.brokers() != other.brokers()),
+            ("mqtt_protocol_version", self.mqtt_protocol_version() != other.mqtt_protocol_version()),
+            ("devices", self.devices().len() != other.devices().len()),
+            ("environment", self.environment != other.environment),
+            ("discovery_prefix", self.discovery_prefix() != other.discovery_prefix()),
+            ("state_prefix", self.state_prefix() != other.state_prefix()),
+            ("unique_id_prefix", self.unique_id_prefix != other.unique_id_prefix),
+            ("websocket_port", self.websocket_port != other.websocket_port),
+            ("health_port", self.health_port != other.health_port),
+            ("metrics_port", self.metrics_port != other.metrics_port),
+            ("gps_device", self.gps_device != other.gps_device),
+            ("log_file", self.log_file != other.log_file),
+        ];
+        changed.into_iter().filter(|(_, c)| *c).map(|(key, _)| key).collect()
+    }
+
+    /// True when `other` would need the serial port reopened to take effect.
+    pub fn serial_changed(&self, other: &AppConfig) -> bool {
+        self.connection() != other.connection()
+            || self.device_addr != other.device_addr
+            || self.serial_port() != other.serial_port()
+            || self.serial_baud_setting() != other.serial_baud_setting()
+            || self.serial_framing() != other.serial_framing()
+            || self.response_framing() != other.response_framing()
+    }
+
+    pub fn local_time(&self) -> NaiveTime {
+        self.local_now().time()
+    }
+
+    /// The current time in `timezone`, or the host's zone.
+    pub fn local_now(&self) -> NaiveDateTime {
+        match self.timezone.as_ref().and_then(|tz| tz.parse::<Tz>().ok()) {
+            Some(tz) => Utc::now().with_timezone(&tz).naive_local(),
+            None => Local::now().naive_local(),
+        }
+    }
+
+    /// A time read off the unit's clock, which keeps `timezone` (or the host's zone) once
+    /// synced, as UTC. None for a time the zone skips.
+    pub fn unit_time_to_utc(&self, at: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self.timezone.as_ref().and_then(|tz| tz.parse::<Tz>().ok()) {
+            Some(tz) => tz.from_local_datetime(&at).earliest().map(|t| t.with_timezone(&Utc)),
+            None => Local.from_local_datetime(&at).earliest().map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    pub fn command_allowed(&self, point: &str) -> bool {
+        let allowed = match &self.command_allowlist {
+            Some(list) => list.iter().any(|p| p == point),
+            None => {
+                DEFAULT_COMMAND_ALLOWLIST.contains(&point)
+                    || (point == RAW_COMMAND_POINT && self.enable_raw_command.unwrap_or(false))
+            }
+        };
+        allowed && !self.command_denylist.as_ref().is_some_and(|list| list.iter().any(|p| p == point))
+    }
+
+    pub fn in_quiet_hours(&self) -> bool {
+        match &self.quiet_hours {
+            Some(q) => q.contains(self.local_time()),
+            None => false,
+        }
+    }
+
+    fn check_time_settings(&self, problems: &mut Vec<String>) {
+        if let Some(tz) = &self.timezone {
+            if tz.parse::<Tz>().is_err() {
+                problems.push(format!("timezone {tz:?} isn't a known IANA zone"));
+            }
+        }
+        if let Some(Err(GQGMCMQTTError::Config(msg))) = self.quiet_hours.as_ref().map(|q| q.parse()) {
+            problems.push(msg);
+        }
+    }
+
+    /// The broker settings, kept apart from `validate` so `--dry-run` can skip them.
+    pub fn validate_brokers(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for broker in self.brokers() {
+            if broker.addr.is_empty() {
+                problems.push("every MQTT broker needs an address (mqtt_server_addr, or addr in mqtt_brokers)".to_string());
+                continue;
+            }
+            if broker.port == Some(0) {
+                problems.push(format!("broker {}: port must be 1-65535", broker.addr));
+            }
+            if broker.client_cert.is_some() != broker.client_key.is_some() {
+                problems.push(format!("broker {}: a TLS client cert and key must be given together", broker.addr));
+            }
+            if broker.alpn.is_some() && !broker.tls.unwrap_or(false) {
+                problems.push(format!("broker {}: ALPN only applies with TLS", broker.addr));
+            }
+            if broker.tls.unwrap_or(false) && broker.port == Some(443) && broker.alpn.is_none() {
+                // MQTT on 443 is multiplexed with HTTPS by ALPN, so brokers like AWS IoT reject it without
+                warn!("broker {}: TLS on port 443 usually needs an ALPN protocol, e.g. x-amzn-mqtt-ca", broker.addr);
+            }
+        }
+        problems
+    }
+
+    /// Checks the settings serde can't, e.g. value ranges and time zone names. Every
+    /// problem is listed, so a bad config can be fixed in one go.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.poll_interval_secs == Some(0) {
+            problems.push("poll_interval_secs must be at least 1".to_string());
+        }
+        let interval = self.poll_interval().as_secs();
+        let sensor_expiries = self.sensors.iter().flatten().map(|(name, s)| (format!("sensors.{name}.expires_after"), s.expires_after));
+        for (key, secs) in std::iter::once(("expires_after".to_string(), self.expires_after)).chain(sensor_expiries) {
+            if let Some(secs) = secs.filter(|s| *s != 0 && *s < interval) {
+                warn!("{key} {secs}s is shorter than the {interval}s poll interval; using {interval}s.");
+            }
+        }
+        let ports = self.devices().iter().map(|d| d.serial_port()).collect::<Vec<String>>();
+        if let Some(port) = ports.iter().enumerate().find_map(|(i, p)| ports[..i].contains(p).then_some(p)) {
+            problems.push(format!("devices: {port} is listed more than once"));
+        }
+        for (key, port) in [("websocket_port", self.websocket_port), ("health_port", self.health_port), ("metrics_port", self.metrics_port)] {
+            if port == Some(0) {
+                problems.push(format!("{key} must be 1-65535"));
+            }
+        }
+        for (key, qos) in [("mqtt_qos", self.mqtt_qos), ("mqtt_discovery_qos", self.mqtt_discovery_qos)] {
+            if qos.is_some_and(|q| q > 2) {
+                problems.push(format!("{key} must be 0, 1 or 2"));
+            }
+        }
+        if self.serial_read_timeout_ms == Some(0) {
+            problems.push("serial_read_timeout_ms must be at least 1".to_string());
+        }
+        if self.mqtt_protocol_version.is_some_and(|v| v != 4 && v != 5) {
+            problems.push("mqtt_protocol_version must be 4 or 5".to_string());
+        }
+        if self.freeze_detect_samples.is_some_and(|n| n < 2) {
+            problems.push("freeze_detect_samples must be at least 2".to_string());
+        }
+        if self.cpm_average_window == Some(0) {
+            problems.push("cpm_average_window must be at least 1".to_string());
+        }
+        if self.usv_conversion_factor.is_some_and(|f| f <= 0.0) {
+            problems.push("usv_conversion_factor must be above 0".to_string());
+        }
+        if let (Some(on), Some(off)) = (self.alert_ratio, self.alert_off_ratio) {
+            if off > on {
+                problems.push(format!("alert_off_ratio ({off}) must not be above alert_ratio ({on})"));
+            }
+        }
+        for (name, precision) in self.display_precision.iter().flatten() {
+            if *precision > 6 {
+                problems.push(format!("display_precision.{name} must be 0-6"));
+            }
+        }
+        for (name, sensor) in self.sensors.iter().flatten() {
+            if sensor.precision.is_some_and(|p| p > 6) {
+                problems.push(format!("sensors.{name}.precision must be 0-6"));
+            }
+            if sensor.state_class.as_deref().is_some_and(|c| !c.is_empty() && !HA_STATE_CLASSES.contains(&c)) {
+                problems.push(format!(
+                    "sensors.{name}.state_class must be one of {} or empty",
+                    HA_STATE_CLASSES.join(", ")
+                ));
+            }
+        }
+        for (key, prefix) in [("discovery_prefix", &self.discovery_prefix), ("state_prefix", &self.state_prefix)] {
+            if let Some(prefix) = prefix {
+                if prefix.is_empty() || prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(['+', '#']) {
+                    problems.push(format!("{key} {prefix:?} must be a topic without wildcards or leading/trailing '/'"));
+                }
+            }
+        }
+        if let Some(env) = &self.environment {
+            if !env.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                problems.push(format!("environment {env:?} may only contain letters, digits, '-' and '_'"));
+            }
+        }
+        if let Some(prefix) = &self.unique_id_prefix {
+            if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+                problems.push(format!("unique_id_prefix {prefix:?} may only contain lowercase letters, digits and '_'"));
+            }
+        }
+        if self.latitude.is_some_and(|lat| !(-90.0..=90.0).contains(&lat))
+            || self.longitude.is_some_and(|lon| !(-180.0..=180.0).contains(&lon))
+        {
+            problems.push("latitude must be within ±90 and longitude within ±180".to_string());
+        }
+        self.check_connection(&mut problems);
+        self.check_serial_framing(&mut problems);
+        self.check_time_settings(&mut problems);
+        problems
+    }
+
+    fn check_connection(&self, problems: &mut Vec<String>) {
+        if self.connection() == Connection::Tcp && self.device_addr.is_none() {
+            problems.push("connection: tcp needs a device_addr".to_string());
+        }
+    }
+
+    fn check_serial_framing(&self, problems: &mut Vec<String>) {
+        let framing = self.serial_framing();
+        if !(5..=8).contains(&framing.data_bits) {
+            problems.push(format!("serial_data_bits must be 5-8, got {}", framing.data_bits));
+        }
+        if !(1..=2).contains(&framing.stop_bits) {
+            problems.push(format!("serial_stop_bits must be 1 or 2, got {}", framing.stop_bits));
+        }
+        if framing.data_bits < 8 {
+            // the GQ protocol returns binary values, which can't survive a 7-bit link
+            warn!("serial framing {framing} has fewer than 8 data bits, binary readings will likely be corrupted");
+        }
+    }
+
+    pub fn sensor(&self, name: &str) -> SensorConfig {
+        let mut sensor = self.sensors
+            .as_ref()
+            .and_then(|s| s.get(name))
+            .cloned()
+            .unwrap_or_default();
+        if sensor.precision.is_none() {
+            sensor.precision = self.display_precision.as_ref().and_then(|p| p.get(name)).copied();
+        }
+        sensor
+    }
+
+    pub fn sensor_enabled(&self, name: &str, default: bool) -> bool {
+        self.sensor(name).enabled.unwrap_or(default)
+    }
+
+    pub fn sensor_name(&self, name: &str, default: &str) -> String {
+        self.sensor(name).name.unwrap_or(default.to_string())
+    }
+}
+
+pub fn config_file_path() -> String {
+    match std::env::var("CONFIG_FILE_PATH") {
+        Ok(s) => s,
+        Err(_e) => "./config.yaml".to_string(),
+    }
+}
+
+/// Prefix of the environment variables that override config fields, e.g.
+/// `GQGMC_POLL_INTERVAL_SECS`. Nested fields join their keys with `__`, as in
+/// `GQGMC_SENSORS__CPM__NAME`.
+const ENV_PREFIX: &str = "GQGMC";
+
+/// Reads and merges the config files, then overlays any `GQGMC_` environment variables.
+/// The file may be missing when the environment is setting fields. Checking the result
+/// with `validate` is left to the caller, since command-line overrides can still change it.
+pub fn load_config(path: &str) -> Result<AppConfig, GQGMCMQTTError> {
+    let env_set = std::env::vars().any(|(k, _)| k.starts_with(&format!("{ENV_PREFIX}_")));
+    let mut merged = if env_set && !Path::new(path).exists() {
+        Value::Mapping(Default::default())
+    } else {
+        read_yaml(Path::new(path))?
+    };
+    let includes: Vec<String> = match merged.get("include") {
+        Some(v) => serde_yaml::from_value(v.clone()).map_err(|e| {
+            GQGMCMQTTError::Config(format!("`include` in {path} must be a list of file paths: {e}"))
+        })?,
+        None => vec![],
+    };
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let layer = read_yaml(&base_dir.join(&include))?;
+        merge_yaml(&mut merged, layer);
+    }
+    // the config crate does the env layer since it coerces between strings and numbers,
+    // which an env var can't say which it is
+    let file = serde_yaml::to_string(&merged)
+        .map_err(|e| GQGMCMQTTError::Config(format!("Couldn't re-serialize merged config: {e}")))?;
+    let config: AppConfig = ::config::Config::builder()
+        .add_source(::config::File::from_str(&file, ::config::FileFormat::Yaml))
+        .add_source(
+            ::config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                .try_parsing(true),
+        )
+        .build()
+        .and_then(|c| c.try_deserialize())
+        .map_err(|e| GQGMCMQTTError::Config(format!("Couldn't deserialize AppConfig: {e}")))?;
+    Ok(config)
+}
+
+fn read_yaml(path: &Path) -> Result<Value, GQGMCMQTTError> {
+    let yaml = fs::read_to_string(path).map_err(|e| {
+        GQGMCMQTTError::Config(format!("Can't read config file {}: {e}", path.display()))
+    })?;
+    serde_yaml::from_str(&yaml).map_err(|e| {
+        GQGMCMQTTError::Config(format!("Can't parse config file {}: {e}", path.display()))
+    })
+}
+
+/// Recursively overlays `layer` onto `base`; nested mappings merge key-by-key, anything
+/// else is replaced wholesale.
+fn merge_yaml(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Mapping(base_map), Value::Mapping(layer_map)) => {
+            for (k, v) in layer_map {
+                match base_map.get_mut(&k) {
+                    Some(existing) => merge_yaml(existing, v),
+                    None => {
+                        base_map.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn si_units_by_default() {
+        let config = AppConfig::default();
+        assert_eq!(config.dose_unit().from_usv(0.12), (0.12, "µSv/h"));
+        assert_eq!(config.temperature_unit().from_celsius(20.0), (20.0, "°C"));
+    }
+
+    #[test]
+    fn us_units_convert() {
+        let config = AppConfig { unit_system: Some(UnitSystem::Us), ..Default::default() };
+        let (dose, uom) = config.dose_unit().from_usv(0.12);
+        assert!((dose - 12.0).abs() < 1e-4);
+        assert_eq!(uom, "µR/h");
+        assert_eq!(config.temperature_unit().from_celsius(20.0), (68.0, "°F"));
+    }
+
+    #[test]
+    fn absorbed_dose_converts_from_h10() {
+        let (gray, uom) = DoseQuantity::Absorbed.from_usv(0.12, DoseUnit::Microsievert);
+        assert!((gray - 0.1).abs() < 1e-6);
+        assert_eq!(uom, "µGy/h");
+        let (rad, uom) = DoseQuantity::Absorbed.from_usv(0.12, DoseUnit::Microroentgen);
+        assert!((rad - 10.0).abs() < 1e-4);
+        assert_eq!(uom, "µrad/h");
+        assert_eq!(DoseQuantity::AmbientEquivalent.from_usv(0.12, DoseUnit::Microsievert), (0.12, "µSv/h"));
+    }
+
+    #[test]
+    fn sensor_units_win_over_the_system() {
+        let config = AppConfig {
+            unit_system: Some(UnitSystem::Us),
+            dose_unit: Some(DoseUnit::Microsievert),
+            temperature_unit: Some(TemperatureUnit::Celsius),
+            ..Default::default()
+        };
+        assert_eq!(config.dose_unit(), DoseUnit::Microsievert);
+        assert_eq!(config.temperature_unit(), TemperatureUnit::Celsius);
+    }
+
+    #[test]
+    fn expires_after_per_sensor() {
+        let config = AppConfig {
+            poll_interval_secs: Some(60),
+            expires_after: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(config.expires_after(None), 600);
+        assert_eq!(config.expires_after(Some(900)), 900);
+        // never shorter than a poll, but 0 still means never
+        assert_eq!(config.expires_after(Some(10)), 60);
+        assert_eq!(config.expires_after(Some(0)), 0);
+        assert_eq!(AppConfig::default().expires_after(None), DEFAULT_POLL_INTERVAL_SECS * EXPIRES_AFTER_POLLS);
+    }
 }
@@ -1,8 +1,87 @@
 
 pub const MQTT_KEEPALIVE_TIME: u64 = 5_u64;
 pub const MQTT_THREAD_CHANNEL_CAPACITY: usize = 10_usize;
+/// How long a v5 broker keeps the gateway's session, and its command subscription, after
+/// the connection drops.
+pub const MQTT_SESSION_EXPIRY_SECS: u32 = 300_u32;
+pub const MQTT_RECONNECT_MAX_SECS: u64 = 60_u64;
+/// Client id for a broker without its own `client_id`.
+pub const DEFAULT_CLIENT_ID: &str = "gqgmcmqtt";
+pub const DEFAULT_STATE_CACHE_SIZE: usize = 64_usize;
+pub const DEFAULT_RECONNECT_JITTER_PCT: u8 = 20_u8;
 pub const MQTT_POLL_INTERVAL_MILLIS: u64 = 100_u64;
 pub const MQTT_PROCESSING_PAD_MILLIS: u64 = 2000_u64;
 
+pub const BROADCAST_CHANNEL_CAPACITY: usize = 64;
 pub const MPSC_BUFFER_SIZE: usize = 100_usize;
-pub const POLL_TIME: u16 = 5_u16;
\ No newline at end of file
+pub const MPSC_SEND_TIMEOUT_MILLIS: u64 = 1000_u64;
+/// 256KB, a common broker default for maximum packet size.
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 262_144_usize;
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5_u64;
+pub const EXPIRES_AFTER_POLLS: u64 = 3_u64;
+pub const DEFAULT_USV_CONVERSION_FACTOR: f32 = 151.0_f32;
+/// H*(10) per unit air kerma for Cs-137's 662 keV gammas (ICRU 57), in Sv/Gy.
+pub const H10_PER_AIR_KERMA: f32 = 1.20_f32;
+pub const DEFAULT_SHUTDOWN_GRACE_MILLIS: u64 = 2000_u64;
+
+#[cfg(windows)]
+pub const DEFAULT_SERIAL_PORT: &str = "COM3";
+#[cfg(not(windows))]
+pub const DEFAULT_SERIAL_PORT: &str = "/dev/ttyUSB0";
+pub const DEFAULT_SERIAL_BAUD: u32 = 57600_u32;
+/// Tried in order with `serial_baud: auto`; the first two cover every current GMC model.
+pub const AUTO_BAUD_RATES: [u32; 5] = [115200, 57600, 38400, 19200, 9600];
+pub const SERIAL_RETRY_MAX_SECS: u64 = 60_u64;
+pub const DEFAULT_SERIAL_RECONNECT_AFTER: u32 = 3_u32;
+/// `serial_port` value that selects the simulated device.
+pub const MOCK_SERIAL_PORT: &str = "mock";
+/// `serial_port` prefix that selects a Unix socket speaking the GQ protocol.
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+pub const STREAM_READ_TIMEOUT_MILLIS: u64 = 3000_u64;
+pub const DEFAULT_SERIAL_READ_TIMEOUT_MILLIS: u64 = 3000_u64;
+pub const STREAM_FLUSH_MILLIS: u64 = 10_u64;
+pub const GPS_FIX_MAX_AGE_SECS: u64 = 10_u64;
+pub const GPS_RETRY_SECS: u64 = 10_u64;
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+pub const DEFAULT_STATE_PREFIX: &str = "gqgmcmqtt";
+/// Commands arrive on `gqgmcmqtt/{serial}/{point}/set`, the payload being the new value.
+pub const COMMAND_TOPIC_FILTER: &str = "gqgmcmqtt/+/+/set";
+/// Command point that only asks for an immediate poll; any payload will do.
+pub const POLL_NOW_POINT: &str = "poll_now";
+/// Command point for the reset-peak button; sets the peak back to the current reading.
+pub const RESET_PEAK_POINT: &str = "reset_peak";
+pub const BUTTON_PRESS: &str = "PRESS";
+/// Command point for the unit's alarm threshold, in CPM; out-of-range values are clamped.
+pub const ALARM_CPM_POINT: &str = "alarm_cpm";
+pub const ALARM_CPM_MIN: u16 = 1_u16;
+pub const ALARM_CPM_MAX: u16 = u16::MAX;
+pub const SWITCH_ON: &str = "ON";
+pub const SWITCH_OFF: &str = "OFF";
+pub const HA_STATE_CLASSES: [&str; 3] = ["measurement", "total", "total_increasing"];
+/// Above this a CPM is taken as misdecoded rather than real; a saturated tube tops out far lower.
+pub const MAX_PLAUSIBLE_CPM: u32 = 1_000_000_u32;
+pub const SPIKE_CHECK_MILLIS: u64 = 1_000_u64;
+pub const DEFAULT_IDENTITY_RECHECK_MINS: u64 = 60_u64;
+pub const DEFAULT_MAX_NVM_WRITES_PER_MIN: usize = 6_usize;
+pub const NVM_MIN_WRITE_INTERVAL_SECS: u64 = 5_u64;
+/// Point for arbitrary device commands, only accepted with `enable_raw_command`.
+pub const RAW_COMMAND_POINT: &str = "raw_command";
+/// Command points acted on when no `command_allowlist` is configured.
+pub const DEFAULT_COMMAND_ALLOWLIST: [&str; 8] = [
+    "data_logging", "click_sound", "log_interval", "backlight_timeout", "display_brightness", "poll_now", "reset_peak",
+    "alarm_cpm",
+];
+
+/// How often a repeating read error is logged again, unless `error_log_interval_secs` says.
+pub const DEFAULT_ERROR_LOG_INTERVAL_SECS: u64 = 60_u64;
+/// Readings averaged for the rapid-increase alert baseline (5 minutes at the default poll).
+pub const ALERT_BASELINE_SAMPLES: usize = 60_usize;
+pub const STATE_SAVE_INTERVAL_SECS: u64 = 300_u64;
+pub const CLOCK_SYNC_INTERVAL_SECS: u64 = 86_400_u64;
+pub const DEFAULT_ALARM_DEBOUNCE_POLLS: u32 = 2_u32;
+pub const DEFAULT_MODEL: &str = "GMC";
+/// SPIR reads past this are refused by the firmware.
+pub const HISTORY_CHUNK_BYTES: u16 = 4096_u16;
+/// How far back `import_history` goes when there's no saved reading to start from.
+pub const DEFAULT_HISTORY_IMPORT_HOURS: i64 = 24_i64;
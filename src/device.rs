@@ -0,0 +1,1066 @@
+use chrono::{Datelike, NaiveDateTime, Timelike, Utc};
+use gqgmclib::{DataBits, Parity, StopBits, GMC};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::time::{timeout, Duration};
+use crate::config::{AppConfig, BaudDetect, Connection, CpmEndianness, SerialBaud, SerialFraming, SerialParity};
+use crate::consts::{AUTO_BAUD_RATES, MAX_PLAUSIBLE_CPM, MOCK_SERIAL_PORT, SERIAL_RETRY_MAX_SECS};
+use crate::errors::GQGMCMQTTError;
+use crate::serial_device::SerialDevice;
+use crate::stream_device::StreamDevice;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use crate::consts::UNIX_SOCKET_PREFIX;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+// Offsets into the device's config-memory block (GETCFG), per the GQ RFC1201 layout.
+pub const CFG_SPEAKER: usize = 2;
+pub const CFG_BACKLIGHT_TIMEOUT: usize = 4;
+// two bytes, big-endian
+pub const CFG_ALARM_CPM: usize = 6;
+pub const CFG_SAVE_DATA_TYPE: usize = 32;
+// only in the 500/600 series layout
+pub const CFG_DISPLAY_BRIGHTNESS: usize = 53;
+
+// SaveDataType values; anything non-zero means the unit is logging to flash.
+pub const SAVE_DATA_OFF: u8 = 0;
+pub const SAVE_DATA_EVERY_SECOND: u8 = 1;
+pub const SAVE_DATA_EVERY_MINUTE: u8 = 2;
+pub const SAVE_DATA_EVERY_HOUR: u8 = 3;
+// the same rates, but only saving while the reading is over the alarm threshold
+pub const SAVE_DATA_SECOND_OVER_THRESHOLD: u8 = 4;
+pub const SAVE_DATA_MINUTE_OVER_THRESHOLD: u8 = 5;
+
+/// Models that log to flash and so have a SaveDataType setting.
+pub const LOG_INTERVAL_MODELS: [&str; 5] = ["GMC-300", "GMC-320", "GMC-500", "GMC-600", "GMC-MOCK"];
+
+pub fn model_has_log_interval(model: &str) -> bool {
+    LOG_INTERVAL_MODELS.iter().any(|m| model.starts_with(m))
+}
+
+/// Seconds between saved samples for a SaveDataType value, 0 when logging is off.
+pub fn log_interval_secs(save_data_type: u8) -> Option<u64> {
+    match save_data_type {
+        SAVE_DATA_OFF => Some(0),
+        SAVE_DATA_EVERY_SECOND | SAVE_DATA_SECOND_OVER_THRESHOLD => Some(1),
+        SAVE_DATA_EVERY_MINUTE | SAVE_DATA_MINUTE_OVER_THRESHOLD => Some(60),
+        SAVE_DATA_EVERY_HOUR => Some(3600),
+        _ => None,
+    }
+}
+
+/// The SaveDataType value for an interval; only the unit's own rates can be set.
+pub fn save_data_type_for(secs: u64) -> Option<u8> {
+    match secs {
+        0 => Some(SAVE_DATA_OFF),
+        1 => Some(SAVE_DATA_EVERY_SECOND),
+        60 => Some(SAVE_DATA_EVERY_MINUTE),
+        3600 => Some(SAVE_DATA_EVERY_HOUR),
+        _ => None,
+    }
+}
+
+/// A setting `publish_device_config` can name: `len` bytes big-endian at `offset`, on
+/// the models listed, or on all when empty.
+pub struct ConfigField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub len: usize,
+    pub models: &'static [&'static str],
+}
+
+const WIDE_LAYOUT: &[&str] = &["GMC-500", "GMC-600", "GMC-MOCK"];
+
+/// The config bytes whose meaning is known, per the RFC1201 layout. The calibration
+/// µSv/h values are floats whose byte order differs between firmwares, so those are left
+/// to the raw regions.
+pub const CONFIG_FIELDS: [ConfigField; 14] = [
+    ConfigField { name: "power_on", offset: 0, len: 1, models: &[] },
+    ConfigField { name: "alarm_on", offset: 1, len: 1, models: &[] },
+    ConfigField { name: "speaker_on", offset: CFG_SPEAKER, len: 1, models: &[] },
+    ConfigField { name: "graphic_mode", offset: 3, len: 1, models: &[] },
+    ConfigField { name: "backlight_timeout", offset: CFG_BACKLIGHT_TIMEOUT, len: 1, models: &[] },
+    ConfigField { name: "idle_title_display_mode", offset: 5, len: 1, models: &[] },
+    ConfigField { name: "alarm_cpm", offset: CFG_ALARM_CPM, len: 2, models: &[] },
+    ConfigField { name: "calibration_cpm_0", offset: 8, len: 2, models: &[] },
+    ConfigField { name: "calibration_cpm_1", offset: 14, len: 2, models: &[] },
+    ConfigField { name: "calibration_cpm_2", offset: 20, len: 2, models: &[] },
+    ConfigField { name: "alarm_type", offset: 31, len: 1, models: &[] },
+    ConfigField { name: "save_data_type", offset: CFG_SAVE_DATA_TYPE, len: 1, models: &[] },
+    ConfigField { name: "power_saving_mode", offset: 44, len: 1, models: &[] },
+    ConfigField { name: "display_brightness", offset: CFG_DISPLAY_BRIGHTNESS, len: 1, models: WIDE_LAYOUT },
+];
+
+impl ConfigField {
+    pub fn applies_to(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| model.starts_with(m))
+    }
+}
+
+/// An on/off setting stored as one config byte, where 0 is off and anything else is on.
+pub struct ConfigSwitch {
+    pub point: &'static str,
+    pub offset: usize,
+    /// Written when switching on; SaveDataType has several "on" values so this picks one.
+    pub on_value: u8,
+}
+
+pub const DATA_LOGGING_SWITCH: ConfigSwitch = ConfigSwitch {
+    point: "data_logging",
+    offset: CFG_SAVE_DATA_TYPE,
+    on_value: SAVE_DATA_EVERY_MINUTE,
+};
+pub const CLICK_SOUND_SWITCH: ConfigSwitch = ConfigSwitch {
+    point: "click_sound",
+    offset: CFG_SPEAKER,
+    on_value: 1,
+};
+pub static CONFIG_SWITCHES: [ConfigSwitch; 2] = [DATA_LOGGING_SWITCH, CLICK_SOUND_SWITCH];
+
+impl ConfigSwitch {
+    pub fn by_point(point: &str) -> Option<&'static ConfigSwitch> {
+        CONFIG_SWITCHES.iter().find(|s| s.point == point)
+    }
+
+    /// Every switch is off at 0: SAVE_DATA_OFF for logging, a silent speaker for clicks.
+    pub async fn get<D: GeigerDevice>(&self, gmc: &mut D) -> Result<bool, GQGMCMQTTError> {
+        Ok(read_config_byte(gmc, self.offset).await? != 0)
+    }
+
+    pub async fn set<D: GeigerDevice>(&self, gmc: &mut D, on: bool) -> Result<(), GQGMCMQTTError> {
+        let value = if on { self.on_value } else { 0 };
+        write_config_byte(gmc, self.offset, value).await
+    }
+}
+
+/// A numeric setting stored as one config byte, on the models whose layout has it.
+pub struct ConfigNumber {
+    pub point: &'static str,
+    pub offset: usize,
+    pub models: &'static [&'static str],
+    pub min: u8,
+    pub max: u8,
+}
+
+pub const BACKLIGHT_TIMEOUT_NUMBER: ConfigNumber = ConfigNumber {
+    point: "backlight_timeout",
+    offset: CFG_BACKLIGHT_TIMEOUT,
+    models: &["GMC-300", "GMC-320", "GMC-500", "GMC-600", "GMC-MOCK"],
+    min: 0,
+    max: 255,
+};
+pub const DISPLAY_BRIGHTNESS_NUMBER: ConfigNumber = ConfigNumber {
+    point: "display_brightness",
+    offset: CFG_DISPLAY_BRIGHTNESS,
+    models: WIDE_LAYOUT,
+    min: 0,
+    max: 15,
+};
+pub static CONFIG_NUMBERS: [ConfigNumber; 2] = [BACKLIGHT_TIMEOUT_NUMBER, DISPLAY_BRIGHTNESS_NUMBER];
+
+impl ConfigNumber {
+    pub fn by_point(point: &str) -> Option<&'static ConfigNumber> {
+        CONFIG_NUMBERS.iter().find(|n| n.point == point)
+    }
+
+    pub fn supported(&self, model: &str) -> bool {
+        self.models.iter().any(|m| model.starts_with(m))
+    }
+
+    pub async fn get<D: GeigerDevice>(&self, gmc: &mut D) -> Result<u8, GQGMCMQTTError> {
+        read_config_byte(gmc, self.offset).await
+    }
+
+    pub async fn set<D: GeigerDevice>(&self, gmc: &mut D, value: u8) -> Result<(), GQGMCMQTTError> {
+        write_config_byte(gmc, self.offset, value).await
+    }
+}
+
+/// The subset of the GQ serial protocol the gateway uses. Everything above this layer is
+/// generic over it so it can run against `MockDevice` as well as a real unit.
+pub trait GeigerDevice {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError>;
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError>;
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError>;
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError>;
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError>;
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError>;
+    /// Degrees Celsius; only answered by models listed in TEMPERATURE_MODELS.
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError>;
+    /// Signal strength in dBm, for WiFi units (see `model_has_wifi`). Unsupported by
+    /// default, so the sensor is never announced.
+    async fn get_wifi_rssi(&mut self) -> Result<i32, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("WiFi RSSI is not supported by this device".to_string()))
+    }
+    /// The unit's own GPS fix as latitude and longitude, on models `model_has_gps` picks
+    /// out. Unsupported by default, and `gps_device` is used instead.
+    async fn get_gps(&mut self) -> Result<(f64, f64), GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("GPS reads are not supported by this device".to_string()))
+    }
+    /// Counts in the last second; unsupported by default.
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("CPS reads are not supported by this device".to_string()))
+    }
+    /// Battery voltage in volts; unsupported by default, like `get_cps`.
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("Voltage reads are not supported by this device".to_string()))
+    }
+    /// The unit's real-time clock, which has no time zone; unsupported by default.
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("Clock reads are not supported by this device".to_string()))
+    }
+    /// Sets the real-time clock, to the second; unsupported by default, like `get_datetime`.
+    async fn set_datetime(&mut self, _at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("Setting the clock is not supported by this device".to_string()))
+    }
+    /// `len` bytes of the history log in flash, from `addr` (SPIR); unsupported by
+    /// default.
+    async fn read_history(&mut self, _addr: u32, _len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("History reads are not supported by this device".to_string()))
+    }
+    /// Throws away whatever is sitting unread in the input buffer.
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        Ok(())
+    }
+    /// Sends an arbitrary command and returns whatever the unit answers. gqgmclib's
+    /// passthrough needs the reply length up front, so like capture this only works on
+    /// transports the gateway drives itself.
+    async fn raw_command(&mut self, _cmd: &[u8]) -> Result<Vec<u8>, GQGMCMQTTError> {
+        Err(GQGMCMQTTError::Device("Raw commands are not supported by this device".to_string()))
+    }
+    /// Start or stop recording each command's raw reply. Only transports the gateway
+    /// drives byte by byte can do this; the rest record nothing.
+    fn set_capture(&mut self, _on: bool) {}
+    /// Replies recorded since the last call.
+    fn take_captured(&mut self) -> Vec<RawResponse> {
+        vec![]
+    }
+}
+
+/// One command's reply exactly as the unit sent it, hex-encoded, for debugging parsing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawResponse {
+    pub command: String,
+    pub hex: String,
+}
+
+/// A simulated unit, selected with `serial_port: mock`, for running the whole gateway
+/// against a broker without hardware attached. Reports a background-ish CPM that wanders
+/// between 10 and 40.
+pub struct MockDevice {
+    pub serial: String,
+    pub version: String,
+    pub config: Vec<u8>,
+    /// How far the simulated clock is off from UTC.
+    clock_offset: chrono::Duration,
+    rng: u64,
+}
+
+impl Default for MockDevice {
+    fn default() -> Self {
+        MockDevice {
+            serial: "MOCK0001".to_string(),
+            version: "GMC-MOCK 1.00".to_string(),
+            config: vec![0_u8; 256],
+            clock_offset: chrono::Duration::zero(),
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl GeigerDevice for MockDevice {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        Ok(self.version.clone())
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        Ok(self.serial.clone())
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        // xorshift64, plenty for made-up counts
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        Ok(10 + (self.rng % 31) as u32)
+    }
+
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        Ok(self.get_cpm().await? / 60)
+    }
+
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        Ok(4.1)
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        Ok(self.config.clone())
+    }
+
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        match self.config.get_mut(offset) {
+            Some(b) => {
+                *b = value;
+                Ok(())
+            }
+            None => Err(GQGMCMQTTError::Device(format!("No config offset {offset}"))),
+        }
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        Ok(())
+    }
+
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        Ok(21.5)
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        Ok(Utc::now().naive_utc() + self.clock_offset)
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        self.clock_offset = at - Utc::now().naive_utc();
+        Ok(())
+    }
+
+    /// The last half hour logged once a minute, then erased flash.
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        let start = Utc::now().naive_utc() + self.clock_offset - chrono::Duration::minutes(30);
+        let mut log = vec![0x55, 0xAA, 0x00];
+        log.extend([start.year() - 2000, start.month() as i32, start.day() as i32].map(|v| v as u8));
+        log.extend([start.hour(), start.minute(), start.second()].map(|v| v as u8));
+        log.extend([0x55, 0xAA, SAVE_DATA_EVERY_MINUTE]);
+        for _ in 0..30 {
+            log.push(self.get_cpm().await? as u8);
+        }
+        let addr = addr as usize;
+        Ok((addr..addr + len as usize).map(|i| log.get(i).copied().unwrap_or(0xFF)).collect())
+    }
+}
+
+/// Whichever device the config selected; lets main hold one concrete type.
+pub enum AnyDevice {
+    Gmc(SerialDevice),
+    Mock(MockDevice),
+    #[cfg(unix)]
+    Socket(StreamDevice<UnixStream>),
+    Tcp(StreamDevice<TcpStream>),
+}
+
+macro_rules! each_device {
+    ($self:ident, $d:ident => $e:expr) => {
+        match $self {
+            AnyDevice::Gmc($d) => $e,
+            AnyDevice::Mock($d) => $e,
+            #[cfg(unix)]
+            AnyDevice::Socket($d) => $e,
+            AnyDevice::Tcp($d) => $e,
+        }
+    };
+}
+
+impl GeigerDevice for AnyDevice {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_version(d).await)
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_serial_number(d).await)
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_cpm(d).await)
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_config(d).await)
+    }
+
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::write_config(d, offset, value).await)
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::update_config(d).await)
+    }
+
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_temperature(d).await)
+    }
+
+    async fn get_wifi_rssi(&mut self) -> Result<i32, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_wifi_rssi(d).await)
+    }
+
+    async fn get_gps(&mut self) -> Result<(f64, f64), GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_gps(d).await)
+    }
+
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_cps(d).await)
+    }
+
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_voltage(d).await)
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::get_datetime(d).await)
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::set_datetime(d, at).await)
+    }
+
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::read_history(d, addr, len).await)
+    }
+
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::flush_input(d).await)
+    }
+
+    async fn raw_command(&mut self, cmd: &[u8]) -> Result<Vec<u8>, GQGMCMQTTError> {
+        each_device!(self, d => GeigerDevice::raw_command(d, cmd).await)
+    }
+
+    fn set_capture(&mut self, on: bool) {
+        each_device!(self, d => GeigerDevice::set_capture(d, on))
+    }
+
+    fn take_captured(&mut self) -> Vec<RawResponse> {
+        each_device!(self, d => GeigerDevice::take_captured(d))
+    }
+}
+
+/// Wraps a device so every command starts from an empty input buffer, for adapters that
+/// leave stray bytes of an earlier reply behind to shift the next one.
+pub struct FlushBeforeRead<'a, D>(pub &'a mut D);
+
+impl<D: GeigerDevice> FlushBeforeRead<'_, D> {
+    async fn flush(&mut self) {
+        if let Err(e) = self.0.flush_input().await {
+            debug!("Couldn't flush device input: {e}");
+        }
+    }
+}
+
+impl<D: GeigerDevice> GeigerDevice for FlushBeforeRead<'_, D> {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_version().await
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_serial_number().await
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_cpm().await
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_config().await
+    }
+
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        self.flush().await;
+        self.0.write_config(offset, value).await
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.flush().await;
+        self.0.update_config().await
+    }
+
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_temperature().await
+    }
+
+    async fn get_wifi_rssi(&mut self) -> Result<i32, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_wifi_rssi().await
+    }
+
+    async fn get_gps(&mut self) -> Result<(f64, f64), GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_gps().await
+    }
+
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_cps().await
+    }
+
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_voltage().await
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.get_datetime().await
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        self.flush().await;
+        self.0.set_datetime(at).await
+    }
+
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.read_history(addr, len).await
+    }
+
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.0.flush_input().await
+    }
+
+    async fn raw_command(&mut self, cmd: &[u8]) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.flush().await;
+        self.0.raw_command(cmd).await
+    }
+
+    fn set_capture(&mut self, on: bool) {
+        self.0.set_capture(on)
+    }
+
+    fn take_captured(&mut self) -> Vec<RawResponse> {
+        self.0.take_captured()
+    }
+}
+
+/// Wraps a device so no command can wait on the unit longer than `serial_read_timeout_ms`.
+/// A command that runs out of time fails like any other read, and the input is flushed so
+/// the rest of a late reply can't be taken for the answer to the next command.
+pub struct TimedRead<'a, D>(pub &'a mut D, pub Duration);
+
+impl<D: GeigerDevice> TimedRead<'_, D> {
+    async fn reset(&mut self) {
+        if let Err(e) = self.0.flush_input().await {
+            debug!("Couldn't flush device input after a timeout: {e}");
+        }
+    }
+}
+
+macro_rules! bounded {
+    ($self:ident, $what:literal, $d:ident => $e:expr) => {{
+        let limit = $self.1;
+        let $d = &mut *$self.0;
+        match timeout(limit, $e).await {
+            Ok(result) => result,
+            Err(_) => {
+                $self.reset().await;
+                Err(GQGMCMQTTError::Device(format!("{} timed out after {}ms", $what, limit.as_millis())))
+            }
+        }
+    }};
+}
+
+impl<D: GeigerDevice> GeigerDevice for TimedRead<'_, D> {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        bounded!(self, "GETVER", d => d.get_version())
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        bounded!(self, "GETSERIAL", d => d.get_serial_number())
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        bounded!(self, "GETCPM", d => d.get_cpm())
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        bounded!(self, "GETCFG", d => d.get_config())
+    }
+
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        bounded!(self, "WRITECONFIG", d => d.write_config(offset, value))
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        bounded!(self, "CFGUPDATE", d => d.update_config())
+    }
+
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        bounded!(self, "GETTEMP", d => d.get_temperature())
+    }
+
+    async fn get_wifi_rssi(&mut self) -> Result<i32, GQGMCMQTTError> {
+        bounded!(self, "WiFi RSSI read", d => d.get_wifi_rssi())
+    }
+
+    async fn get_gps(&mut self) -> Result<(f64, f64), GQGMCMQTTError> {
+        bounded!(self, "GPS read", d => d.get_gps())
+    }
+
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        bounded!(self, "GETCPS", d => d.get_cps())
+    }
+
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        bounded!(self, "GETVOLT", d => d.get_voltage())
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        bounded!(self, "GETDATETIME", d => d.get_datetime())
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        bounded!(self, "SETDATETIME", d => d.set_datetime(at))
+    }
+
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        bounded!(self, "SPIR", d => d.read_history(addr, len))
+    }
+
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.0.flush_input().await
+    }
+
+    async fn raw_command(&mut self, cmd: &[u8]) -> Result<Vec<u8>, GQGMCMQTTError> {
+        bounded!(self, "Raw command", d => d.raw_command(cmd))
+    }
+
+    fn set_capture(&mut self, on: bool) {
+        self.0.set_capture(on)
+    }
+
+    fn take_captured(&mut self) -> Vec<RawResponse> {
+        self.0.take_captured()
+    }
+}
+
+/// Opens the configured device, retrying with exponential backoff (capped at
+/// SERIAL_RETRY_MAX_SECS) until it succeeds.
+///
+/// Besides a serial port, `serial_port` may be `mock` for the simulated device or
+/// `unix:/path/to.sock` to talk the GQ protocol over a Unix socket, e.g. one bridged to a
+/// pty pair with `socat -d -d pty,raw,echo=0 UNIX-LISTEN:/tmp/gmc.sock` so a test process
+/// can play the unit or replay captured traffic. (A bare pty from
+/// `socat -d -d pty,raw pty,raw` can also be given directly as a serial port.)
+///
+/// With `connection: tcp`, `serial_port` is ignored and the unit is reached at `device_addr`.
+pub async fn connect_device(config: &AppConfig) -> AnyDevice {
+    let port = match config.connection() {
+        Connection::Tcp => config.device_addr.clone().unwrap_or_default(),
+        Connection::Serial => config.serial_port(),
+    };
+    if config.connection() == Connection::Serial && port == MOCK_SERIAL_PORT {
+        info!("Using simulated device instead of a serial port.");
+        return AnyDevice::Mock(MockDevice::default());
+    }
+    let mut delay = 1_u64;
+    loop {
+        match open_device(config).await {
+            Ok(device) => return device,
+            Err(e) => {
+                error!("Can't connect to unit on {port}: {e}, retrying in {delay}s");
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(SERIAL_RETRY_MAX_SECS);
+    }
+}
+
+async fn open_device(config: &AppConfig) -> Result<AnyDevice, GQGMCMQTTError> {
+    if config.connection() == Connection::Tcp {
+        let addr = config.device_addr.clone().unwrap_or_default();
+        let stream = TcpStream::connect(&addr)
+            .await
+            .map_err(|e| GQGMCMQTTError::Device(e.to_string()))?;
+        info!("Connected to unit over TCP at {addr}.");
+        return Ok(AnyDevice::Tcp(StreamDevice::new(stream, config.response_framing())));
+    }
+    let port = config.serial_port();
+    #[cfg(unix)]
+    if let Some(path) = port.strip_prefix(UNIX_SOCKET_PREFIX) {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|e| GQGMCMQTTError::Device(e.to_string()))?;
+        info!("Connected to unit over Unix socket {path}.");
+        return Ok(AnyDevice::Socket(StreamDevice::new(stream, config.response_framing())));
+    }
+    let framing = config.serial_framing();
+    if config.serial_baud == Some(SerialBaud::Detect(BaudDetect::Auto)) {
+        return detect_baud(&port, framing).await;
+    }
+    let baud = config.serial_baud();
+    let gmc = open_serial(&port, baud, framing)?;
+    info!("Connected to unit on {port} at {baud} baud, {framing}.");
+    Ok(AnyDevice::Gmc(gmc))
+}
+
+/// Opens the port at each rate GMC units ship with and keeps the first one a GETVER gets
+/// a believable answer at. A wrong rate reads as garbage or nothing, never a "GMC" string.
+async fn detect_baud(port: &str, framing: SerialFraming) -> Result<AnyDevice, GQGMCMQTTError> {
+    for baud in AUTO_BAUD_RATES {
+        let mut gmc = open_serial(port, baud, framing)?;
+        match GeigerDevice::get_version(&mut gmc).await {
+            Ok(version) if version.trim_start().starts_with("GMC") => {
+                info!("Detected the unit on {port} at {baud} baud, {framing}.");
+                return Ok(AnyDevice::Gmc(gmc));
+            }
+            Ok(version) => debug!("No plausible version at {baud} baud, got {version:?}"),
+            Err(e) => debug!("No answer at {baud} baud: {e}"),
+        }
+    }
+    Err(GQGMCMQTTError::Device(format!(
+        "unit didn't answer at any of {AUTO_BAUD_RATES:?} baud; check the cable and serial_port, or set serial_baud"
+    )))
+}
+
+fn open_serial(port: &str, baud: u32, framing: SerialFraming) -> Result<SerialDevice, GQGMCMQTTError> {
+    GMC::new_with_framing(
+        port,
+        baud,
+        data_bits(framing.data_bits),
+        parity(framing.parity),
+        stop_bits(framing.stop_bits),
+    )
+    .map(SerialDevice::new)
+    .map_err(|e| {
+        // gqgmclib only passes on the OS error text; EACCES is what a missing dialout group gives
+        let msg = e.to_string();
+        if msg.to_lowercase().contains("permission denied") {
+            GQGMCMQTTError::SerialPermission(port.to_string())
+        } else {
+            GQGMCMQTTError::Device(msg)
+        }
+    })
+}
+
+fn data_bits(bits: u8) -> DataBits {
+    match bits {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        _ => DataBits::Eight,
+    }
+}
+
+fn parity(parity: SerialParity) -> Parity {
+    match parity {
+        SerialParity::None => Parity::None,
+        SerialParity::Odd => Parity::Odd,
+        SerialParity::Even => Parity::Even,
+    }
+}
+
+fn stop_bits(bits: u8) -> StopBits {
+    match bits {
+        2 => StopBits::Two,
+        _ => StopBits::One,
+    }
+}
+
+/// Version-string prefixes of models with a temperature sensor behind GETTEMP. Other
+/// models don't reliably reject the command, so support goes by model rather than a probe.
+pub const TEMPERATURE_MODELS: [&str; 4] = ["GMC-320", "GMC-500", "GMC-600", "GMC-MOCK"];
+
+pub fn model_has_temperature(model: &str) -> bool {
+    TEMPERATURE_MODELS.iter().any(|m| model.starts_with(m))
+}
+
+/// The tube a model ships with: an LND 7317 pancake in the 600 series, an M4011 in the
+/// rest (the 500+ adds an SI-3BG for high rates, but CPM comes from the M4011).
+pub fn model_tube(model: &str) -> &'static str {
+    if model.starts_with("GMC-6") {
+        "LND 7317"
+    } else {
+        "M4011"
+    }
+}
+
+/// Splits a version string such as `GMC-320Re 4.22` into the model and the firmware
+/// revision after it. Anything else comes back whole as the model, with no revision.
+pub fn split_version(version: &str) -> (&str, Option<&str>) {
+    let version = version.trim();
+    if let Some((model, firmware)) = version.rsplit_once(' ') {
+        let numeric = firmware.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+        if numeric && !model.trim().is_empty() {
+            return (model.trim_end(), Some(firmware));
+        }
+    }
+    debug!("Version string {version:?} has no firmware revision to split off");
+    (version, None)
+}
+
+/// Models with the WiFi module, whose firmware reports its signal strength. Matched on
+/// the model split off the version string, so `GMC-500` doesn't also pick up `GMC-500+`.
+pub const WIFI_MODELS: [&str; 2] = ["GMC-500+", "GMC-600+"];
+
+/// Models whose firmware answers a position command. The same WiFi-era 500+/600+
+/// firmware, so far.
+pub const GPS_MODELS: [&str; 2] = ["GMC-500+", "GMC-600+"];
+
+/// `GMC-500+Re 2.42` and `GMC-500+ 2.42` both count; the firmware suffix after the `+`
+/// varies.
+fn model_in(version: &str, models: &[&str]) -> bool {
+    let (model, _) = split_version(version);
+    models.iter().any(|m| model.strip_prefix(m).is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_digit())))
+}
+
+pub fn model_has_wifi(version: &str) -> bool {
+    model_in(version, &WIFI_MODELS)
+}
+
+pub fn model_has_gps(version: &str) -> bool {
+    model_in(version, &GPS_MODELS)
+}
+
+/// Size of the flash the history log lives in: 1 MiB on the 500/600 series, 64 KiB on
+/// older models.
+pub fn model_history_bytes(model: &str) -> u32 {
+    if model_has_wide_cpm(model) {
+        0x10_0000
+    } else {
+        0x1_0000
+    }
+}
+
+/// GETCPM and GETCPS answer with 4 bytes on the 500/600 series and 2 on older models.
+pub fn model_has_wide_cpm(model: &str) -> bool {
+    model.starts_with("GMC-5") || model.starts_with("GMC-6")
+}
+
+/// Puts a count decoded the documented big-endian way into the configured byte order.
+/// Auto only swaps when the reading is implausible as sent and believable swapped.
+pub fn cpm_byte_order(count: u32, model: &str, endianness: CpmEndianness) -> u32 {
+    if !model_has_wide_cpm(model) {
+        return count;
+    }
+    match endianness {
+        CpmEndianness::Big => count,
+        CpmEndianness::Little => count.swap_bytes(),
+        CpmEndianness::Auto if count > MAX_PLAUSIBLE_CPM && count.swap_bytes() <= MAX_PLAUSIBLE_CPM => {
+            debug!("CPM {count} is implausible, using the little-endian reading {}", count.swap_bytes());
+            count.swap_bytes()
+        }
+        CpmEndianness::Auto => count,
+    }
+}
+
+/// Optional commands that not every model/firmware understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeviceFeature {
+    /// GETCFG config-memory reads, backing the data logging switch.
+    Config,
+    /// Signal strength reporting on WiFi units.
+    WifiRssi,
+    /// The unit's own position fix.
+    Gps,
+    /// GETCPS, backing the between-poll spike check.
+    Cps,
+    /// GETVOLT, the battery voltage.
+    Voltage,
+}
+
+impl DeviceFeature {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceFeature::Config => "config",
+            DeviceFeature::WifiRssi => "wifi_rssi",
+            DeviceFeature::Gps => "gps",
+            DeviceFeature::Cps => "cps",
+            DeviceFeature::Voltage => "voltage",
+        }
+    }
+}
+
+/// Issues each optional command once at startup. Firmware that doesn't know a command
+/// tends to echo it back or answer with a stray byte, so a probe only counts as supported
+/// if the response also passes the same validation the regular read does.
+pub async fn probe_features<D: GeigerDevice>(gmc: &mut D, config: &AppConfig) -> HashSet<DeviceFeature> {
+    let mut features = HashSet::new();
+    match read_config_byte(gmc, CFG_SAVE_DATA_TYPE).await {
+        Ok(_) => {
+            features.insert(DeviceFeature::Config);
+        }
+        Err(e) => {
+            debug!("Config reads unsupported: {e}");
+        }
+    }
+    // like GETTEMP, older firmware doesn't reliably reject these, so only the models with
+    // the hardware are asked, and only when the sensors are wanted
+    if config.enable_extended_sensors.unwrap_or(false) {
+        let version = gmc.get_version().await.unwrap_or_default();
+        if model_has_wifi(&version) {
+            match gmc.get_wifi_rssi().await {
+                Ok(_) => {
+                    features.insert(DeviceFeature::WifiRssi);
+                }
+                Err(e) => {
+                    debug!("WiFi RSSI unsupported: {e}");
+                }
+            }
+        }
+        if model_has_gps(&version) {
+            match gmc.get_gps().await {
+                Ok(_) => {
+                    features.insert(DeviceFeature::Gps);
+                }
+                Err(e) => {
+                    debug!("GPS reads unsupported: {e}");
+                }
+            }
+        }
+    }
+    match gmc.get_cps().await {
+        Ok(_) => {
+            features.insert(DeviceFeature::Cps);
+        }
+        Err(e) => {
+            debug!("CPS reads unsupported: {e}");
+        }
+    }
+    // a unit on battery can't be below 2V or above 10V, so anything else is garbage
+    match gmc.get_voltage().await {
+        Ok(volts) if (2.0..=10.0).contains(&volts) => {
+            features.insert(DeviceFeature::Voltage);
+        }
+        Ok(volts) => debug!("Voltage reads unsupported: implausible {volts}V"),
+        Err(e) => {
+            debug!("Voltage reads unsupported: {e}");
+        }
+    }
+    features
+}
+
+/// Sets the unit's clock to the current time in the configured zone, which is what it
+/// shows on its display, logging its time before and after.
+pub async fn sync_clock<D: GeigerDevice>(gmc: &mut D, config: &AppConfig) {
+    match gmc.get_datetime().await {
+        Ok(before) => info!("Unit clock reads {before}, setting it to the host's time."),
+        Err(e) => debug!("Can't read the unit's clock before setting it: {e}"),
+    }
+    if let Err(e) = gmc.set_datetime(config.local_now()).await {
+        warn!("Couldn't set the unit's clock: {e}");
+        return;
+    }
+    match gmc.get_datetime().await {
+        Ok(after) => info!("Unit clock now reads {after}."),
+        Err(e) => debug!("Can't read the unit's clock back: {e}"),
+    }
+}
+
+pub async fn read_config_byte<D: GeigerDevice>(gmc: &mut D, offset: usize) -> Result<u8, GQGMCMQTTError> {
+    let cfg = match gmc.get_config().await {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(GQGMCMQTTError::Default(format!("Can't read device config: {e}")));
+        }
+    };
+    match cfg.get(offset) {
+        Some(b) => Ok(*b),
+        None => Err(GQGMCMQTTError::Default(format!(
+            "Device config block is {} bytes, no offset {offset}",
+            cfg.len()
+        ))),
+    }
+}
+
+/// The CPM the unit's own alarm sounds above.
+pub async fn get_alarm_cpm<D: GeigerDevice>(gmc: &mut D) -> Result<u16, GQGMCMQTTError> {
+    let cfg = gmc
+        .get_config()
+        .await
+        .map_err(|e| GQGMCMQTTError::Default(format!("Can't read device config: {e}")))?;
+    match cfg.get(CFG_ALARM_CPM..CFG_ALARM_CPM + 2) {
+        Some(&[hi, lo]) => Ok(u16::from_be_bytes([hi, lo])),
+        _ => Err(GQGMCMQTTError::Default(format!(
+            "Device config block is {} bytes, no alarm CPM at {CFG_ALARM_CPM}",
+            cfg.len()
+        ))),
+    }
+}
+
+/// Writes both alarm threshold bytes before a single CFGUPDATE, so the unit never applies
+/// half of the new value.
+pub async fn set_alarm_cpm<D: GeigerDevice>(gmc: &mut D, cpm: u16) -> Result<(), GQGMCMQTTError> {
+    for (i, byte) in cpm.to_be_bytes().into_iter().enumerate() {
+        if let Err(e) = gmc.write_config(CFG_ALARM_CPM + i, byte).await {
+            return Err(GQGMCMQTTError::Default(format!("Can't write device config: {e}")));
+        }
+    }
+    if let Err(e) = gmc.update_config().await {
+        return Err(GQGMCMQTTError::Default(format!("Can't apply device config: {e}")));
+    }
+    Ok(())
+}
+
+/// Writes a single config byte and applies it with CFGUPDATE so the unit picks it up
+/// without a power cycle.
+pub async fn write_config_byte<D: GeigerDevice>(gmc: &mut D, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+    if let Err(e) = gmc.write_config(offset, value).await {
+        return Err(GQGMCMQTTError::Default(format!("Can't write device config: {e}")));
+    }
+    if let Err(e) = gmc.update_config().await {
+        return Err(GQGMCMQTTError::Default(format!("Can't apply device config: {e}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 4 bytes of a wide GETCPM reply, decoded big-endian like the transports do.
+    fn decoded(reply: [u8; 4]) -> u32 {
+        u32::from_be_bytes(reply)
+    }
+
+    #[test]
+    fn big_endian_replies() {
+        let count = decoded([0x00, 0x00, 0x00, 0x1c]);
+        assert_eq!(cpm_byte_order(count, "GMC-500+Re 2.42", CpmEndianness::Big), 28);
+        assert_eq!(cpm_byte_order(count, "GMC-500+Re 2.42", CpmEndianness::Auto), 28);
+        assert_eq!(cpm_byte_order(count, "GMC-500+Re 2.42", CpmEndianness::Little), 0x1c00_0000);
+    }
+
+    #[test]
+    fn little_endian_replies() {
+        let count = decoded([0x1c, 0x00, 0x00, 0x00]);
+        assert_eq!(cpm_byte_order(count, "GMC-600+Re 2.52", CpmEndianness::Little), 28);
+        assert_eq!(cpm_byte_order(count, "GMC-600+Re 2.52", CpmEndianness::Auto), 28);
+        assert_eq!(cpm_byte_order(count, "GMC-600+Re 2.52", CpmEndianness::Big), 0x1c00_0000);
+    }
+
+    #[test]
+    fn auto_keeps_readings_implausible_both_ways() {
+        let count = decoded([0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(cpm_byte_order(count, "GMC-500+Re 2.42", CpmEndianness::Auto), count);
+    }
+
+    #[test]
+    fn narrow_models_are_left_alone() {
+        assert_eq!(cpm_byte_order(0x1c00, "GMC-320Re 4.26", CpmEndianness::Little), 0x1c00);
+    }
+
+    #[test]
+    fn wifi_models_by_version() {
+        assert!(model_has_wifi("GMC-500+Re 2.42"));
+        assert!(model_has_wifi("GMC-600+ 2.52"));
+        assert!(model_has_gps("GMC-600+Re 2.52"));
+        assert!(!model_has_wifi("GMC-500Re 1.08"));
+        assert!(!model_has_wifi("GMC-320Re 4.26"));
+        assert!(!model_has_gps("GMC-5000"));
+    }
+
+    #[tokio::test]
+    async fn click_sound_switches_off_to_zero() {
+        let mut gmc = MockDevice::default();
+        CLICK_SOUND_SWITCH.set(&mut gmc, true).await.unwrap();
+        assert_eq!(gmc.config[CFG_SPEAKER], 1);
+        assert!(CLICK_SOUND_SWITCH.get(&mut gmc).await.unwrap());
+        CLICK_SOUND_SWITCH.set(&mut gmc, false).await.unwrap();
+        assert_eq!(gmc.config[CFG_SPEAKER], 0);
+        assert!(!CLICK_SOUND_SWITCH.get(&mut gmc).await.unwrap());
+    }
+}
@@ -4,5 +4,72 @@ pub enum GQGMCMQTTError {
     #[error("Default: {0}")]
     Default(String),
     #[error("Received request for thread exit")]
-    ExitingThread
-}
\ No newline at end of file
+    ExitingThread,
+    #[error("Config: {0}")]
+    Config(String),
+    #[error("Device: {0}")]
+    Device(String),
+    #[error("MQTT: {0}")]
+    Mqtt(String),
+    #[error("Permission denied opening {0}; add this user to the dialout group (then log in again) or give the port a udev rule granting access")]
+    SerialPermission(String),
+}
+
+/// Why a message couldn't be handed to the MQTT threads. Only a closed channel is fatal;
+/// a full one means the brokers are backed up, and later messages may still get through.
+#[derive(Error, Clone, Debug)]
+pub enum GqError {
+    #[error("MQTT channel is closed, the publisher threads are gone")]
+    ChannelClosed,
+    #[error("MQTT channel stayed full for {0}ms, dropped a message")]
+    ChannelFull(u64),
+}
+
+/// A failed poll of the MQTT event loop, whichever protocol version it speaks, reduced to
+/// what the reconnect handling tells apart.
+#[derive(Error, Clone, Debug)]
+pub enum MqttLinkError {
+    #[error("TLS: {0}")]
+    Tls(String),
+    #[error("broker refused the connection: {0}")]
+    Refused(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Coarse classification of device failures, for showing to users rather than for
+/// handling; the library only hands back error text, so this goes by the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceErrorKind {
+    Timeout,
+    Framing,
+    Unsupported,
+    Communication,
+}
+
+impl DeviceErrorKind {
+    pub fn classify(err: &GQGMCMQTTError) -> Self {
+        let msg = err.to_string().to_lowercase();
+        if msg.contains("closed") {
+            // a lost link, whatever the underlying io error happened to say
+            DeviceErrorKind::Communication
+        } else if msg.contains("timed out") || msg.contains("timeout") {
+            DeviceErrorKind::Timeout
+        } else if msg.contains("framing") || msg.contains("parity") || msg.contains("unexpected") {
+            DeviceErrorKind::Framing
+        } else if msg.contains("unsupported") || msg.contains("not supported") {
+            DeviceErrorKind::Unsupported
+        } else {
+            DeviceErrorKind::Communication
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceErrorKind::Timeout => "timeout",
+            DeviceErrorKind::Framing => "framing error",
+            DeviceErrorKind::Unsupported => "unsupported command",
+            DeviceErrorKind::Communication => "communication error",
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::AppConfig;
+use crate::consts::{GPS_FIX_MAX_AGE_SECS, GPS_RETRY_SECS};
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref FIX: Mutex<Option<(f64, f64, Instant)>> = Mutex::new(None);
+}
+
+/// Reads NMEA sentences from `path` (a USB receiver's tty, or a pipe from gpsd) on a
+/// blocking thread, keeping the latest fix for `position`. The port's baud rate is
+/// left to the OS; most USB receivers ignore it anyway. Reopens the source if it goes away.
+pub fn spawn(path: String) {
+    tokio::task::spawn_blocking(move || loop {
+        match File::open(&path) {
+            Ok(file) => {
+                info!("Reading GPS fixes from {path}.");
+                for line in BufReader::new(file).lines() {
+                    match line {
+                        Ok(line) => {
+                            if let Some((lat, lon)) = parse_sentence(line.trim()) {
+                                *FIX.lock().unwrap() = Some((lat, lon, Instant::now()));
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Lost the GPS source {path}: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Can't open the GPS source {path}: {e}"),
+        }
+        std::thread::sleep(Duration::from_secs(GPS_RETRY_SECS));
+    });
+}
+
+/// Where the unit is: the latest GPS fix when `gps_device` is set, else the static
+/// `latitude`/`longitude`. A fix older than `GPS_FIX_MAX_AGE_SECS` counts as none, so a
+/// receiver that lost the sky doesn't pin a moving survey to where it last was.
+pub fn position(config: &AppConfig) -> Option<(f64, f64)> {
+    if config.gps_device.is_none() {
+        return config.latitude.zip(config.longitude);
+    }
+    match *FIX.lock().unwrap() {
+        Some((lat, lon, at)) if at.elapsed() < Duration::from_secs(GPS_FIX_MAX_AGE_SECS) => Some((lat, lon)),
+        _ => None,
+    }
+}
+
+/// Latitude and longitude from a GGA or RMC sentence with a valid checksum and a fix.
+fn parse_sentence(sentence: &str) -> Option<(f64, f64)> {
+    let body = sentence.strip_prefix('$')?;
+    let (body, checksum) = body.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    if body.bytes().fold(0_u8, |acc, b| acc ^ b) != expected {
+        return None;
+    }
+    let fields: Vec<&str> = body.split(',').collect();
+    let kind = fields.first()?.get(2..)?;
+    let (lat_at, has_fix) = match kind {
+        "GGA" => (2, fields.get(6).is_some_and(|q| !q.is_empty() && *q != "0")),
+        "RMC" => (3, fields.get(2) == Some(&"A")),
+        _ => return None,
+    };
+    if !has_fix {
+        return None;
+    }
+    let lat = coordinate(fields.get(lat_at)?, fields.get(lat_at + 1)?, 2)?;
+    let lon = coordinate(fields.get(lat_at + 2)?, fields.get(lat_at + 3)?, 3)?;
+    Some((lat, lon))
+}
+
+/// NMEA's `ddmm.mmmm`/`dddmm.mmmm` and a hemisphere letter, as signed decimal degrees.
+fn coordinate(value: &str, hemisphere: &str, degree_digits: usize) -> Option<f64> {
+    let degrees: f64 = value.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = value.get(degree_digits..)?.parse().ok()?;
+    let decimal = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(decimal),
+        "S" | "W" => Some(-decimal),
+        _ => None,
+    }
+}
@@ -0,0 +1,54 @@
+use crate::SETTINGS;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+lazy_static! {
+    /// When the main loop last got a good reading out of the unit.
+    static ref LAST_SUCCESSFUL_POLL: RwLock<Option<Instant>> = RwLock::new(None);
+}
+
+pub async fn record_poll() {
+    *LAST_SUCCESSFUL_POLL.write().await = Some(Instant::now());
+}
+
+/// Serves `/healthz` on `port` for liveness and readiness probes: 200 while the last good
+/// poll is within twice the poll interval, 503 before the first one or once it's older.
+pub async fn serve(port: u16) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            error!("Can't listen for health checks on port {port}: {e}");
+            return;
+        }
+    };
+    info!("Serving health checks on port {port}.");
+    if let Err(e) = server.await {
+        error!("Health check server stopped: {e}");
+    }
+}
+
+async fn respond(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/healthz" {
+        return Ok(reply(StatusCode::NOT_FOUND, "not found\n"));
+    }
+    // read per request, so a reloaded poll_interval_secs moves the window too
+    let window = SETTINGS.read().await.poll_interval() * 2;
+    Ok(match *LAST_SUCCESSFUL_POLL.read().await {
+        Some(at) if at.elapsed() <= window => reply(StatusCode::OK, "ok\n"),
+        Some(at) => reply(StatusCode::SERVICE_UNAVAILABLE, &format!("last good poll {}s ago\n", at.elapsed().as_secs())),
+        None => reply(StatusCode::SERVICE_UNAVAILABLE, "no good poll yet\n"),
+    })
+}
+
+fn reply(status: StatusCode, body: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
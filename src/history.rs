@@ -0,0 +1,234 @@
+use crate::config::AppConfig;
+use crate::consts::{DEFAULT_HISTORY_IMPORT_HOURS, HISTORY_CHUNK_BYTES};
+use crate::device::{
+    model_history_bytes, GeigerDevice, SAVE_DATA_EVERY_HOUR, SAVE_DATA_EVERY_MINUTE, SAVE_DATA_MINUTE_OVER_THRESHOLD,
+};
+use crate::errors::GQGMCMQTTError;
+use crate::ipc::PublishMessage;
+use crate::payload::{Payload, PayloadValueType, StatePayload};
+use crate::poll_state::PollState;
+use crate::protocol::decode_count;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+
+// Every special entry in the log starts 55 AA, then one of these.
+const MARKER: [u8; 2] = [0x55, 0xAA];
+/// YY MM DD HH MI SS 55 AA and the SaveDataType the samples after it were logged with.
+const ENTRY_DATETIME: u8 = 0x00;
+const ENTRY_TWO_BYTE: u8 = 0x01;
+/// A length byte, then that many bytes of text noted on the unit.
+const ENTRY_NOTE: u8 = 0x02;
+const ENTRY_THREE_BYTE: u8 = 0x03;
+const ENTRY_FOUR_BYTE: u8 = 0x04;
+/// Flash reads as this once erased, so a chunk of nothing else is past the end of the log.
+const ERASED: u8 = 0xFF;
+
+/// One logged sample, at the unit's local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryRecord {
+    pub at: NaiveDateTime,
+    pub cpm: u32,
+}
+
+/// The history log's bytes from the start of flash up to the first erased chunk.
+pub async fn read_log<D: GeigerDevice>(gmc: &mut D, model: &str) -> Result<Vec<u8>, GQGMCMQTTError> {
+    let mut log = vec![];
+    let size = model_history_bytes(model);
+    let mut addr = 0_u32;
+    while addr < size {
+        let len = (size - addr).min(HISTORY_CHUNK_BYTES as u32) as u16;
+        let chunk = gmc.read_history(addr, len).await?;
+        if chunk.iter().all(|b| *b == ERASED) {
+            break;
+        }
+        log.extend(chunk);
+        addr += len as u32;
+    }
+    let end = log.iter().rposition(|b| *b != ERASED).map_or(0, |i| i + 1);
+    log.truncate(end);
+    Ok(log)
+}
+
+/// Walks the log, stamping each sample from the last date/time entry plus one logging
+/// interval per sample since. Samples ahead of the first date/time entry can't be placed
+/// and are dropped; so are per-second CPS samples, which make a poor CPM.
+pub fn parse_log(log: &[u8]) -> Vec<HistoryRecord> {
+    let mut records = vec![];
+    // the time the next sample was taken and the step to the one after, None while CPS
+    let mut clock: Option<(NaiveDateTime, Option<Duration>)> = None;
+    let mut i = 0;
+    while i < log.len() {
+        let (count, len) = match (log[i..].starts_with(&MARKER), log.get(i + 2)) {
+            (true, Some(&ENTRY_DATETIME)) => {
+                let Some(entry) = log.get(i + 3..i + 12) else { break };
+                clock = entry_datetime(entry).map(|at| (at, sample_step(entry[8])));
+                if clock.is_none() {
+                    debug!("Skipping unreadable history date/time entry {entry:02x?}");
+                }
+                i += 12;
+                continue;
+            }
+            (true, Some(&ENTRY_NOTE)) => {
+                let Some(&len) = log.get(i + 3) else { break };
+                i += 4 + len as usize;
+                continue;
+            }
+            (true, Some(&ENTRY_TWO_BYTE)) => (wide_count(log.get(i + 3..i + 5)), 5),
+            (true, Some(&ENTRY_THREE_BYTE)) => (wide_count(log.get(i + 3..i + 6)), 6),
+            (true, Some(&ENTRY_FOUR_BYTE)) => (wide_count(log.get(i + 3..i + 7)), 7),
+            _ => (Some(log[i] as u32), 1),
+        };
+        let Some(count) = count else { break };
+        if let Some((at, step)) = &mut clock {
+            // a CPS sample still moves the clock on, by its one second
+            let step = step.unwrap_or(Duration::seconds(1));
+            if step > Duration::seconds(1) {
+                records.push(HistoryRecord { at: *at, cpm: count });
+            }
+            *at += step;
+        }
+        i += len;
+    }
+    records
+}
+
+/// The logged CPM since the last reading the gateway saw, oldest first, as states for the
+/// CPM sensor.
+pub async fn import<D: GeigerDevice>(gmc: &mut D, config: &AppConfig, state: &PollState) -> Vec<PublishMessage> {
+    let Some(identity) = &state.identity else {
+        return vec![];
+    };
+    let log = match read_log(gmc, &identity.model).await {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("Couldn't read the unit's history log: {e}");
+            return vec![];
+        }
+    };
+    let since = match state.last_reading {
+        Some((_, at)) => at,
+        None => Utc::now() - Duration::hours(DEFAULT_HISTORY_IMPORT_HOURS),
+    };
+    let mut readings = parse_log(&log)
+        .into_iter()
+        .filter_map(|r| config.unit_time_to_utc(r.at).map(|at| (at, r.cpm)))
+        .filter(|(at, _)| *at > since)
+        .collect::<Vec<_>>();
+    // the log is a ring, so once it has wrapped the newest samples come first
+    readings.sort_by_key(|(at, _)| *at);
+    info!("Importing {} logged readings from the unit's {} byte history log.", readings.len(), log.len());
+    readings
+        .into_iter()
+        .map(|(at, cpm)| PublishMessage {
+            topic: format!("gqgmcmqtt/{}/geiger_counter_cpm", identity.serial),
+            payload: Payload::CurrentState(StatePayload {
+                value: PayloadValueType::Int(cpm as i64),
+                last_seen: Some(at),
+                ..Default::default()
+            }),
+            retain: false,
+        })
+        .collect()
+}
+
+fn entry_datetime(entry: &[u8]) -> Option<NaiveDateTime> {
+    if entry[6..8] != MARKER {
+        return None;
+    }
+    NaiveDate::from_ymd_opt(2000 + entry[0] as i32, entry[1] as u32, entry[2] as u32)
+        .and_then(|d| d.and_hms_opt(entry[3] as u32, entry[4] as u32, entry[5] as u32))
+}
+
+/// None for the per-second modes, whose samples are CPS.
+fn sample_step(save_data_type: u8) -> Option<Duration> {
+    match save_data_type {
+        SAVE_DATA_EVERY_MINUTE | SAVE_DATA_MINUTE_OVER_THRESHOLD => Some(Duration::minutes(1)),
+        SAVE_DATA_EVERY_HOUR => Some(Duration::hours(1)),
+        _ => None,
+    }
+}
+
+fn wide_count(bytes: Option<&[u8]>) -> Option<u32> {
+    bytes.map(decode_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A date/time entry for `at`, with the SaveDataType the samples after it use.
+    fn datetime(at: &str, save_data_type: u8) -> Vec<u8> {
+        let at = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M:%S").unwrap();
+        let stamp = at.format("%y %m %d %H %M %S").to_string();
+        let mut entry = vec![0x55, 0xAA, ENTRY_DATETIME];
+        entry.extend(stamp.split(' ').map(|n| n.parse::<u8>().unwrap()));
+        entry.extend_from_slice(&[0x55, 0xAA, save_data_type]);
+        entry
+    }
+
+    fn record(at: &str, cpm: u32) -> HistoryRecord {
+        HistoryRecord { at: NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M:%S").unwrap(), cpm }
+    }
+
+    #[test]
+    fn multi_byte_entries() {
+        let mut log = datetime("2026-10-14 03:00:00", SAVE_DATA_EVERY_MINUTE);
+        log.push(20);
+        log.extend_from_slice(&[0x55, 0xAA, ENTRY_TWO_BYTE, 0x01, 0x2c]);
+        log.extend_from_slice(&[0x55, 0xAA, ENTRY_THREE_BYTE, 0x01, 0x00, 0x00]);
+        log.extend_from_slice(&[0x55, 0xAA, ENTRY_FOUR_BYTE, 0x00, 0x02, 0x00, 0x01]);
+        assert_eq!(
+            parse_log(&log),
+            [
+                record("2026-10-14 03:00:00", 20),
+                record("2026-10-14 03:01:00", 300),
+                record("2026-10-14 03:02:00", 0x1_0000),
+                record("2026-10-14 03:03:00", 0x2_0001),
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_take_no_time() {
+        let mut log = datetime("2026-10-14 03:00:00", SAVE_DATA_EVERY_HOUR);
+        log.push(18);
+        log.extend_from_slice(&[0x55, 0xAA, ENTRY_NOTE, 4]);
+        log.extend_from_slice(b"roof");
+        log.push(22);
+        assert_eq!(parse_log(&log), [record("2026-10-14 03:00:00", 18), record("2026-10-14 04:00:00", 22)]);
+    }
+
+    #[test]
+    fn samples_before_the_first_datetime_are_dropped() {
+        let mut log = vec![17, 19, 0x55, 0xAA, ENTRY_TWO_BYTE, 0x00, 0x15];
+        log.extend(datetime("2026-10-14 03:00:00", SAVE_DATA_EVERY_MINUTE));
+        log.push(23);
+        assert_eq!(parse_log(&log), [record("2026-10-14 03:00:00", 23)]);
+    }
+
+    #[test]
+    fn a_datetime_mid_stream_resets_the_clock() {
+        let mut log = datetime("2026-10-14 03:00:00", SAVE_DATA_EVERY_MINUTE);
+        log.extend_from_slice(&[20, 21]);
+        // the unit was switched to per-second logging for a while, then back
+        log.extend(datetime("2026-10-14 05:30:00", SAVE_DATA_EVERY_SECOND));
+        log.extend_from_slice(&[1, 0]);
+        log.extend(datetime("2026-10-14 06:00:00", SAVE_DATA_EVERY_MINUTE));
+        log.push(24);
+        assert_eq!(
+            parse_log(&log),
+            [
+                record("2026-10-14 03:00:00", 20),
+                record("2026-10-14 03:01:00", 21),
+                record("2026-10-14 06:00:00", 24),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_cut_off_entry_ends_the_log() {
+        let mut log = datetime("2026-10-14 03:00:00", SAVE_DATA_EVERY_MINUTE);
+        log.push(20);
+        log.extend_from_slice(&[0x55, 0xAA, ENTRY_FOUR_BYTE, 0x00]);
+        assert_eq!(parse_log(&log), [record("2026-10-14 03:00:00", 20)]);
+    }
+}
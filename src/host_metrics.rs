@@ -0,0 +1,29 @@
+use lazy_static::lazy_static;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Pins the start time; call once at startup so uptime isn't measured from first use.
+pub fn init() {
+    lazy_static::initialize(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+/// Resident memory of this process in bytes, from `VmRSS` in /proc/self/status.
+#[cfg(target_os = "linux")]
+pub fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rss_bytes() -> Option<u64> {
+    None
+}
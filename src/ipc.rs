@@ -1,4 +1,9 @@
+use crate::consts::MPSC_SEND_TIMEOUT_MILLIS;
+use crate::errors::GqError;
 use crate::payload::Payload;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tokio::time::Duration;
 
 #[derive(Clone)]
 pub struct InboundMessage {
@@ -12,6 +17,13 @@ pub struct InboundMessage {
 pub struct PublishMessage {
     pub(crate) topic: String,
     pub(crate) payload: Payload,
+    pub(crate) retain: bool,
+}
+
+/// Every publish from one poll cycle, sent as a single channel message.
+#[derive(Clone)]
+pub struct BatchMessage {
+    pub(crate) messages: Vec<PublishMessage>,
 }
 
 #[derive(Clone)]
@@ -25,7 +37,33 @@ pub struct IPCError {
 pub enum IPCMessage {
     Inbound(InboundMessage),
     Outbound(PublishMessage),
+    Batch(BatchMessage),
     PleaseReconnect(String, u8),
+    /// The broker connection came back after having been up before.
+    MqttReconnected,
+    /// The broker connection dropped; a MqttReconnected follows once it's back.
+    MqttDisconnected,
     Error(IPCError),
     Shutdown,
 }
+
+/// Hands `msg` to the MQTT side, giving a backed-up channel MPSC_SEND_TIMEOUT_MILLIS to
+/// make room rather than waiting on it forever.
+pub async fn queue(tx: &mpsc::Sender<IPCMessage>, msg: IPCMessage) -> Result<(), GqError> {
+    match tx.send_timeout(msg, Duration::from_millis(MPSC_SEND_TIMEOUT_MILLIS)).await {
+        Ok(()) => Ok(()),
+        Err(SendTimeoutError::Timeout(_)) => Err(GqError::ChannelFull(MPSC_SEND_TIMEOUT_MILLIS)),
+        Err(SendTimeoutError::Closed(_)) => Err(GqError::ChannelClosed),
+    }
+}
+
+/// Logs a full channel and carries on; only a closed one is passed back.
+pub fn tolerate_full(result: Result<(), GqError>) -> Result<(), GqError> {
+    match result {
+        Err(e @ GqError::ChannelFull(_)) => {
+            warn!("{e}");
+            Ok(())
+        }
+        other => other,
+    }
+}
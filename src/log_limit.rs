@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+/// Lets the same message through at most once per interval, counting what it held back
+/// so the next one that gets through can say how many were skipped.
+#[derive(Debug, Default)]
+pub struct LogLimiter {
+    seen: HashMap<String, (Instant, u32)>,
+}
+
+impl LogLimiter {
+    /// Some(suppressed count) if `key` should be logged now, None to stay quiet.
+    pub fn should_log(&mut self, key: &str, interval: Duration) -> Option<u32> {
+        let now = Instant::now();
+        match self.seen.get_mut(key) {
+            Some((last, suppressed)) if now.duration_since(*last) < interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((last, suppressed)) => {
+                let count = *suppressed;
+                *last = now;
+                *suppressed = 0;
+                Some(count)
+            }
+            None => {
+                self.seen.insert(key.to_string(), (now, 0));
+                Some(0)
+            }
+        }
+    }
+
+    /// Forget everything, e.g. once the device is answering again, so the next failure is
+    /// logged straight away.
+    pub fn clear(&mut self) {
+        self.seen.clear();
+    }
+}
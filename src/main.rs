@@ -5,39 +5,57 @@ mod config;
 mod mqtt_poll;
 mod payload;
 mod ipc;
+mod device;
+mod commands;
+mod poll_state;
+mod stream_device;
+mod serial_device;
+mod protocol;
+mod signing;
+mod log_limit;
+mod host_metrics;
+mod websocket;
+mod topics;
+mod gps;
+mod cli;
+mod metrics;
+mod history;
+#[cfg(feature = "health")]
+mod health;
 
 #[macro_use] extern crate tokio;
 #[macro_use] extern crate tracing;
 
-use std::fs;
-use crate::config::AppConfig;
+use crate::cli::{Cli, Command, ARGS};
+use crate::config::{AppConfig, Connection};
+use futures::future::join_all;
 use lazy_static::lazy_static;
+use std::collections::HashSet;
 use std::process;
-use std::thread::sleep;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 use tokio::sync::{broadcast, mpsc, RwLock};
-use gqgmclib::GMC;
-use crate::consts::{MPSC_BUFFER_SIZE, POLL_TIME};
-use crate::ipc::{IPCMessage, PublishMessage};
-use crate::mqtt_connection::MqttConnection;
-use crate::mqtt_poll::mqtt_poll_loop;
-use crate::payload::{generate_payloads, Payload};
+use tokio::time::{timeout, Duration, Instant};
+use crate::consts::{BROADCAST_CHANNEL_CAPACITY, CLOCK_SYNC_INTERVAL_SECS, DEFAULT_CLIENT_ID, DEFAULT_MAX_PAYLOAD_BYTES, DEFAULT_SHUTDOWN_GRACE_MILLIS, MPSC_BUFFER_SIZE, MQTT_POLL_INTERVAL_MILLIS, SERIAL_RETRY_MAX_SECS, SPIKE_CHECK_MILLIS};
+use crate::commands::{addressed_to, handle_inbound, NvmGuard};
+use crate::errors::GqError;
+use crate::ipc::{queue, tolerate_full, BatchMessage, IPCMessage, PublishMessage};
+use crate::mqtt_connection::{tls_configuration, MqttConnection};
+use crate::topics::{gateway_availability_topic, outbound_topic};
+use crate::mqtt_poll::{mqtt_poll_loop, outbound_message};
+use crate::device::{connect_device, probe_features, sync_clock, DeviceFeature, TimedRead};
+use crate::poll_state::PollState;
+use crate::payload::{absent_removal, check_spike, combined_state_message, device_config_message, discovery_event, generate_payloads, offline_states, purge_discovery, restored_reading, read_identity, sensor_availability, swapped_unit_removal, unavailable_states, unknown_states, DiagnosticsPayload, FleetPayload, GatewayStatus, Payload};
 
 
 lazy_static! {
         //region create SETTINGS static object
     static ref SETTINGS: RwLock<AppConfig> = RwLock::new({
-         let cfg_file = match std::env::var("CONFIG_FILE_PATH") {
-             Ok(s) => s,
-             Err(_e) => { "./config.yaml".to_string()}
-         };
-        let yaml = fs::read_to_string(cfg_file).unwrap_or_else(|e| {
-            die(&format!("Can't read config file: {e}"));
-            String::default()
-            });
-        let gc: AppConfig = match serde_yaml::from_str(&yaml)  {
+        let gc: AppConfig = match ARGS.load_config() {
             Ok(gc) => gc,
-            Err(e) => { die(&format!("Couldn't deserialize AppConfig: {e}"));
+            Err(e) => { die(&e.to_string());
             AppConfig::default()}
         };
         gc
@@ -47,70 +65,710 @@ lazy_static! {
 
 #[tokio::main]
 pub async fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-//region create mqtt server connection and spawn mqtt thread
-    let config = SETTINGS.read().await;
-    let mqtt_conn = match MqttConnection::new(
-        config
-            .mqtt_client_id
-            .clone()
-            .unwrap_or("sunspec_gateway".to_string()),
-        config.mqtt_server_addr.clone(),
-        config.mqtt_server_port.unwrap_or(1883),
-        config.mqtt_username.clone(),
-        config.mqtt_password.clone(),
-    )
-        .await
-    {
-        Ok(m) => m,
-        Err(_e) => {
-            return die("Couldn't create mqtt connection object: {e}");
-        }
+    let filter = match &ARGS.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::from_default_env(),
     };
+    // logging has to be up before SETTINGS loads for its warnings to show, so this peeks
+    // at the config for log_file alone; SETTINGS loads it again and reports any problems
+    let log_file = ARGS.load_config().ok().and_then(|c| c.log_file).map(|path| (file_appender(&path), path));
+    let (file_layer, file_error) = match log_file {
+        Some((Ok(appender), _)) => (Some(fmt::layer().with_writer(appender).with_ansi(false)), None),
+        Some((Err(e), path)) => (None, Some(format!("Can't log to {path}, logging to the console only: {e}"))),
+        None => (None, None),
+    };
+    tracing_subscriber::registry().with(filter).with(fmt::layer()).with(file_layer).init();
+    if let Some(e) = file_error {
+        warn!("{e}");
+    }
+    host_metrics::init();
+    match ARGS.command {
+        Some(Command::Validate) => return validate_config(),
+        Some(Command::PrintLovelace) => return print_lovelace().await,
+        None => {}
+    }
+    let run_limit = RunLimit::from_args(&ARGS);
+//region create mqtt server connections and spawn an mqtt thread per broker
+    let config = SETTINGS.read().await.clone();
+    if let Some(port) = config.devices().iter().find_map(missing_serial_port) {
+        return die(&format!("Serial port {port} doesn't exist; set serial_port to the unit's device node."));
+    }
 
     let (tx, mut rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
     let (mqtt_tx, mqtt_rx) =mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
-    let (from_mqtt_tx, mut from_mqtt_rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
-    let (broadcast_tx, _broadcast_rx) = broadcast::channel::<IPCMessage>(16_usize);
-
-    let bcasttx = broadcast_tx.clone();
-    let mqtt_handler = tokio::task::spawn(async move {
-        let _ = mqtt_poll_loop(
-            mqtt_conn,
-            mqtt_rx,
-            bcasttx.clone().subscribe(),
-            from_mqtt_tx,
+    let (from_mqtt_tx, from_mqtt_rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
+    let (broadcast_tx, _broadcast_rx) = broadcast::channel::<IPCMessage>(BROADCAST_CHANNEL_CAPACITY);
+
+    let mut broker_txs = vec![];
+    let mut mqtt_handlers = vec![];
+    // a dry run stands the printer in for every broker
+    let brokers = if ARGS.dry_run { vec![] } else { config.brokers() };
+    for broker in brokers {
+        let tls = match tls_configuration(&broker) {
+            Ok(t) => t,
+            Err(e) => return die(&e.to_string()),
+        };
+        let client_id = broker.client_id.clone().unwrap_or(DEFAULT_CLIENT_ID.to_string());
+        let availability_topic = outbound_topic(&gateway_availability_topic(&client_id), &config);
+        let mqtt_conn = match MqttConnection::new(
+            client_id,
+            broker.addr.clone(),
+            broker.port.unwrap_or(1883),
+            broker.username.clone(),
+            broker.password.clone(),
+            tls,
+            availability_topic,
+            config.mqtt_protocol_version(),
         )
-            .await;
-    });
+            .await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                return die(&format!("Couldn't create mqtt connection object for {}: {e}", broker.addr));
+            }
+        };
+        let (broker_tx, broker_rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
+        broker_txs.push(broker_tx);
+        let mqtt_config = config.clone();
+        let bcast_rx = broadcast_tx.subscribe();
+        let from_mqtt_tx = from_mqtt_tx.clone();
+        mqtt_handlers.push(tokio::task::spawn(async move {
+            let _ = mqtt_poll_loop(
+                mqtt_conn,
+                broker_rx,
+                bcast_rx,
+                from_mqtt_tx,
+                mqtt_config,
+            )
+                .await;
+        }));
+    }
+    let broker_queues = broker_txs.clone();
+    if ARGS.dry_run {
+        info!("Dry run: printing messages instead of publishing them.");
+        tokio::task::spawn(print_messages(mqtt_rx, config.clone()));
+    } else {
+        tokio::task::spawn(fan_out(mqtt_rx, broker_txs));
+    }
+    if let Some(path) = config.gps_device.clone() {
+        gps::spawn(path);
+    }
+    if let Some(port) = config.websocket_port {
+        tokio::task::spawn(websocket::serve(port, broadcast_tx.clone()));
+    }
+    if let Some(port) = config.health_port {
+        #[cfg(feature = "health")]
+        tokio::task::spawn(health::serve(port));
+        #[cfg(not(feature = "health"))]
+        warn!("health_port {port} is set, but this build has no health feature; not serving health checks.");
+    }
+    if let Some(port) = config.metrics_port {
+        #[cfg(feature = "metrics")]
+        tokio::task::spawn(metrics::serve(port));
+        #[cfg(not(feature = "metrics"))]
+        warn!("metrics_port {port} is set, but this build has no metrics feature; not serving metrics.");
+    }
     //endregion
 
-    let mut gmc = GMC::new("COM3", 57600).expect("Can't connect to unit.");
-    loop {
-        let payloads = generate_payloads(&mut gmc).await;
+    let mut device_txs = vec![];
+    let mut pollers = vec![];
+    for (index, device) in config.devices().into_iter().enumerate() {
+        let (device_tx, device_rx) = mpsc::channel::<IPCMessage>(MPSC_BUFFER_SIZE);
+        device_txs.push(device_tx);
+        pollers.push(tokio::task::spawn(poll_device(
+            device,
+            index,
+            mqtt_tx.clone(),
+            broadcast_tx.clone(),
+            device_rx,
+            run_limit,
+        )));
+    }
+    // every unit sees every command and broker event; each acts only on its own serial
+    tokio::task::spawn(fan_out(from_mqtt_rx, device_txs));
+    let mut outcome: Result<(), GqError> = Ok(());
+    for result in join_all(pollers).await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => outcome = Err(e),
+            Err(e) => error!("A device's poll loop panicked: {e}"),
+        }
+    }
+
+    //region drain outbound publishes within the grace period, then stop the mqtt threads
+    let grace = Duration::from_millis(config.shutdown_grace_ms.unwrap_or(DEFAULT_SHUTDOWN_GRACE_MILLIS));
+    let deadline = Instant::now() + grace;
+    let queued = || {
+        std::iter::once(&mqtt_tx)
+            .chain(broker_queues.iter())
+            .map(|q| q.max_capacity() - q.capacity())
+            .sum::<usize>()
+    };
+    let pending = queued();
+    while queued() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS)).await;
+    }
+    let remaining = queued();
+    info!("Flushed {} pending messages before shutdown, {remaining} left unsent.", pending.saturating_sub(remaining));
+    let _ = broadcast_tx.send(IPCMessage::Shutdown);
+    if timeout(deadline.saturating_duration_since(Instant::now()), join_all(mqtt_handlers)).await.is_err() {
+        warn!("MQTT threads didn't finish within the shutdown grace period.");
+    }
+    //endregion
+    if let Err(e) = outcome {
+        die(&e.to_string());
+    }
+}
+
+/// Polls one unit until shutdown, the run limit or a closed MQTT channel, then sends what
+/// a clean exit needs and saves its history. `index` finds its `devices` entry again on
+/// reload.
+async fn poll_device(
+    mut config: AppConfig,
+    index: usize,
+    mqtt_tx: mpsc::Sender<IPCMessage>,
+    broadcast_tx: broadcast::Sender<IPCMessage>,
+    mut from_mqtt_rx: mpsc::Receiver<IPCMessage>,
+    run_limit: RunLimit,
+) -> Result<(), GqError> {
+    let mut gmc = connect_device(&config).await;
+    let startup_delay = config.startup_delay_ms.unwrap_or(0);
+    if startup_delay > 0 {
+        info!("Waiting {startup_delay}ms for the unit to settle before polling.");
+        tokio::time::sleep(Duration::from_millis(startup_delay)).await;
+    }
+    let mut features = probe_features(&mut gmc, &config).await;
+    let mut status = GatewayStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        poll_interval_secs: config.poll_interval().as_secs(),
+        supported_features: supported_features(&features),
+        ..Default::default()
+    };
+    info!("Device supports: {:?}", status.supported_features);
+    let mut poll_state = PollState::default();
+    match read_identity(&mut gmc, &config).await {
+        Ok(identity) => {
+            if config.publish_unknown_on_start.unwrap_or(false) {
+                for msg in unknown_states(&config, &identity) {
+                    tolerate_full(queue(&mqtt_tx, IPCMessage::Outbound(msg)).await)?;
+                }
+            }
+            if let Some(path) = &config.state_file {
+                poll_state.restore(path, &identity.serial, &config);
+            }
+            poll_state.identity = Some(identity);
+            if let Some(msg) = restored_reading(&poll_state) {
+                tolerate_full(queue(&mqtt_tx, IPCMessage::Outbound(msg)).await)?;
+            }
+            if config.import_history.unwrap_or(false) {
+                // one batch, so a day of samples doesn't wait on the channel one at a time
+                let messages = history::import(&mut gmc, &config, &poll_state).await;
+                if !messages.is_empty() {
+                    tolerate_full(queue(&mqtt_tx, IPCMessage::Batch(BatchMessage { messages })).await)?;
+                }
+            }
+            publish_status(&mqtt_tx, &poll_state, &status).await?;
+        }
+        Err(e) => {
+            error!("Can't publish gateway status: {e}");
+        }
+    }
+    if config.sync_clock_on_start.unwrap_or(false) {
+        sync_clock(&mut gmc, &config).await;
+    }
+    let mut next_clock_sync = Instant::now() + Duration::from_secs(CLOCK_SYNC_INTERVAL_SECS);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut reload_rx = reload_signal();
+    let mut nvm_guard = NvmGuard::new(config.max_nvm_writes_per_min());
+    let mut brokers_down = 0_usize;
+    if config.spike_alert_cps.is_some() && !features.contains(&DeviceFeature::Cps) {
+        warn!("spike_alert_cps is set, but the unit's connection can't read CPS; no spike alerts will be sent.");
+    }
+    let mut serial_backoff = Duration::from_secs(1);
+    let mut next_reopen = Instant::now();
+    let outcome: Result<(), GqError> = 'poll: loop {
+        // timed from the start of the poll, so slow device reads don't stretch the cadence
+        let next_poll = Instant::now() + config.poll_interval();
+        let payloads = generate_payloads(&mut gmc, &config, &features, &mut poll_state).await;
         info!(?payloads);
-        for payload in payloads {
-            if let Err(e) = mqtt_tx.send(
-                IPCMessage::Outbound(PublishMessage {
-                    topic: payload.config_topic,
-                    payload: Payload::Config(payload.config.clone())
-                })
-            ).await {
-                die(&e.to_string());
-            }
-            if let Err(e) = mqtt_tx.send(
-                IPCMessage::Outbound(PublishMessage {
+        metrics::record_poll();
+        #[cfg(feature = "health")]
+        if poll_state.last_read_ok {
+            health::record_poll().await;
+        }
+        let mut messages = vec![];
+        if let Some(removal) = absent_removal(&config, &mut poll_state, brokers_down == 0) {
+            messages = removal;
+        }
+        messages.extend(swapped_unit_removal(&mut poll_state));
+        messages.extend(unavailable_states(&config, &mut poll_state));
+        let combined = match (&poll_state.identity, config.combined_state.unwrap_or(false)) {
+            (Some(identity), true) if !poll_state.removed && !payloads.is_empty() => {
+                Some(combined_state_message(&identity.serial, &payloads))
+            }
+            _ => None,
+        };
+        for payload in payloads.into_iter().filter(|_| !poll_state.removed) {
+            // buttons only take commands, so they have no state to send
+            let stateless = payload.config_topic.starts_with("homeassistant/button/");
+            let separate = !stateless && (combined.is_none() || payload.config.state_topic == payload.state_topic);
+            messages.push(PublishMessage {
+                topic: payload.config_topic,
+                payload: Payload::Config(payload.config.clone()),
+                // retained so HA gets the entities back from a restarted broker
+                retain: true,
+            });
+            if separate {
+                messages.push(PublishMessage {
                     topic: payload.state_topic,
-                    payload: Payload::CurrentState(payload.state.clone())
-                })
-            ).await {
-                die(&e.to_string());
+                    payload: Payload::CurrentState(payload.state.clone()),
+                    retain: false,
+                });
+            }
+        }
+        messages.extend(combined);
+        messages.extend(sensor_availability(&config, &poll_state));
+        if config.announce_discovery.unwrap_or(false) {
+            if let Some(msg) = discovery_event(&mut poll_state) {
+                messages.push(msg);
+            }
+        }
+        if config.publish_device_config.unwrap_or(false) {
+            if let Some(msg) = device_config_message(&mut gmc, &features, &mut poll_state).await {
+                messages.push(msg);
+            }
+        }
+        if let (Some(identity), Some(raw)) = (&poll_state.identity, poll_state.captured.take()) {
+            messages.push(PublishMessage {
+                topic: format!("gqgmcmqtt/{}/diagnostics", identity.serial),
+                payload: Payload::Diagnostics(DiagnosticsPayload { raw }),
+                retain: false,
+            });
+        }
+        if config.fleet_topic.unwrap_or(false) {
+            if let (Some(identity), Some(reading)) = (&poll_state.identity, &poll_state.grafana) {
+                let position = gps::position(&config);
+                messages.push(PublishMessage {
+                    topic: format!("gqgmc/fleet/{}", identity.serial),
+                    payload: Payload::Fleet(FleetPayload {
+                        serial: identity.serial.clone(),
+                        model: identity.model.clone(),
+                        cpm: reading.cpm,
+                        ts: reading.ts,
+                        lat: position.map(|(lat, _)| lat),
+                        lon: position.map(|(_, lon)| lon),
+                    }),
+                    retain: false,
+                });
+            }
+        }
+        if config.grafana_output.unwrap_or(false) {
+            if let (Some(identity), Some(reading)) = (&poll_state.identity, poll_state.grafana.take()) {
+                messages.push(PublishMessage {
+                    topic: format!("gqgmcmqtt/{}/grafana", identity.serial),
+                    payload: Payload::Grafana(reading),
+                    retain: false,
+                });
+            }
+        }
+        if config.websocket_port.is_some() {
+            let states = messages
+                .iter()
+                .filter(|m| matches!(m.payload, Payload::CurrentState(_) | Payload::CombinedState(_)))
+                .cloned()
+                .collect::<Vec<PublishMessage>>();
+            // the MQTT threads skip batches on the broadcast channel; only WebSocket clients use them
+            let _ = broadcast_tx.send(IPCMessage::Batch(BatchMessage { messages: states }));
+        }
+        if let Some(path) = &config.state_file {
+            poll_state.save_due(path);
+        }
+        if config.sync_clock_on_start.unwrap_or(false) && Instant::now() >= next_clock_sync {
+            // the units' clocks drift by seconds a day, so once a day keeps logs honest
+            sync_clock(&mut gmc, &config).await;
+            next_clock_sync = Instant::now() + Duration::from_secs(CLOCK_SYNC_INTERVAL_SECS);
+        }
+        let queued = if config.batch_publish.unwrap_or(false) {
+            tolerate_full(queue(&mqtt_tx, IPCMessage::Batch(BatchMessage { messages })).await)
+        } else {
+            let mut queued = Ok(());
+            for msg in messages {
+                queued = tolerate_full(queue(&mqtt_tx, IPCMessage::Outbound(msg)).await);
+                if queued.is_err() {
+                    break;
+                }
+            }
+            queued
+        };
+        if let Err(e) = queued {
+            break 'poll Err(e);
+        }
+        if let (true, None, Some(identity)) = (poll_state.last_read_ok, &status.status, &poll_state.identity) {
+            // the serial and model are only known now, so the birth can't go out at connect
+            info!(
+                serial = %identity.serial,
+                model = %identity.model,
+                version = %status.version,
+                poll_interval_secs = status.poll_interval_secs,
+                "Gateway online."
+            );
+            status.status = Some("online".to_string());
+            status.model = Some(identity.model.clone());
+            if let Err(e) = publish_status(&mqtt_tx, &poll_state, &status).await {
+                break 'poll Err(e);
+            }
+        }
+        if run_limit == RunLimit::Once {
+            info!("Published one poll, exiting.");
+            break 'poll Ok(());
+        }
+        if poll_state.last_read_ok {
+            serial_backoff = Duration::from_secs(1);
+        } else if poll_state.failed_streak >= config.serial_reconnect_after() && Instant::now() >= next_reopen {
+            // a renumerated USB adapter leaves the old handle dead; reopening finds it again
+            warn!("{} reads failed in a row, reopening the unit.", poll_state.failed_streak);
+            drop(gmc);
+            gmc = connect_device(&config).await;
+            features = probe_features(&mut gmc, &config).await;
+            status.supported_features = supported_features(&features);
+            status.record_serial_reconnect();
+            if let Err(e) = publish_status(&mqtt_tx, &poll_state, &status).await {
+                break 'poll Err(e);
+            }
+            // if it still won't answer, the next reopen waits longer
+            next_reopen = Instant::now() + serial_backoff;
+            serial_backoff = (serial_backoff * 2).min(Duration::from_secs(SERIAL_RETRY_MAX_SECS));
+        }
+        // a spike check between polls mustn't push the next poll back
+        let spike_check = config.spike_alert_cps.is_some() && features.contains(&DeviceFeature::Cps);
+        loop {
+            select! {
+                _ = &mut shutdown => {
+                    info!("Received shutdown signal, stopping device polling.");
+                    break 'poll Ok(());
+                }
+                _ = tokio::time::sleep_until(next_poll) => {}
+                _ = tokio::time::sleep_until(run_limit.deadline().unwrap_or(next_poll)), if run_limit.deadline().is_some() => {
+                    info!("Reached the end of --duration, exiting.");
+                    break 'poll Ok(());
+                }
+                _ = tokio::time::sleep(Duration::from_millis(SPIKE_CHECK_MILLIS)), if spike_check => {
+                    if let Some(alert) = check_spike(&mut gmc, &config, &mut poll_state).await {
+                        if let Err(e) = tolerate_full(queue(&mqtt_tx, IPCMessage::Outbound(alert)).await) {
+                            break 'poll Err(e);
+                        }
+                    }
+                    // keep waiting for the poll that was due
+                    continue;
+                }
+                Some(()) = reload_rx.recv() => {
+                    match ARGS.load_config() {
+                        Ok(full_config) => {
+                            let mut settings = SETTINGS.write().await;
+                            for key in settings.restart_required(&full_config) {
+                                warn!("{key} changed in the reloaded config, but only takes effect after a restart.");
+                            }
+                            *settings = full_config.clone();
+                            drop(settings);
+                            let Some(new_config) = full_config.devices().into_iter().nth(index) else {
+                                warn!("Device {index} is gone from the reloaded config; restart to stop polling it.");
+                                continue;
+                            };
+                            let serial_changed = config.serial_changed(&new_config);
+                            config = new_config;
+                            nvm_guard.max_per_min = config.max_nvm_writes_per_min();
+                            status.poll_interval_secs = config.poll_interval().as_secs();
+                            info!("Reloaded configuration.");
+                            if serial_changed {
+                                info!(
+                                    "Serial settings changed by config reload, reconnecting to {} at {} baud.",
+                                    config.serial_port(),
+                                    config.serial_baud_setting()
+                                );
+                                drop(gmc);
+                                gmc = connect_device(&config).await;
+                                features = probe_features(&mut gmc, &config).await;
+                                status.supported_features = supported_features(&features);
+                                status.record_serial_reconnect();
+                                if let Err(e) = publish_status(&mqtt_tx, &poll_state, &status).await {
+                                    break 'poll Err(e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("Couldn't reload config, keeping the current one: {e}");
+                        }
+                    }
+                }
+                Some(msg) = from_mqtt_rx.recv() => {
+                    // a handled command falls through to an immediate re-poll, which refreshes
+                    // every state topic from the device
+                    match msg {
+                        IPCMessage::Inbound(inbound) => {
+                            if !addressed_to(&inbound, poll_state.identity.as_ref()) {
+                                debug!("Ignoring command for {}, which isn't this unit", inbound.serial_number);
+                            } else if config.command_allowed(&inbound.point_name) {
+                                handle_inbound(&mut TimedRead(&mut gmc, config.serial_read_timeout()), &config, &mqtt_tx, &mut nvm_guard, &mut poll_state, inbound).await;
+                            } else {
+                                warn!("Refusing {} command, it isn't in the allowed command set", inbound.point_name);
+                            }
+                        }
+                        IPCMessage::MqttDisconnected => {
+                            brokers_down += 1;
+                        }
+                        IPCMessage::MqttReconnected => {
+                            brokers_down = brokers_down.saturating_sub(1);
+                            status.record_mqtt_reconnect();
+                            if let Err(e) = publish_status(&mqtt_tx, &poll_state, &status).await {
+                                break 'poll Err(e);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
             }
+            break;
+        }
+    };
+
+    if run_limit != RunLimit::Forever {
+        // a bounded run isn't coming back by itself, so don't leave HA showing its last values
+        for msg in offline_states(&poll_state) {
+            if let Err(e) = mqtt_tx.send(IPCMessage::Outbound(msg)).await {
+                error!("Couldn't queue offline state: {e}");
+            }
+        }
+    }
+    if outcome.is_ok() && config.purge_discovery_on_exit.unwrap_or(false) {
+        let purge = purge_discovery(&mut poll_state);
+        info!("Removing {} discovered entities from Home Assistant before exiting.", purge.len());
+        for msg in purge {
+            if let Err(e) = mqtt_tx.send(IPCMessage::Outbound(msg)).await {
+                error!("Couldn't queue discovery removal: {e}");
+            }
+        }
+    }
+    if outcome.is_ok() && status.status.is_some() {
+        status.status = Some("offline".to_string());
+        if let Err(e) = publish_status(&mqtt_tx, &poll_state, &status).await {
+            error!("Couldn't queue offline status: {e}");
+        }
+    }
+    if let Some(path) = &config.state_file {
+        match poll_state.save(path) {
+            Ok(()) => info!("Saved reading history to {path}."),
+            Err(e) => error!("Couldn't save reading history: {e}"),
+        }
+    }
+    outcome
+}
+
+/// `gqgmcmqtt validate`: load and check the config, print what it resolves to and exit,
+/// without touching the serial port or the broker.
+fn validate_config() {
+    let path = ARGS.config_path();
+    let config = match ARGS.load_config() {
+        Ok(c) => c,
+        Err(e) => return die(&e.to_string()),
+    };
+    println!("{path} is valid.");
+    for broker in config.brokers() {
+        println!("mqtt: {}:{} as {}", broker.addr, broker.port.unwrap_or(1883), broker.client_id.unwrap_or(DEFAULT_CLIENT_ID.to_string()));
+    }
+    match config.connection() {
+        Connection::Tcp => println!("device: tcp {}", config.device_addr.clone().unwrap_or_default()),
+        Connection::Serial => println!("device: {} at {} baud, {}", config.serial_port(), config.serial_baud_setting(), config.serial_framing()),
+    }
+    println!("state schema: {:?}", config.state_schema.clone().unwrap_or_default());
+    println!("batch publish: {}", config.batch_publish.unwrap_or(false));
+    println!("max payload bytes: {}", config.max_payload_bytes.unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES));
+    if let Some(ratio) = config.alert_ratio {
+        println!("rapid increase alert: {ratio}x baseline, clearing under {}x", config.alert_off_ratio.unwrap_or(ratio));
+    }
+    if let Some(quiet) = &config.quiet_hours {
+        println!("quiet hours: {}-{}", quiet.start, quiet.end);
+    }
+    let mut sensors = config.sensors.clone().unwrap_or_default().into_iter().collect::<Vec<_>>();
+    sensors.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, sensor) in sensors {
+        println!("sensor {name}: enabled={:?} name={:?}", sensor.enabled, sensor.name);
+    }
+}
 
+/// `gqgmcmqtt print-lovelace`: poll the unit once, without connecting to MQTT, and print
+/// an entities card listing exactly the entities that poll would announce.
+async fn print_lovelace() {
+    let config = match ARGS.load_config() {
+        // the first unit, when there are several
+        Ok(c) => c.devices().remove(0),
+        Err(e) => return die(&e.to_string()),
+    };
+    let mut gmc = connect_device(&config).await;
+    let features = probe_features(&mut gmc, &config).await;
+    let mut poll_state = PollState::default();
+    // the std dev sensor needs two readings before it's announced, so poll twice
+    let _ = generate_payloads(&mut gmc, &config, &features, &mut poll_state).await;
+    let payloads = generate_payloads(&mut gmc, &config, &features, &mut poll_state).await;
+    let Some(identity) = &poll_state.identity else {
+        return die("Couldn't identify the unit, see the log for why.");
+    };
+    println!("type: entities");
+    println!("title: {} {}", identity.model, identity.serial);
+    println!("entities:");
+    for payload in payloads {
+        println!("  - entity: {}", payload.config.entity_id);
+        println!("    name: {}", payload.config.name);
+    }
+}
+
+/// Copies every message to each receiver: outbound ones to each broker's thread, and
+/// inbound ones to each unit's poll loop. try_send, so one that's backed up drops its
+/// own messages rather than stalling the rest.
+async fn fan_out(mut rx: mpsc::Receiver<IPCMessage>, receivers: Vec<mpsc::Sender<IPCMessage>>) {
+    while let Some(msg) = rx.recv().await {
+        for receiver in &receivers {
+            if let Err(e) = receiver.try_send(msg.clone()) {
+                warn!("Couldn't queue message: {e}");
+            }
+        }
+    }
+}
+
+/// `--dry-run`: each message as it would be published, topic first, then its payload as
+/// pretty JSON.
+async fn print_messages(mut rx: mpsc::Receiver<IPCMessage>, config: AppConfig) {
+    let client_id = config.brokers().first().and_then(|b| b.client_id.clone()).unwrap_or(DEFAULT_CLIENT_ID.to_string());
+    let availability_topic = outbound_topic(&gateway_availability_topic(&client_id), &config);
+    while let Some(msg) = rx.recv().await {
+        let messages = match msg {
+            IPCMessage::Outbound(msg) => vec![msg],
+            IPCMessage::Batch(batch) => batch.messages,
+            _ => continue,
+        };
+        for msg in messages {
+            let msg = outbound_message(msg, &config, &availability_topic);
+            let body = match &msg.payload {
+                Payload::None => "(empty)".to_string(),
+                payload => serde_json::to_string_pretty(payload).unwrap_or_else(|e| format!("(unserializable: {e})")),
+            };
+            println!("{}{}\n{body}\n", msg.topic, if msg.retain { " (retained)" } else { "" });
         }
-        sleep(tokio::time::Duration::from_secs(POLL_TIME as u64));
+    }
+}
+
+/// A device-node `serial_port` that isn't there, caught at startup since it's almost
+/// always a typo or the wrong default. A node that vanishes later (unplugged) is retried.
+fn missing_serial_port(config: &AppConfig) -> Option<String> {
+    let port = config.serial_port();
+    let is_node = port.starts_with('/');
+    (config.connection() == Connection::Serial && is_node && !std::path::Path::new(&port).exists()).then_some(port)
+}
+
+/// How long to run: `--once` publishes a single poll and `--duration <secs>` runs for a
+/// while, both then shutting down as on SIGTERM. For collecting from cron and the like.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RunLimit {
+    Forever,
+    Once,
+    Until(Instant),
+}
+
+impl RunLimit {
+    fn from_args(args: &Cli) -> Self {
+        match (args.once, args.duration) {
+            (true, _) => RunLimit::Once,
+            (false, Some(secs)) => RunLimit::Until(Instant::now() + Duration::from_secs(secs)),
+            (false, None) => RunLimit::Forever,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            RunLimit::Until(deadline) => Some(*deadline),
+            _ => None,
+        }
+    }
+}
+
+fn supported_features(features: &HashSet<DeviceFeature>) -> Vec<String> {
+    let mut supported = features.iter().map(|f| f.as_str().to_string()).collect::<Vec<String>>();
+    supported.sort();
+    supported
+}
+
+/// Publishes to `gqgmcmqtt/{serial}/status`; skipped until the unit has been identified,
+/// since there's no serial to put in the topic before then, and retained once it's online.
+async fn publish_status(mqtt_tx: &mpsc::Sender<IPCMessage>, poll_state: &PollState, status: &GatewayStatus) -> Result<(), GqError> {
+    let Some(identity) = &poll_state.identity else {
+        return Ok(());
+    };
+    tolerate_full(queue(mqtt_tx,
+        IPCMessage::Outbound(PublishMessage {
+            topic: format!("gqgmcmqtt/{}/status", identity.serial),
+            payload: Payload::Status(status.clone()),
+            retain: status.status.is_some(),
+        })
+    ).await)
+}
+
+/// A daily-rotated appender for `path`, creating its directory if need be.
+fn file_appender(path: &str) -> Result<RollingFileAppender, String> {
+    let path = std::path::Path::new(path);
+    let Some(name) = path.file_name() else {
+        return Err("log_file needs a file name".to_string());
+    };
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy())
+        .build(dir)
+        .map_err(|e| e.to_string())
+}
+
+/// Yields once per SIGHUP. On platforms without SIGHUP the sender is dropped straight
+/// away, so the receiver just reports closed and the select branch never fires.
+fn reload_signal() -> mpsc::Receiver<()> {
+    let (reload_tx, reload_rx) = mpsc::channel::<()>(1);
+    #[cfg(unix)]
+    tokio::task::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Couldn't install SIGHUP handler: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            let _ = reload_tx.try_send(());
+        }
+    });
+    #[cfg(not(unix))]
+    drop(reload_tx);
+    reload_rx
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                error!("Couldn't install SIGTERM handler: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
 
@@ -0,0 +1,79 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// Latest CPM, for `gqgmc_cpm`.
+static CPM: AtomicU32 = AtomicU32::new(0);
+/// Latest dose rate as f32 bits, for `gqgmc_usv_per_hour`.
+static USV_BITS: AtomicU32 = AtomicU32::new(0);
+/// The gauges hold a real reading; until then they're left out rather than shown as 0.
+static HAVE_READING: AtomicBool = AtomicBool::new(false);
+static POLLS: AtomicU64 = AtomicU64::new(0);
+static POLL_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_reading(cpm: u32, usv: f32) {
+    CPM.store(cpm, Ordering::Relaxed);
+    USV_BITS.store(usv.to_bits(), Ordering::Relaxed);
+    HAVE_READING.store(true, Ordering::Relaxed);
+}
+
+pub fn record_poll() {
+    POLLS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_poll_error() {
+    POLL_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Everything in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    if HAVE_READING.load(Ordering::Relaxed) {
+        let _ = writeln!(out, "# HELP gqgmc_cpm Counts per minute at the last poll.");
+        let _ = writeln!(out, "# TYPE gqgmc_cpm gauge");
+        let _ = writeln!(out, "gqgmc_cpm {}", CPM.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP gqgmc_usv_per_hour Dose rate at the last poll, from usv_conversion_factor.");
+        let _ = writeln!(out, "# TYPE gqgmc_usv_per_hour gauge");
+        let _ = writeln!(out, "gqgmc_usv_per_hour {}", f32::from_bits(USV_BITS.load(Ordering::Relaxed)));
+    }
+    let _ = writeln!(out, "# HELP gqgmc_polls_total Poll cycles run.");
+    let _ = writeln!(out, "# TYPE gqgmc_polls_total counter");
+    let _ = writeln!(out, "gqgmc_polls_total {}", POLLS.load(Ordering::Relaxed));
+    let _ = writeln!(out, "# HELP gqgmc_poll_errors_total Polls that failed to read the unit.");
+    let _ = writeln!(out, "# TYPE gqgmc_poll_errors_total counter");
+    let _ = writeln!(out, "gqgmc_poll_errors_total {}", POLL_ERRORS.load(Ordering::Relaxed));
+    out
+}
+
+/// Serves `render()` on `/metrics` for Prometheus to scrape.
+#[cfg(feature = "metrics")]
+pub async fn serve(port: u16) {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    async fn respond(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let mut response = Response::new(Body::empty());
+        if req.uri().path() == "/metrics" {
+            *response.body_mut() = Body::from(render());
+            response.headers_mut().insert("content-type", "text/plain; version=0.0.4".parse().unwrap());
+        } else {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+        Ok(response)
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            error!("Can't listen for metrics scrapes on port {port}: {e}");
+            return;
+        }
+    };
+    info!("Serving Prometheus metrics on port {port}.");
+    if let Err(e) = server.await {
+        error!("Metrics server stopped: {e}");
+    }
+}
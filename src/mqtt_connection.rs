@@ -1,9 +1,14 @@
 use crate::consts::*;
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::{Packet, PubAckReason, PublishProperties};
+use rumqttc::{AsyncClient, ConnectionError, Event, EventLoop, Incoming, LastWill, MqttOptions, Outgoing, QoS, TlsConfiguration, Transport};
 use std::fmt::{Debug, Formatter};
-use std::ops::{Deref, DerefMut};
+use rumqttc::tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+use std::sync::Arc;
 use tokio::time::Duration;
-use crate::errors::GQGMCMQTTError;
+use crate::config::BrokerConfig;
+use crate::errors::{GQGMCMQTTError, MqttLinkError};
 
 #[derive(Debug)]
 // TODO: decide if I'm implementing mqtt reconnect or just panicking
@@ -14,25 +19,160 @@ pub struct MqttConnection {
     port: u16,
     username: Option<String>,
     password: Option<String>,
-    pub(crate) client: AsyncClient,
+    /// Retained `online` while connected; the broker sets it `offline` through the last
+    /// will if the gateway drops off without disconnecting.
+    pub(crate) availability_topic: String,
+    pub(crate) client: MqttClient,
     pub(crate) event_loop: MyEventLoop,
 }
 
-pub(crate) struct MyEventLoop(EventLoop);
+/// rumqttc's v3.1.1 or v5 client, behind the handful of calls the MQTT threads make.
+/// QoS is passed as its number, since the two versions have their own QoS types.
+#[derive(Clone, Debug)]
+pub(crate) enum MqttClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl MqttClient {
+    /// `expiry` is the v5 message expiry in seconds; v3.1.1 has nowhere to put it.
+    pub async fn publish(&self, topic: String, qos: u8, retain: bool, payload: Vec<u8>, expiry: Option<u32>) -> Result<(), GQGMCMQTTError> {
+        let result = match self {
+            MqttClient::V4(c) => c.publish(topic, v4_qos(qos), retain, payload).await.map_err(|e| e.to_string()),
+            MqttClient::V5(c) => match expiry {
+                Some(secs) => {
+                    let properties = PublishProperties { message_expiry_interval: Some(secs), ..Default::default() };
+                    c.publish_with_properties(topic, v5_qos(qos), retain, payload, properties).await
+                }
+                None => c.publish(topic, v5_qos(qos), retain, payload).await,
+            }
+            .map_err(|e| e.to_string()),
+        };
+        result.map_err(GQGMCMQTTError::Mqtt)
+    }
+
+    /// Queues without waiting, for the event loop task, which mustn't block on the very
+    /// request channel it drains.
+    pub fn try_publish(&self, topic: &str, qos: u8, retain: bool, payload: &str) -> Result<(), GQGMCMQTTError> {
+        match self {
+            MqttClient::V4(c) => c.try_publish(topic, v4_qos(qos), retain, payload).map_err(|e| e.to_string()),
+            MqttClient::V5(c) => c.try_publish(topic, v5_qos(qos), retain, payload).map_err(|e| e.to_string()),
+        }
+        .map_err(GQGMCMQTTError::Mqtt)
+    }
+
+    pub fn try_subscribe(&self, filter: String, qos: u8) -> Result<(), GQGMCMQTTError> {
+        match self {
+            MqttClient::V4(c) => c.try_subscribe(filter, v4_qos(qos)).map_err(|e| e.to_string()),
+            MqttClient::V5(c) => c.try_subscribe(filter, v5_qos(qos)).map_err(|e| e.to_string()),
+        }
+        .map_err(GQGMCMQTTError::Mqtt)
+    }
+
+    pub async fn disconnect(&self) -> Result<(), GQGMCMQTTError> {
+        match self {
+            MqttClient::V4(c) => c.disconnect().await.map_err(|e| e.to_string()),
+            MqttClient::V5(c) => c.disconnect().await.map_err(|e| e.to_string()),
+        }
+        .map_err(GQGMCMQTTError::Mqtt)
+    }
+}
+
+fn v4_qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+fn v5_qos(level: u8) -> v5::mqttbytes::QoS {
+    match level {
+        0 => v5::mqttbytes::QoS::AtMostOnce,
+        2 => v5::mqttbytes::QoS::ExactlyOnce,
+        _ => v5::mqttbytes::QoS::AtLeastOnce,
+    }
+}
 
-impl Deref for MyEventLoop {
-    type Target = EventLoop;
+/// What the MQTT thread's event loop acts on, the same for either protocol version.
+#[derive(Debug)]
+pub(crate) enum LinkEvent {
+    ConnAck,
+    /// The broker hung up; v5 brokers say why.
+    Disconnect(Option<String>),
+    /// A v5 broker can acknowledge a publish and still refuse it, so the reason comes along
+    /// unless it was a plain success.
+    PubAck(u16, Option<String>),
+    PingResp,
+    SubAck,
+    Publish { topic: String, payload: Vec<u8> },
+    SentPing,
+    SentPublish(u16),
+    SentSubscribe,
+    SentDisconnect,
+    Other(String),
+}
 
-    //Target = EventLoop;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+pub(crate) enum MyEventLoop {
+    V4(EventLoop),
+    V5(v5::EventLoop),
+}
+
+impl MyEventLoop {
+    pub async fn poll(&mut self) -> Result<LinkEvent, MqttLinkError> {
+        match self {
+            MyEventLoop::V4(conn) => match conn.poll().await {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => Ok(LinkEvent::ConnAck),
+                Ok(Event::Incoming(Incoming::Disconnect)) => Ok(LinkEvent::Disconnect(None)),
+                Ok(Event::Incoming(Incoming::PubAck(pa))) => Ok(LinkEvent::PubAck(pa.pkid, None)),
+                Ok(Event::Incoming(Incoming::PingResp)) => Ok(LinkEvent::PingResp),
+                Ok(Event::Incoming(Incoming::SubAck(_))) => Ok(LinkEvent::SubAck),
+                Ok(Event::Incoming(Incoming::Publish(p))) => Ok(LinkEvent::Publish { topic: p.topic, payload: p.payload.to_vec() }),
+                Ok(Event::Incoming(other)) => Ok(LinkEvent::Other(format!("incoming {other:?}"))),
+                Ok(Event::Outgoing(o)) => Ok(outgoing(o)),
+                Err(ConnectionError::Tls(tls)) => Err(MqttLinkError::Tls(tls.to_string())),
+                Err(ConnectionError::ConnectionRefused(code)) => Err(MqttLinkError::Refused(format!("{code:?}"))),
+                Err(e) => Err(MqttLinkError::Other(e.to_string())),
+            },
+            MyEventLoop::V5(conn) => match conn.poll().await {
+                Ok(v5::Event::Incoming(Packet::ConnAck(ack))) => {
+                    debug!("MQTT v5 connack, session present: {}", ack.session_present);
+                    Ok(LinkEvent::ConnAck)
+                }
+                Ok(v5::Event::Incoming(Packet::Disconnect(d))) => {
+                    let why = d.properties.and_then(|p| p.reason_string).map(|s| format!(" ({s})")).unwrap_or_default();
+                    Ok(LinkEvent::Disconnect(Some(format!("{:?}{why}", d.reason_code))))
+                }
+                Ok(v5::Event::Incoming(Packet::PubAck(pa))) => {
+                    let reason = (!matches!(pa.reason, PubAckReason::Success)).then(|| format!("{:?}", pa.reason));
+                    Ok(LinkEvent::PubAck(pa.pkid, reason))
+                }
+                Ok(v5::Event::Incoming(Packet::PingResp(_))) => Ok(LinkEvent::PingResp),
+                Ok(v5::Event::Incoming(Packet::SubAck(_))) => Ok(LinkEvent::SubAck),
+                Ok(v5::Event::Incoming(Packet::Publish(p))) => Ok(LinkEvent::Publish {
+                    topic: String::from_utf8_lossy(&p.topic).to_string(),
+                    payload: p.payload.to_vec(),
+                }),
+                Ok(v5::Event::Incoming(other)) => Ok(LinkEvent::Other(format!("incoming {other:?}"))),
+                Ok(v5::Event::Outgoing(o)) => Ok(outgoing(o)),
+                Err(v5::ConnectionError::Tls(tls)) => Err(MqttLinkError::Tls(tls.to_string())),
+                Err(v5::ConnectionError::ConnectionRefused(code)) => Err(MqttLinkError::Refused(format!("{code:?}"))),
+                Err(e) => Err(MqttLinkError::Other(e.to_string())),
+            },
+        }
     }
 }
-impl DerefMut for MyEventLoop {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+
+fn outgoing(o: Outgoing) -> LinkEvent {
+    match o {
+        Outgoing::PingReq => LinkEvent::SentPing,
+        Outgoing::Publish(pkid) => LinkEvent::SentPublish(pkid),
+        Outgoing::Subscribe(_) => LinkEvent::SentSubscribe,
+        Outgoing::Disconnect => LinkEvent::SentDisconnect,
+        other => LinkEvent::Other(format!("outgoing {other:?}")),
     }
 }
+
 impl Debug for MyEventLoop {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "EventLoop has no Debug.")
@@ -40,19 +180,47 @@ impl Debug for MyEventLoop {
 }
 
 impl MqttConnection {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         client: String,
         addr: String,
         port: u16,
         username: Option<String>,
         password: Option<String>,
+        tls: Option<TlsConfiguration>,
+        availability_topic: String,
+        protocol_version: u8,
     ) -> Result<Self, GQGMCMQTTError> {
-        let mut mqttoptions = MqttOptions::new(&client, &addr, port);
-        mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEPALIVE_TIME));
-        if username.is_some() && password.is_some() {
-            mqttoptions.set_credentials(username.clone().unwrap(), password.clone().unwrap());
-        }
-        let (mqtt_client, eventloop) = AsyncClient::new(mqttoptions, MQTT_THREAD_CHANNEL_CAPACITY);
+        let (mqtt_client, eventloop) = if protocol_version == 5 {
+            let mut mqttoptions = v5::MqttOptions::new(&client, &addr, port);
+            mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEPALIVE_TIME));
+            let will = v5::mqttbytes::v5::LastWill::new(&availability_topic, "offline", v5_qos(1), true, None);
+            mqttoptions.set_last_will(will);
+            // resume the session across a short outage, and let the broker drop it if the
+            // gateway doesn't come back
+            mqttoptions.set_clean_start(false);
+            mqttoptions.set_session_expiry_interval(Some(MQTT_SESSION_EXPIRY_SECS));
+            if let (Some(username), Some(password)) = (&username, &password) {
+                mqttoptions.set_credentials(username.clone(), password.clone());
+            }
+            if let Some(tls) = tls {
+                mqttoptions.set_transport(Transport::tls_with_config(tls));
+            }
+            let (c, e) = v5::AsyncClient::new(mqttoptions, MQTT_THREAD_CHANNEL_CAPACITY);
+            (MqttClient::V5(c), MyEventLoop::V5(e))
+        } else {
+            let mut mqttoptions = MqttOptions::new(&client, &addr, port);
+            mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEPALIVE_TIME));
+            mqttoptions.set_last_will(LastWill::new(&availability_topic, "offline", QoS::AtLeastOnce, true));
+            if username.is_some() && password.is_some() {
+                mqttoptions.set_credentials(username.clone().unwrap(), password.clone().unwrap());
+            }
+            if let Some(tls) = tls {
+                mqttoptions.set_transport(Transport::tls_with_config(tls));
+            }
+            let (c, e) = AsyncClient::new(mqttoptions, MQTT_THREAD_CHANNEL_CAPACITY);
+            (MqttClient::V4(c), MyEventLoop::V4(e))
+        };
 
         Ok(MqttConnection {
             client_name: client,
@@ -60,8 +228,74 @@ impl MqttConnection {
             port,
             username,
             password,
+            availability_topic,
             client: mqtt_client,
-            event_loop: MyEventLoop(eventloop),
+            event_loop: eventloop,
         })
     }
 }
+
+/// Reads the broker's cert files into a rumqttc TLS config, None for a plaintext broker.
+pub fn tls_configuration(broker: &BrokerConfig) -> Result<Option<TlsConfiguration>, GQGMCMQTTError> {
+    if !broker.tls.unwrap_or(false) {
+        return Ok(None);
+    }
+    let client_auth = match (&broker.client_cert, &broker.client_key) {
+        (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+        _ => None,
+    };
+    let alpn: Option<Vec<Vec<u8>>> = broker
+        .alpn
+        .as_ref()
+        .map(|protocols| protocols.iter().map(|p| p.as_bytes().to_vec()).collect());
+    match &broker.ca_cert {
+        Some(path) => Ok(Some(TlsConfiguration::Simple { ca: read_pem(path)?, alpn, client_auth })),
+        None => platform_roots_configuration(broker, alpn, client_auth).map(Some),
+    }
+}
+
+/// rumqttc's simple config only takes a CA file, so a broker with a publicly trusted
+/// certificate gets a rustls config built on the OS's root store instead.
+fn platform_roots_configuration(
+    broker: &BrokerConfig,
+    alpn: Option<Vec<Vec<u8>>>,
+    client_auth: Option<(Vec<u8>, Vec<u8>)>,
+) -> Result<TlsConfiguration, GQGMCMQTTError> {
+    let tls_err = |e: String| GQGMCMQTTError::Config(format!("broker {}: {e}", broker.addr));
+    let native = rustls_native_certs::load_native_certs()
+        .map_err(|e| tls_err(format!("can't load the platform's root certificates: {e}")))?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&native.into_iter().map(|c| c.0).collect::<Vec<Vec<u8>>>());
+    if added == 0 {
+        return Err(tls_err("the platform has no usable root certificates; set a CA cert".to_string()));
+    }
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+    let mut config = match client_auth {
+        Some((cert, key)) => {
+            let certs = rustls_pemfile::certs(&mut cert.as_slice())
+                .map_err(|e| tls_err(format!("can't parse the client cert: {e}")))?;
+            let key = rustls_pemfile::read_all(&mut key.as_slice())
+                .map_err(|e| tls_err(format!("can't parse the client key: {e}")))?
+                .into_iter()
+                .find_map(|item| match item {
+                    Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(k),
+                    _ => None,
+                })
+                .ok_or_else(|| tls_err("no private key in the client key file".to_string()))?;
+            builder
+                .with_client_auth_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+                .map_err(|e| tls_err(format!("client cert rejected: {e}")))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.unwrap_or_default();
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, GQGMCMQTTError> {
+    let pem = std::fs::read(path).map_err(|e| GQGMCMQTTError::Config(format!("Can't read {path}: {e}")))?;
+    if !pem.starts_with(b"-----BEGIN") {
+        return Err(GQGMCMQTTError::Config(format!("{path} doesn't look like a PEM file")));
+    }
+    Ok(pem)
+}
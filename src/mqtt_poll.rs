@@ -1,14 +1,24 @@
-use crate::consts::MQTT_POLL_INTERVAL_MILLIS;
-use crate::ipc::{IPCMessage, InboundMessage};
-use crate::mqtt_connection::MqttConnection;
-use crate::payload::Payload;
-use crate::errors::GQGMCMQTTError;
-use rumqttc::{Event, Incoming, Outgoing, QoS};
+use crate::commands::parse_command_topic;
+use crate::config::{AppConfig, StateSchema};
+use crate::payload::{AvailabilityTopic, Payload};
+use crate::consts::{
+    COMMAND_TOPIC_FILTER, DEFAULT_MAX_PAYLOAD_BYTES, DEFAULT_RECONNECT_JITTER_PCT, DEFAULT_STATE_CACHE_SIZE,
+    MQTT_POLL_INTERVAL_MILLIS, MQTT_PROCESSING_PAD_MILLIS, MQTT_RECONNECT_MAX_SECS,
+};
+use crate::ipc::{IPCMessage, PublishMessage};
+use crate::mqtt_connection::{LinkEvent, MqttClient, MqttConnection};
+use crate::errors::{GQGMCMQTTError, MqttLinkError};
+use crate::signing::sign_state;
+use crate::topics::{inbound_topic, outbound_topic};
+use std::collections::{HashMap, HashSet};
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::broadcast::error::TryRecvError as BroadcastTryRecvError;
 use tokio::time::{sleep, timeout};
 
 pub async fn mqtt_poll_loop(
@@ -16,55 +26,127 @@ pub async fn mqtt_poll_loop(
     mut incoming_rx: tokio::sync::mpsc::Receiver<IPCMessage>,
     mut bcast_rx: tokio::sync::broadcast::Receiver<IPCMessage>,
     outgoing_tx: mpsc::Sender<IPCMessage>,
+    config: AppConfig,
 ) -> Result<(), GQGMCMQTTError> {
+    if config.state_message_expiry_secs.is_some() && config.mqtt_protocol_version() < 5 {
+        // the v3.1.1 client has no message properties to carry the expiry
+        warn!("state_message_expiry_secs is set, but has no effect on an MQTT v3.1.1 connection.");
+    }
+    let jitter_pct = config.reconnect_jitter_pct.unwrap_or(DEFAULT_RECONNECT_JITTER_PCT);
+    let client = mqtt.client.clone();
+    let inbound_tx = outgoing_tx.clone();
+    let mut pacer = DiscoveryPacer::new(config.discovery_publish_delay_ms.unwrap_or(0));
+    let new_connection = pacer.reset.clone();
+    let mut cache = StateCache::new(config.state_cache_size.unwrap_or(DEFAULT_STATE_CACHE_SIZE));
+    let link_up = cache.link_up.clone();
+    let mut retained = RetainedConfigs::new();
+    let reconnected = retained.reconnected.clone();
+    let topic_config = config.clone();
+    let availability_topic = mqtt.availability_topic.clone();
     let task = tokio::spawn(async move {
         let mut conn = mqtt.event_loop;
         let mut dlq: Vec<u16> = vec![];
+        let mut link = LinkState::new();
+        let mut rng = seed();
         loop {
             let notification = match conn.poll().await {
                 Ok(event) => event,
                 Err(e) => {
-                    let msg = format!("Unable to poll mqtt: {e}");
-                    panic!("{}", msg);
+                    if link.dropped() {
+                        link_up.store(false, Ordering::Relaxed);
+                        if let Err(e) = inbound_tx.send(IPCMessage::MqttDisconnected).await {
+                            error!("Couldn't report mqtt disconnect: {e}");
+                        }
+                    }
+                    if let MqttLinkError::Tls(tls) = &e {
+                        error!("TLS handshake with the broker failed: {tls}. Check the CA cert matches the broker, the address is the hostname on its certificate, and any ALPN or client cert it requires.");
+                    }
+                    // polling again makes rumqttc reconnect, so just wait before doing so
+                    let delay = jittered(link.next_backoff(), jitter_pct, &mut rng);
+                    error!("Unable to poll mqtt: {e}, reconnecting in {}ms", delay.as_millis());
+                    sleep(delay).await;
+                    continue;
                 }
             };
 
             match notification {
-                Event::Incoming(i) => {
-                    match i {
-                        Incoming::Disconnect => {
-                            // we should do something here.
-                            error!("mqtt disconnect packet received.");
-                            return;
-                        }
-                        Incoming::ConnAck(_ca) => {
-                            info!("MQTT connection established.");
-                        }
-                        Incoming::PubAck(pa) => {
-                            dlq.retain(|x| *x != pa.pkid);
-                        }
-                        Incoming::PingResp => {
-                            trace!("Recv MQTT PONG");
-                        }
-                        Incoming::SubAck(_) => {}
-                        Incoming::Publish(pr) => {}
-                       _ => {
-                            info!("mqtt incoming packet: {:#?}", i);
+                LinkEvent::Disconnect(reason) => {
+                    match reason {
+                        Some(reason) => error!("mqtt disconnect packet received, reason {reason}; reconnecting."),
+                        None => error!("mqtt disconnect packet received; reconnecting."),
+                    }
+                    // a broker restarting says goodbye first; the next poll fails and reconnects
+                    // with the usual backoff, so the task carries on instead of ending here
+                    if link.dropped() {
+                        link_up.store(false, Ordering::Relaxed);
+                        if let Err(e) = inbound_tx.send(IPCMessage::MqttDisconnected).await {
+                            error!("Couldn't report mqtt disconnect: {e}");
                         }
                     }
                 }
-                Event::Outgoing(o) => match o {
-                    Outgoing::PingReq => {
-                        trace!("Sent MQTT PING");
+                LinkEvent::ConnAck => {
+                    let again = link.connected();
+                    if again {
+                        info!("MQTT connection re-established, republishing retained discovery.");
+                    } else {
+                        info!("MQTT connection established.");
                     }
-                    Outgoing::Publish(pb) => {
-                        dlq.push(pb);
+                    // clean sessions drop subscriptions, so (re)subscribe on every connack.
+                    // try_subscribe because awaiting here would block the loop that drains requests.
+                    let filter = outbound_topic(COMMAND_TOPIC_FILTER, &topic_config);
+                    if let Err(e) = client.try_subscribe(filter, 1) {
+                        error!("Couldn't subscribe to command topics: {e}");
                     }
-                    Outgoing::Subscribe(_) => {}
-                    _ => {
-                        info!("outgoing mqtt packet: {:#?}", o);
+                    if let Err(e) = client.try_publish(&availability_topic, 1, true, "online") {
+                        error!("Couldn't publish gateway availability: {e}");
+                    }
+                    if again {
+                        reconnected.store(true, Ordering::Relaxed);
+                        if let Err(e) = inbound_tx.send(IPCMessage::MqttReconnected).await {
+                            error!("Couldn't report mqtt reconnect: {e}");
+                        }
                     }
-                },
+                    new_connection.store(true, Ordering::Relaxed);
+                    link_up.store(true, Ordering::Relaxed);
+                }
+                LinkEvent::PubAck(pkid, reason) => {
+                    if let Some(reason) = reason {
+                        warn!("Broker refused publish {pkid}: {reason}");
+                    }
+                    dlq.retain(|x| *x != pkid);
+                }
+                LinkEvent::PingResp => {
+                    trace!("Recv MQTT PONG");
+                }
+                LinkEvent::SubAck => {}
+                LinkEvent::Publish { topic: raw_topic, payload } => {
+                    let topic = inbound_topic(&raw_topic, &topic_config);
+                    match topic.and_then(|t| parse_command_topic(&t, &payload)) {
+                        Some(msg) => {
+                            if let Err(e) = inbound_tx.send(IPCMessage::Inbound(msg)).await {
+                                error!("Couldn't forward inbound command: {e}");
+                            }
+                        }
+                        None => {
+                            debug!("Ignoring publish on unexpected topic {raw_topic}");
+                        }
+                    }
+                }
+                LinkEvent::SentPing => {
+                    trace!("Sent MQTT PING");
+                }
+                LinkEvent::SentPublish(pkid) => {
+                    dlq.push(pkid);
+                }
+                LinkEvent::SentSubscribe => {}
+                LinkEvent::SentDisconnect => {
+                    // everything queued ahead of the disconnect has been written by now
+                    info!("MQTT disconnect sent.");
+                    return;
+                }
+                LinkEvent::Other(packet) => {
+                    info!("mqtt packet: {packet}");
+                }
             }
             if dlq.len() > 0 {
                 trace!("DLQ is {}", dlq.len());
@@ -74,55 +156,74 @@ pub async fn mqtt_poll_loop(
 
     loop {
         if task.is_finished() {
-            panic!("mqtt eventloop finished, this only happens in a connection error");
+            // the event loop only returns after a disconnect we sent, so this is a panic in it;
+            // report the broker as gone and let main see the channel close
+            error!("MQTT event loop for {} ended unexpectedly, closing this connection.", mqtt.availability_topic);
+            if let Err(e) = outgoing_tx.send(IPCMessage::MqttDisconnected).await {
+                error!("Couldn't report mqtt disconnect: {e}");
+            }
+            return Err(GQGMCMQTTError::Mqtt("event loop ended".to_string()));
         }
         match bcast_rx.try_recv() {
             Ok(ipcm) => match ipcm {
                 IPCMessage::Shutdown => {
                     info!("MQTT Received shutdown message, exiting thread.");
+                    for msg in cache.drain().await {
+                        publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
+                    }
+                    // a clean disconnect doesn't trigger the will
+                    publish_availability(&mqtt.client, &mqtt.availability_topic, false).await;
                     let _ = mqtt.client.disconnect().await;
+                    let _ = timeout(Duration::from_millis(MQTT_PROCESSING_PAD_MILLIS), task).await;
                     return Err(GQGMCMQTTError::ExitingThread);
                 }
                 IPCMessage::Inbound(_) => {}
                 IPCMessage::Outbound(_) => {}
                 IPCMessage::PleaseReconnect(_, _) => {}
+                IPCMessage::MqttReconnected => {}
+                IPCMessage::MqttDisconnected => {}
                 IPCMessage::Error(_) => {}
+                IPCMessage::Batch(_) => {}
             },
-            Err(_) => {}
+            Err(e) => match e {
+                BroadcastTryRecvError::Empty => {}
+                BroadcastTryRecvError::Lagged(skipped) => {
+                    // the receiver has already skipped ahead to the oldest kept message
+                    warn!("MQTT thread fell behind on broadcasts, {skipped} messages skipped.");
+                }
+                BroadcastTryRecvError::Closed => {}
+            },
+        }
+        for msg in retained.due() {
+            publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
+        }
+        for msg in cache.flush() {
+            publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
         }
         //region MQTT loop channel handling
-        match incoming_rx.try_recv() {
-            Ok(ipcm) => match ipcm {
+        // everything queued since the last tick goes out now, not one message per tick
+        let (ready, disconnected) = ready_messages(&mut incoming_rx);
+        if disconnected {
+            error!("We are disconnected!");
+        }
+        for ipcm in ready {
+            match ipcm {
                 IPCMessage::Outbound(msg) => {
-                    let payload = match serde_json::to_vec(&msg.payload) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            error!("Payload couldn't be serialized to vec: {e}");
-                            continue;
-                        }
-                    };
-                    match timeout(
-                        Duration::from_secs(3),
-                        mqtt.client
-                            .publish(msg.topic, QoS::AtLeastOnce, false, payload),
-                    )
-                    .await
-                    {
-                        Ok(result) => match result {
-                            Ok(_) => {
-                                if let Payload::Config(config) = msg.payload {
-                                    let vals =
-                                        config.unique_id.splitn(3, ".").collect::<Vec<&str>>();
-
-                                };
-                            }
-                            Err(e) => {
-                                error!("Couldn't send message: {e}");
-                            }
-                        },
-                        Err(_e) => {
-                            error!("Timeout trying to mqtt publish!")
-                        }
+                    retained.remember(&msg);
+                    if let Some(msg) = cache.hold(msg) {
+                        pacer.pace(&msg).await;
+                        publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
+                    }
+                }
+                IPCMessage::Batch(batch) => {
+                    // once per poll, in case another connection's will marked the topic offline
+                    publish_availability(&mqtt.client, &mqtt.availability_topic, true).await;
+                    // the publishes only enqueue onto rumqttc's request channel, so issuing
+                    // them back to back lets the event loop write them out in one go
+                    batch.messages.iter().for_each(|m| retained.remember(m));
+                    for msg in batch.messages.into_iter().filter_map(|m| cache.hold(m)) {
+                        pacer.pace(&msg).await;
+                        publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
                     }
                 }
                 IPCMessage::PleaseReconnect(_, _) => {
@@ -133,17 +234,17 @@ pub async fn mqtt_poll_loop(
                 }
                 IPCMessage::Shutdown => {
                     info!("MQTT Received shutdown message, exiting thread.");
+                    for msg in cache.drain().await {
+                        publish_message(&mqtt.client, msg, &config, &mqtt.availability_topic).await;
+                    }
+                    // a clean disconnect doesn't trigger the will
+                    publish_availability(&mqtt.client, &mqtt.availability_topic, false).await;
                     let _ = mqtt.client.disconnect().await;
+                    let _ = timeout(Duration::from_millis(MQTT_PROCESSING_PAD_MILLIS), task).await;
                     return Err(GQGMCMQTTError::ExitingThread);
                 }
                 _ => {}
-            },
-            Err(e) => match e {
-                TryRecvError::Empty => {}
-                TryRecvError::Disconnected => {
-                    error!("We are disconnected!");
-                }
-            },
+            }
         }
 
         //endregion
@@ -151,3 +252,462 @@ pub async fn mqtt_poll_loop(
         let _ = sleep(Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS)).await;
     }
 }
+
+/// Every message already queued on the channel, without waiting for more, and whether
+/// the senders have all gone.
+fn ready_messages(rx: &mut mpsc::Receiver<IPCMessage>) -> (Vec<IPCMessage>, bool) {
+    let mut ready = vec![];
+    loop {
+        match rx.try_recv() {
+            Ok(ipcm) => ready.push(ipcm),
+            Err(TryRecvError::Empty) => return (ready, false),
+            Err(TryRecvError::Disconnected) => return (ready, true),
+        }
+    }
+}
+
+/// Whether the event loop's broker link is up, and how long to wait before the next
+/// reconnect attempt while it isn't.
+#[derive(Debug)]
+struct LinkState {
+    connected: bool,
+    connected_before: bool,
+    backoff: Duration,
+}
+
+impl LinkState {
+    fn new() -> Self {
+        LinkState {
+            connected: false,
+            connected_before: false,
+            backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Marks the link down; true if it was up, so the drop is only reported once.
+    fn dropped(&mut self) -> bool {
+        std::mem::replace(&mut self.connected, false)
+    }
+
+    /// The wait before polling again, doubling each time up to MQTT_RECONNECT_MAX_SECS.
+    fn next_backoff(&mut self) -> Duration {
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(MQTT_RECONNECT_MAX_SECS));
+        delay
+    }
+
+    /// Marks the link up on a connack; true if it had been up before, i.e. a reconnect.
+    fn connected(&mut self) -> bool {
+        self.backoff = Duration::from_secs(1);
+        self.connected = true;
+        std::mem::replace(&mut self.connected_before, true)
+    }
+}
+
+/// The newest retained discovery config per topic, published again after a reconnect,
+/// since a broker that restarted without persistence has lost them and HA with it.
+struct RetainedConfigs {
+    configs: HashMap<String, PublishMessage>,
+    /// Set by the event loop on a reconnect's connack.
+    reconnected: Arc<AtomicBool>,
+}
+
+impl RetainedConfigs {
+    fn new() -> Self {
+        RetainedConfigs {
+            configs: HashMap::new(),
+            reconnected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn remember(&mut self, msg: &PublishMessage) {
+        match &msg.payload {
+            Payload::Config(_) if msg.retain => {
+                self.configs.insert(msg.topic.clone(), msg.clone());
+            }
+            // the empty config that removes an entity shouldn't bring it back later
+            Payload::None => {
+                self.configs.remove(&msg.topic);
+            }
+            _ => {}
+        }
+    }
+
+    /// Every remembered config once the connection has come back, otherwise nothing.
+    fn due(&mut self) -> Vec<PublishMessage> {
+        if !self.reconnected.swap(false, Ordering::Relaxed) {
+            return vec![];
+        }
+        info!("Republishing {} retained discovery configs.", self.configs.len());
+        self.configs.values().cloned().collect()
+    }
+}
+
+/// Spaces out the first publish of each discovery config on a connection, so the burst
+/// after startup or a reconnect doesn't swamp HA's discovery on small hardware. Later
+/// republishes of the same config, and all state publishes, go straight through.
+struct DiscoveryPacer {
+    delay: Duration,
+    published: HashSet<String>,
+    /// Set by the event loop on every connack.
+    reset: Arc<AtomicBool>,
+}
+
+impl DiscoveryPacer {
+    fn new(delay_ms: u64) -> Self {
+        DiscoveryPacer {
+            delay: Duration::from_millis(delay_ms),
+            published: HashSet::new(),
+            reset: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    async fn pace(&mut self, msg: &PublishMessage) {
+        if self.delay.is_zero() || !matches!(msg.payload, Payload::Config(_)) {
+            return;
+        }
+        if self.reset.swap(false, Ordering::Relaxed) {
+            self.published.clear();
+        }
+        if self.published.insert(msg.topic.clone()) {
+            sleep(self.delay).await;
+        }
+    }
+}
+
+/// Holds back state publishes while the broker is unreachable, keeping only the newest
+/// per topic, and hands them over once it's back so HA is current straight away instead
+/// of a poll later. Everything else passes through to rumqttc's own queue.
+struct StateCache {
+    max_topics: usize,
+    held: HashMap<String, PublishMessage>,
+    /// Set by the event loop on connack, cleared when the connection drops.
+    link_up: Arc<AtomicBool>,
+}
+
+impl StateCache {
+    fn new(max_topics: usize) -> Self {
+        StateCache {
+            max_topics,
+            held: HashMap::new(),
+            link_up: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The message back if it should be published now, None if it was held or dropped.
+    fn hold(&mut self, msg: PublishMessage) -> Option<PublishMessage> {
+        if self.max_topics == 0 || self.link_up.load(Ordering::Relaxed) || !matches!(msg.payload, Payload::CurrentState(_) | Payload::CombinedState(_)) {
+            return Some(msg);
+        }
+        if self.held.len() >= self.max_topics && !self.held.contains_key(&msg.topic) {
+            debug!("State cache is full, dropping the state for {}", msg.topic);
+            return None;
+        }
+        self.held.insert(msg.topic.clone(), msg);
+        None
+    }
+
+    /// Everything held, on the way out. A run that ends before the first connack would
+    /// lose them otherwise, so this gives the connection a moment to come up first.
+    async fn drain(&mut self) -> Vec<PublishMessage> {
+        let link_up = self.link_up.clone();
+        if !self.held.is_empty() {
+            let wait_for_link = async {
+                while !link_up.load(Ordering::Relaxed) {
+                    sleep(Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS)).await;
+                }
+            };
+            let _ = timeout(Duration::from_millis(MQTT_PROCESSING_PAD_MILLIS), wait_for_link).await;
+        }
+        self.flush()
+    }
+
+    /// Everything held, once the connection is up again.
+    fn flush(&mut self) -> Vec<PublishMessage> {
+        if self.held.is_empty() || !self.link_up.load(Ordering::Relaxed) {
+            return vec![];
+        }
+        info!("Broker is back, publishing {} held states.", self.held.len());
+        self.held.drain().map(|(_, msg)| msg).collect()
+    }
+}
+
+/// Spreads `delay` randomly over +/- `pct` percent, so a fleet that lost the same broker
+/// doesn't come back in lockstep. Applied after the cap, so a capped delay can land a
+/// little above MQTT_RECONNECT_MAX_SECS.
+fn jittered(delay: Duration, pct: u8, rng: &mut u64) -> Duration {
+    // xorshift64, same as the mock device; this only needs to differ between hosts
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    let band = delay.as_millis() as u64 * pct.min(100) as u64 / 100;
+    if band == 0 {
+        return delay;
+    }
+    let offset = *rng % (2 * band + 1);
+    Duration::from_millis(delay.as_millis() as u64 - band + offset)
+}
+
+fn seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    // xorshift must not start at zero
+    (nanos ^ std::process::id() as u64) | 1
+}
+
+async fn publish_availability(client: &MqttClient, topic: &str, online: bool) {
+    let payload = if online { "online" } else { "offline" };
+    match timeout(Duration::from_secs(3), client.publish(topic.to_string(), 1, true, payload.into(), None)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Couldn't publish gateway availability: {e}"),
+        Err(_) => error!("Timed out publishing gateway availability"),
+    }
+}
+
+/// `msg` as it goes on the wire: topics moved under the environment and prefixes, and
+/// configs pointed at the gateway's availability.
+pub fn outbound_message(mut msg: PublishMessage, config: &AppConfig, availability_topic: &str) -> PublishMessage {
+    msg.topic = outbound_topic(&msg.topic, config);
+    if let Payload::Config(c) = &mut msg.payload {
+        // the topics it points HA at, and the ids HA keys entities on, must follow suit
+        c.state_topic = outbound_topic(&c.state_topic, config);
+        for topic in [&mut c.command_topic, &mut c.json_attributes_topic, &mut c.availability_topic].into_iter().flatten() {
+            *topic = outbound_topic(topic, config);
+        }
+        if let Some(env) = config.environment.as_deref().filter(|e| !e.is_empty()) {
+            c.unique_id = format!("{env}-{}", c.unique_id);
+        }
+    }
+    if let Payload::Config(c) = &mut msg.payload {
+        // the gateway's will covers every entity; a sensor's own topic can only narrow that
+        let mut availability = vec![AvailabilityTopic { topic: availability_topic.to_string() }];
+        if let Some(topic) = c.availability_topic.take() {
+            availability.push(AvailabilityTopic { topic });
+            c.availability_mode = Some("all".to_string());
+        }
+        c.availability = Some(availability);
+    }
+    if !config.publish_last_seen.unwrap_or(true) {
+        match &mut msg.payload {
+            Payload::CurrentState(state) => state.last_seen = None,
+            Payload::CombinedState(state) => state.last_seen = None,
+            _ => {}
+        }
+    }
+    msg
+}
+
+async fn publish_message(client: &MqttClient, msg: PublishMessage, config: &AppConfig, availability_topic: &str) {
+    let msg = outbound_message(msg, config, availability_topic);
+    let state_schema = config.state_schema.clone().unwrap_or_default();
+    let payload = match encode_payload(&msg.payload, &state_schema, config) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Payload couldn't be serialized to vec: {e}");
+            return;
+        }
+    };
+    let max_bytes = config.max_payload_bytes.unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES);
+    let payload = if payload.len() > max_bytes {
+        match shrink_payload(msg.payload.clone(), &state_schema, config, max_bytes) {
+            Some(p) => {
+                warn!(
+                    "Payload for {} was {} bytes, over max_payload_bytes {max_bytes}; dropped its attributes",
+                    msg.topic,
+                    payload.len()
+                );
+                p
+            }
+            None => {
+                warn!(
+                    "Payload for {} is {} bytes, over max_payload_bytes {max_bytes}; skipping publish",
+                    msg.topic,
+                    payload.len()
+                );
+                return;
+            }
+        }
+    } else {
+        payload
+    };
+    let level = match msg.payload {
+        Payload::Config(_) => config.mqtt_discovery_qos,
+        _ => config.mqtt_qos,
+    };
+    let expiry = match msg.payload {
+        Payload::CurrentState(_) | Payload::CombinedState(_) => config.state_message_expiry_secs,
+        _ => None,
+    };
+    match timeout(
+        Duration::from_secs(3),
+        client.publish(msg.topic, level.unwrap_or(1), msg.retain, payload, expiry),
+    )
+    .await
+    {
+        Ok(result) => {
+            if let Err(e) = result {
+                error!("Couldn't send message: {e}");
+            }
+        }
+        Err(_e) => {
+            error!("Timeout trying to mqtt publish!")
+        }
+    }
+}
+
+fn encode_payload(payload: &Payload, state_schema: &StateSchema, config: &AppConfig) -> serde_json::Result<Vec<u8>> {
+    let bytes = payload.to_bytes(state_schema)?;
+    match (payload, state_schema, &config.hmac_secret) {
+        (Payload::CurrentState(_), StateSchema::Nested, Some(secret)) => sign_state(&bytes, secret),
+        // readings too, and JSON whatever the schema
+        (Payload::CombinedState(_) | Payload::Grafana(_) | Payload::Fleet(_), _, Some(secret)) => sign_state(&bytes, secret),
+        _ => Ok(bytes),
+    }
+}
+
+/// Attributes are the only open-ended part of a payload, so an oversized one gets them
+/// stripped; None if it's still too big without them.
+fn shrink_payload(mut payload: Payload, state_schema: &StateSchema, config: &AppConfig, max_bytes: usize) -> Option<Vec<u8>> {
+    match &mut payload {
+        Payload::Config(c) => c.extra_state_attributes = None,
+        Payload::CurrentState(s) => s.attributes = None,
+        _ => return None,
+    }
+    encode_payload(&payload, state_schema, config).ok().filter(|p| p.len() <= max_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{CombinedStatePayload, FleetPayload, GrafanaPayload, HAConfigPayload, PayloadValueType, StatePayload};
+
+    fn config_message(topic: &str, retain: bool) -> PublishMessage {
+        PublishMessage {
+            topic: topic.to_string(),
+            payload: Payload::Config(HAConfigPayload::default()),
+            retain,
+        }
+    }
+
+    #[test]
+    fn link_resumes_after_a_disconnect() {
+        let mut link = LinkState::new();
+        assert!(!link.connected(), "the first connack isn't a reconnect");
+        assert!(link.dropped());
+        assert!(!link.dropped(), "a drop is only reported once");
+        assert_eq!(link.next_backoff(), Duration::from_secs(1));
+        assert_eq!(link.next_backoff(), Duration::from_secs(2));
+        assert!(link.connected(), "the connack after a drop is a reconnect");
+        assert_eq!(link.next_backoff(), Duration::from_secs(1), "a connack resets the backoff");
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut link = LinkState::new();
+        for _ in 0..10 {
+            link.next_backoff();
+        }
+        assert_eq!(link.next_backoff(), Duration::from_secs(MQTT_RECONNECT_MAX_SECS));
+    }
+
+    #[test]
+    fn retained_configs_come_back_after_a_reconnect() {
+        let mut retained = RetainedConfigs::new();
+        retained.remember(&config_message("homeassistant/sensor/1/cpm/config", true));
+        retained.remember(&config_message("homeassistant/sensor/1/cps/config", true));
+        retained.remember(&config_message("homeassistant/sensor/1/usv/config", false));
+        retained.remember(&PublishMessage {
+            topic: "homeassistant/sensor/1/cps/config".to_string(),
+            payload: Payload::None,
+            retain: true,
+        });
+        assert!(retained.due().is_empty(), "nothing is due before a reconnect");
+        retained.reconnected.store(true, Ordering::Relaxed);
+        let due = retained.due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].topic, "homeassistant/sensor/1/cpm/config");
+        assert!(retained.due().is_empty(), "a reconnect republishes once");
+    }
+
+    fn state_message(topic: &str, cpm: i64) -> PublishMessage {
+        PublishMessage {
+            topic: topic.to_string(),
+            payload: Payload::CurrentState(StatePayload {
+                value: PayloadValueType::Int(cpm),
+                ..Default::default()
+            }),
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn every_reading_payload_is_signed() {
+        let config = AppConfig {
+            hmac_secret: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        let mut combined = CombinedStatePayload::default();
+        combined.values.insert("cpm".to_string(), serde_json::Value::from(20));
+        let signed = [
+            Payload::CombinedState(combined),
+            Payload::Grafana(GrafanaPayload::default()),
+            Payload::Fleet(FleetPayload::default()),
+        ];
+        for schema in [StateSchema::Nested, StateSchema::Flat] {
+            for payload in &signed {
+                let bytes = encode_payload(payload, &schema, &config).unwrap();
+                let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                assert!(value.get("sig").is_some(), "{payload:?} unsigned with {schema:?}");
+            }
+        }
+        let config_bytes = encode_payload(&Payload::Config(HAConfigPayload::default()), &StateSchema::Nested, &config).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&config_bytes).unwrap();
+        assert!(value.get("sig").is_none(), "discovery configs aren't readings");
+    }
+
+    /// Stands in for a benchmark: the drain is what a tick spends before publishing, and
+    /// one message per 100ms tick used to make a 1000-message backlog take 100s.
+    #[test]
+    fn one_tick_drains_the_whole_backlog() {
+        let (tx, mut rx) = mpsc::channel(1024);
+        for i in 0..1000 {
+            tx.try_send(IPCMessage::Outbound(state_message(&format!("gqgmcmqtt/F488E1234/point_{i}"), i))).unwrap();
+        }
+        let started = std::time::Instant::now();
+        let (ready, disconnected) = ready_messages(&mut rx);
+        let elapsed = started.elapsed();
+        assert_eq!(ready.len(), 1000);
+        assert!(!disconnected);
+        assert!(elapsed < Duration::from_millis(MQTT_POLL_INTERVAL_MILLIS), "draining took {elapsed:?}");
+
+        drop(tx);
+        let (ready, disconnected) = ready_messages(&mut rx);
+        assert!(ready.is_empty());
+        assert!(disconnected);
+    }
+
+    #[test]
+    fn held_states_flush_on_reconnect() {
+        let mut cache = StateCache::new(2);
+        cache.link_up.store(true, Ordering::Relaxed);
+        assert!(cache.hold(state_message("gqgmcmqtt/1/cpm", 20)).is_some(), "nothing is held while connected");
+
+        cache.link_up.store(false, Ordering::Relaxed);
+        assert!(cache.hold(state_message("gqgmcmqtt/1/cpm", 21)).is_none());
+        assert!(cache.hold(state_message("gqgmcmqtt/1/cpm", 22)).is_none());
+        assert!(cache.hold(state_message("gqgmcmqtt/1/usv", 1)).is_none());
+        assert!(cache.hold(state_message("gqgmcmqtt/1/cps", 1)).is_none(), "a full cache drops new topics");
+        assert!(cache.hold(config_message("homeassistant/sensor/1/cpm/config", true)).is_some(), "only states are held");
+        assert!(cache.flush().is_empty(), "nothing goes out while the link is down");
+
+        cache.link_up.store(true, Ordering::Relaxed);
+        let mut flushed = cache.flush();
+        flushed.sort_by(|a, b| a.topic.cmp(&b.topic));
+        let topics: Vec<_> = flushed.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(topics, ["gqgmcmqtt/1/cpm", "gqgmcmqtt/1/usv"]);
+        assert!(matches!(&flushed[0].payload, Payload::CurrentState(s) if matches!(s.value, PayloadValueType::Int(22))), "only the newest state is kept");
+        assert!(cache.flush().is_empty());
+    }
+}
@@ -1,9 +1,17 @@
+use crate::config::{AppConfig, StateSchema, DoseUnit};
 use crate::consts::*;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use gqgmclib::GMC;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::time::{Duration, Instant};
+use crate::device::{cpm_byte_order, split_version, log_interval_secs, model_has_log_interval, model_has_temperature, model_tube, read_config_byte, get_alarm_cpm, DeviceFeature, FlushBeforeRead, TimedRead, RawResponse, GeigerDevice, CFG_SAVE_DATA_TYPE, CONFIG_FIELDS, BACKLIGHT_TIMEOUT_NUMBER, CLICK_SOUND_SWITCH, DATA_LOGGING_SWITCH, DISPLAY_BRIGHTNESS_NUMBER};
 use crate::payload;
+use crate::ipc::PublishMessage;
+use crate::errors::{DeviceErrorKind, GQGMCMQTTError};
+use crate::gps;
+use crate::host_metrics;
+use crate::metrics;
+use crate::poll_state::PollState;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct DeviceInfo {
@@ -12,6 +20,8 @@ pub struct DeviceInfo {
     pub name: String,
     pub model: String,
     pub sw_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
@@ -25,15 +35,167 @@ pub enum PayloadValueType {
     None,
 }
 
+impl PayloadValueType {
+    /// Bare wire form used by the flat state schema. Strings go out unquoted so HA can
+    /// match them against payload_on/payload_off; None uses HA's "None" marker for unknown.
+    pub fn to_raw_string(&self) -> String {
+        match self {
+            PayloadValueType::Float(f) => f.to_string(),
+            PayloadValueType::Int(i) => i.to_string(),
+            PayloadValueType::String(s) => s.clone(),
+            PayloadValueType::Boolean(b) => b.to_string(),
+            PayloadValueType::None => "None".to_string(),
+        }
+    }
+}
+
+/// Gateway-level (rather than per-sensor) information, published to `gqgmcmqtt/{serial}/status`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GatewayStatus {
+    /// `online` from the first successful poll and `offline` after a clean exit; status
+    /// is retained from then on, so it doubles as a birth message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// The gateway's version, not the unit's firmware.
+    pub version: String,
+    pub poll_interval_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub supported_features: Vec<String>,
+    /// Reconnects since the gateway started, so automations can alert on a flaky link.
+    pub mqtt_reconnect_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_mqtt_reconnect: Option<DateTime<Utc>>,
+    pub serial_reconnect_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_serial_reconnect: Option<DateTime<Utc>>,
+}
+
+impl GatewayStatus {
+    pub fn record_mqtt_reconnect(&mut self) {
+        self.mqtt_reconnect_count += 1;
+        self.last_mqtt_reconnect = Some(Utc::now());
+    }
+
+    pub fn record_serial_reconnect(&mut self) {
+        self.serial_reconnect_count += 1;
+        self.last_serial_reconnect = Some(Utc::now());
+    }
+}
+
+/// One poll's reading as flat numeric JSON for Grafana's MQTT datasource, published to
+/// `gqgmcmqtt/{serial}/grafana` when `grafana_output` is on. `ts` is Unix epoch seconds;
+/// readings the unit doesn't provide are left out rather than sent as null.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GrafanaPayload {
+    pub cpm: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usv: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voltage: Option<f32>,
+    pub ts: i64,
+}
+
+/// The latest reading in one schema shared by every gateway, published to
+/// `gqgmc/fleet/{serial}` when `fleet_topic` is on so a central subscriber can map a whole
+/// network without knowing each unit's topics.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FleetPayload {
+    pub serial: String,
+    pub model: String,
+    pub cpm: u32,
+    pub ts: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+}
+
+/// Published to `gqgmcmqtt/{serial}/spike_alert` as soon as a between-poll CPS sample
+/// reaches `spike_alert_cps`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SpikeAlert {
+    pub cps: u32,
+    pub threshold: u32,
+    pub ts: DateTime<Utc>,
+}
+
+/// Published retained to `gqgmcmqtt/{serial}/device_config` when `publish_device_config`
+/// is on. `settings` has every value the model's layout names; the bytes in between go
+/// out as hex in `unknown`, one entry per run of unnamed bytes.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DeviceConfigPayload {
+    pub model: String,
+    pub settings: BTreeMap<String, u32>,
+    pub unknown: Vec<ConfigRegion>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ConfigRegion {
+    pub offset: usize,
+    pub hex: String,
+}
+
+/// Published to `gqgmcmqtt/{serial}/state` when `combined_state` is on: each reading's
+/// value under its sensor name, and any attributes it carries under `attributes`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CombinedStatePayload {
+    #[serde(flatten)]
+    pub values: BTreeMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Published to `gqgmcmqtt/{serial}/diagnostics` when `capture_raw` is on.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DiagnosticsPayload {
+    pub raw: Vec<RawResponse>,
+}
+
+/// Retained once per unit to `gqgmcmqtt/discovered`, for keeping track of a fleet.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DiscoveryEvent {
+    pub serial: String,
+    pub model: String,
+    pub first_seen: DateTime<Utc>,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Payload {
     Config(HAConfigPayload),
     CurrentState(StatePayload),
+    Status(GatewayStatus),
+    Grafana(GrafanaPayload),
+    Fleet(FleetPayload),
+    SpikeAlert(SpikeAlert),
+    Discovered(DiscoveryEvent),
+    Diagnostics(DiagnosticsPayload),
+    DeviceConfig(DeviceConfigPayload),
+    CombinedState(CombinedStatePayload),
+    RawResponse(RawResponse),
+    /// A sensor's availability, sent as HA's default "online"/"offline".
+    Availability(bool),
     #[default]
     None,
 }
 
+impl Payload {
+    pub fn to_bytes(&self, schema: &StateSchema) -> serde_json::Result<Vec<u8>> {
+        match (self, schema) {
+            (Payload::CurrentState(state), StateSchema::Flat) => Ok(state.value.to_raw_string().into_bytes()),
+            // an empty retained config is how HA discovery deletes an entity
+            (Payload::None, _) => Ok(vec![]),
+            (Payload::Availability(online), _) => Ok(if *online { b"online".to_vec() } else { b"offline".to_vec() }),
+            _ => serde_json::to_vec(self),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum EntityCategory {
@@ -48,6 +210,10 @@ pub struct HAConfigPayload {
     pub device: DeviceInfo,
     pub unique_id: String,
     pub entity_id: String,
+    /// What HA actually builds the entity id from; when unset it slugifies the name, so
+    /// renaming an entity would start a new history.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_id: Option<String>,
     pub state_topic: String,
     pub expires_after: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -99,6 +265,28 @@ pub struct HAConfigPayload {
     pub step: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_template: Option<String>,
+    /// The sensor's own availability topic, with `sensor_availability`. Folded into
+    /// `availability` along with the gateway's on the way out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability: Option<Vec<AvailabilityTopic>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+    /// `gps` for the device tracker.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_type: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AvailabilityTopic {
+    pub topic: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -110,95 +298,1749 @@ pub struct StatePayload {
     pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub notes: Option<String>,
-    pub last_seen: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
 impl Default for StatePayload {
     fn default() -> Self {
         StatePayload {
             value: PayloadValueType::None,
-            last_seen: Utc::now(),
+            last_seen: Some(Utc::now()),
             description: None,
             label: None,
             notes: None,
+            attributes: None,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct CompoundPayload {
+    /// Short name the sensor's settings are keyed by in `AppConfig::sensors`.
+    pub(crate) sensor: &'static str,
     pub(crate) config: HAConfigPayload,
     pub(crate) config_topic: String,
     pub(crate) state: StatePayload,
     pub(crate) state_topic: String,
 }
 
-pub async fn generate_payloads(gmc: &mut GMC) -> Vec<CompoundPayload> {
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    pub serial: String,
+    pub model: String,
+}
+
+pub async fn read_identity<D: GeigerDevice>(gmc: &mut D, config: &AppConfig) -> Result<DeviceIdentity, GQGMCMQTTError> {
     let model = match &gmc.get_version().await {
-        Ok(s) => s.clone(),
+        Ok(s) => s.trim().to_string(),
         Err(e) => {
-            error!{"Can't get unit version: {e}"};
-            return vec![]
+            return Err(GQGMCMQTTError::Device(format!("Can't get unit version: {e}")));
         }
     };
+    let model = if model.is_empty() {
+        warn!("Unit returned an empty version string, using {DEFAULT_MODEL}");
+        DEFAULT_MODEL.to_string()
+    } else {
+        model
+    };
     let serial = match &gmc.get_serial_number().await {
         Ok(s) => s.clone(),
         Err(e) => {
-            error!("Can't get unit serial: {e}");
-            return vec![]
+            return Err(GQGMCMQTTError::Device(format!("Can't get unit serial: {e}")));
+        }
+    };
+    let serial = match resolve_serial(&serial, config.device_id.as_deref()) {
+        Some(s) => s,
+        None => {
+            return Err(GQGMCMQTTError::Device(
+                "Unit returned an empty serial and no device_id is configured, refusing to publish".to_string(),
+            ));
         }
     };
-    let device_info = payload::DeviceInfo {
-        identifiers: vec![serial.clone()],
-        manufacturer: "GQ Electronics".to_string(),
-        name: "GQ Geiger Counter".to_string(),
-        model: model.clone(),
-        sw_version: "".to_string() };
+    Ok(DeviceIdentity { serial, model })
+}
 
-    let cpm = match &gmc.get_cpm().await {
-        Ok(cpm) => {
-            if *cpm == 0 {
-                return vec![];
+/// The unit's identity, re-read every `identity_recheck_mins` (and after any failed read)
+/// rather than every poll, since it only changes when someone swaps the unit. A new serial
+/// queues the old unit's entities for removal and starts the new one from a clean slate.
+async fn current_identity<D: GeigerDevice>(
+    gmc: &mut D,
+    config: &AppConfig,
+    state: &mut PollState,
+) -> Result<DeviceIdentity, GQGMCMQTTError> {
+    let recheck = Duration::from_secs(config.identity_recheck_mins.unwrap_or(DEFAULT_IDENTITY_RECHECK_MINS) * 60);
+    if let (Some(identity), Some(checked)) = (&state.identity, state.identity_checked) {
+        if checked.elapsed() < recheck {
+            return Ok(identity.clone());
+        }
+    }
+    let identity = read_identity(gmc, config).await?;
+    if let Some(old) = state.identity.as_ref().filter(|old| old.serial != identity.serial) {
+        warn!(
+            "Unit changed from {} {} to {} {}, removing the old unit's entities and starting over.",
+            old.model, old.serial, identity.model, identity.serial
+        );
+        *state = PollState {
+            stale_config_topics: state.config_topics.drain().collect(),
+            announced: std::mem::take(&mut state.announced),
+            ..Default::default()
+        };
+    }
+    state.identity_checked = Some(Instant::now());
+    Ok(identity)
+}
+
+/// Empty retained configs for the entities of a unit that was swapped out.
+pub fn swapped_unit_removal(state: &mut PollState) -> Vec<PublishMessage> {
+    state
+        .stale_config_topics
+        .drain(..)
+        .map(|topic| PublishMessage {
+            topic,
+            payload: Payload::None,
+            retain: true,
+        })
+        .collect()
+}
+
+fn device_info(config: &AppConfig, identity: &DeviceIdentity) -> DeviceInfo {
+    let (model, firmware) = split_version(&identity.model);
+    payload::DeviceInfo {
+        identifiers: vec![identity.serial.clone()],
+        manufacturer: config.device_manufacturer.clone().unwrap_or("GQ Electronics".to_string()),
+        name: config.device_name.clone().unwrap_or("GQ Geiger Counter".to_string()),
+        model: config.device_model.clone().unwrap_or(model.to_string()),
+        sw_version: firmware.unwrap_or_default().to_string(),
+        suggested_area: config.device_suggested_area.clone() }
+}
+
+/// Diagnostic sensor holding the kind of the last failed read, or `none` once a read
+/// succeeds again.
+fn last_error_payload(config: &AppConfig, identity: &DeviceIdentity, error: Option<DeviceErrorKind>) -> CompoundPayload {
+    let serial = &identity.serial;
+    let unit_name = format!("{}-{serial}", identity.model);
+    let config_topic = format!("homeassistant/sensor/{serial}/last_error/config");
+    let state_topic = format!("gqgmcmqtt/{serial}/last_error");
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.name = config.sensor_name("last_error", "Last Error");
+    config_payload.has_entity_name = Some(true);
+    config_payload.state_topic = state_topic.clone();
+    config_payload.value_template = value_template(config);
+    config_payload.unique_id = format!("{unit_name}-last_error");
+    config_payload.entity_id = format!("sensor.{serial}_last_error");
+    config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    config_payload.device = device_info(config, identity);
+    config_payload.icon = Some("mdi:alert-circle-outline".to_string());
+    let value = match error {
+        Some(kind) => kind.as_str(),
+        None => "none",
+    };
+    CompoundPayload {
+        sensor: "last_error",
+        config: config_payload,
+        state: StatePayload {
+            value: PayloadValueType::String(value.to_string()),
+            ..Default::default()
+        },
+        config_topic,
+        state_topic,
+    }
+}
+
+/// Diagnostic sensor for the link to the unit: `connected`, `failing` while reads fail,
+/// and `reconnecting` once enough have failed that the gateway reopens the unit.
+fn connection_state_payload(config: &AppConfig, identity: &DeviceIdentity, state: &PollState) -> CompoundPayload {
+    let serial = &identity.serial;
+    let unit_name = format!("{}-{serial}", identity.model);
+    let config_topic = format!("homeassistant/sensor/{serial}/connection_state/config");
+    let state_topic = format!("gqgmcmqtt/{serial}/connection_state");
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.name = config.sensor_name("connection_state", "Connection State");
+    config_payload.has_entity_name = Some(true);
+    config_payload.state_topic = state_topic.clone();
+    config_payload.value_template = value_template(config);
+    config_payload.unique_id = format!("{unit_name}-connection_state");
+    config_payload.entity_id = format!("sensor.{serial}_connection_state");
+    config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    config_payload.device = device_info(config, identity);
+    config_payload.icon = Some("mdi:serial-port".to_string());
+    let value = match state.failed_streak {
+        0 => "connected",
+        n if n < config.serial_reconnect_after() => "failing",
+        _ => "reconnecting",
+    };
+    CompoundPayload {
+        sensor: "connection_state",
+        config: config_payload,
+        state: StatePayload {
+            value: PayloadValueType::String(value.to_string()),
+            ..Default::default()
+        },
+        config_topic,
+        state_topic,
+    }
+}
+
+/// Diagnostic sensor counting up while reads fail, back to 0 after each good one.
+fn since_last_read_payload(config: &AppConfig, identity: &DeviceIdentity, state: &PollState) -> CompoundPayload {
+    let serial = &identity.serial;
+    let unit_name = format!("{}-{serial}", identity.model);
+    let config_topic = format!("homeassistant/sensor/{serial}/seconds_since_last_read/config");
+    let state_topic = format!("gqgmcmqtt/{serial}/seconds_since_last_read");
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.name = config.sensor_name("seconds_since_last_read", "Time Since Last Read");
+    config_payload.has_entity_name = Some(true);
+    config_payload.state_topic = state_topic.clone();
+    config_payload.value_template = value_template(config);
+    config_payload.state_class = Some("measurement".to_string());
+    config_payload.native_uom = Some("s".to_string());
+    config_payload.unique_id = format!("{unit_name}-seconds_since_last_read");
+    config_payload.entity_id = format!("sensor.{serial}_seconds_since_last_read");
+    config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    config_payload.device = device_info(config, identity);
+    config_payload.icon = Some("mdi:timer-sand".to_string());
+    // ticks over every poll, so it's only worth the recorder rows to someone who asks for it
+    config_payload.enabled_by_default = Some(false);
+    // before the first good read, count from startup
+    let secs = match state.last_success {
+        Some(t) => t.elapsed().as_secs(),
+        None => host_metrics::uptime().as_secs(),
+    };
+    CompoundPayload {
+        sensor: "seconds_since_last_read",
+        config: config_payload,
+        state: StatePayload {
+            value: PayloadValueType::Int(secs as i64),
+            ..Default::default()
+        },
+        config_topic,
+        state_topic,
+    }
+}
+
+/// When the last good CPM was read off the unit, as an HA timestamp; never expires, so it
+/// can show how stale the data is rather than going unavailable itself. None until the
+/// first reading.
+fn last_seen_payload(config: &AppConfig, identity: &DeviceIdentity, state: &PollState) -> Option<CompoundPayload> {
+    let (_, read_at) = state.last_reading?;
+    let serial = &identity.serial;
+    let unit_name = format!("{}-{serial}", identity.model);
+    let config_topic = format!("homeassistant/sensor/{serial}/last_seen/config");
+    let state_topic = format!("gqgmcmqtt/{serial}/last_seen");
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.name = config.sensor_name("last_seen", "Last Seen");
+    config_payload.has_entity_name = Some(true);
+    config_payload.state_topic = state_topic.clone();
+    config_payload.value_template = value_template(config);
+    config_payload.device_class = Some("timestamp".to_string());
+    config_payload.unique_id = format!("{unit_name}-last_seen");
+    config_payload.entity_id = format!("sensor.{serial}_last_seen");
+    config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    config_payload.device = device_info(config, identity);
+    config_payload.icon = Some("mdi:clock-check-outline".to_string());
+    Some(CompoundPayload {
+        sensor: "last_seen",
+        config: config_payload,
+        state: StatePayload {
+            value: PayloadValueType::String(read_at.to_rfc3339()),
+            last_seen: Some(read_at),
+            ..Default::default()
+        },
+        config_topic,
+        state_topic,
+    })
+}
+
+/// Logs a failed read and, if the unit has been identified before, reports it on the
+/// last_error sensor; there's nowhere to publish it otherwise.
+fn read_failed(config: &AppConfig, state: &mut PollState, e: &GQGMCMQTTError) -> Vec<CompoundPayload> {
+    let interval = Duration::from_secs(config.error_log_interval_secs.unwrap_or(DEFAULT_ERROR_LOG_INTERVAL_SECS));
+    match state.error_log.should_log(&e.to_string(), interval) {
+        Some(0) => error!("{e}"),
+        Some(suppressed) => error!("{e} (repeated {suppressed} times since last logged)"),
+        None => {}
+    }
+    // a swapped unit shows up as a dropout first, so re-read the identity once reads resume
+    state.identity_checked = None;
+    state.valid_streak = 0;
+    state.failed_streak = state.failed_streak.saturating_add(1);
+    metrics::record_poll_error();
+    let kind = DeviceErrorKind::classify(e);
+    let Some(identity) = &state.identity else {
+        return vec![];
+    };
+    let mut payloads = vec![last_error_payload(config, identity, Some(kind)), connection_state_payload(config, identity, state)];
+    if config.sensor_enabled("seconds_since_last_read", true) {
+        payloads.push(since_last_read_payload(config, identity, state));
+    }
+    // still the last good read, but sent anyway so it's current for anyone subscribing now
+    payloads.extend(last_seen_payload(config, identity, state).filter(|_| config.sensor_enabled("last_seen", true)));
+    payloads
+}
+
+/// Retained unknown states for the readings refreshed every poll. Switches and the like
+/// are left alone since the device holds their real value and it's read on the first poll.
+pub fn unknown_states(config: &AppConfig, identity: &DeviceIdentity) -> Vec<PublishMessage> {
+    let serial = &identity.serial;
+    let mut points = vec!["geiger_counter_cpm"];
+    if config.sensor_enabled("cph", false) {
+        points.push("geiger_counter_cph");
+    }
+    if config.sensor_enabled("cpm_stddev", false) {
+        points.push("cpm_stddev");
+    }
+    if config.cpm_average_window.is_some() {
+        points.push("geiger_counter_cpm_avg");
+    }
+    if config.temperature_enabled() && model_has_temperature(&identity.model) {
+        points.push("temperature");
+    }
+    points
+        .into_iter()
+        .map(|point| PublishMessage {
+            topic: format!("gqgmcmqtt/{serial}/{point}"),
+            payload: Payload::CurrentState(StatePayload::default()),
+            retain: true,
+        })
+        .collect()
+}
+
+/// With `publish_unavailable_on_error`, unknown states for the polled readings as soon as a
+/// poll fails rather than leaving HA on the last value until it expires. Once per outage,
+/// so a long one doesn't republish the same unknown every poll.
+pub fn unavailable_states(config: &AppConfig, state: &mut PollState) -> Vec<PublishMessage> {
+    if state.last_read_ok {
+        state.marked_unavailable = false;
+        return vec![];
+    }
+    if !config.publish_unavailable_on_error.unwrap_or(false) || state.marked_unavailable || state.removed {
+        return vec![];
+    }
+    state.marked_unavailable = true;
+    match &state.identity {
+        Some(identity) => unknown_states(config, identity),
+        None => vec![],
+    }
+}
+
+/// Reads one CPS sample and returns an alert if it's at or over `spike_alert_cps`. Fires
+/// once per excursion; it re-arms when a sample drops back under the threshold.
+pub async fn check_spike<D: GeigerDevice>(gmc: &mut D, config: &AppConfig, state: &mut PollState) -> Option<PublishMessage> {
+    let threshold = config.spike_alert_cps?;
+    let serial = state.identity.as_ref()?.serial.clone();
+    let model = state.identity.as_ref()?.model.clone();
+    let cps = match gmc.get_cps().await {
+        Ok(cps) => cpm_byte_order(cps, &model, config.cpm_endianness.unwrap_or_default()),
+        Err(e) => {
+            debug!("Can't read CPS for the spike check: {e}");
+            return None;
+        }
+    };
+    if cps < threshold {
+        state.spiking = false;
+        return None;
+    }
+    if std::mem::replace(&mut state.spiking, true) {
+        return None;
+    }
+    warn!("CPS of {cps} reached spike_alert_cps {threshold}, alerting now.");
+    Some(PublishMessage {
+        topic: format!("gqgmcmqtt/{serial}/spike_alert"),
+        payload: Payload::SpikeAlert(SpikeAlert { cps, threshold, ts: Utc::now() }),
+        retain: false,
+    })
+}
+
+/// The discovery event for the current unit if it hasn't been announced yet. With
+/// `state_file` set the announced serials survive restarts; without it a restart
+/// announces again.
+pub fn discovery_event(state: &mut PollState) -> Option<PublishMessage> {
+    let identity = state.identity.as_ref()?;
+    if !state.announced.insert(identity.serial.clone()) {
+        return None;
+    }
+    Some(PublishMessage {
+        topic: "gqgmcmqtt/discovered".to_string(),
+        payload: Payload::Discovered(DiscoveryEvent {
+            serial: identity.serial.clone(),
+            model: identity.model.clone(),
+            first_seen: Utc::now(),
+        }),
+        retain: true,
+    })
+}
+
+/// Once the unit has been silent for `remove_after_mins`, empty retained configs that take
+/// its entities out of HA. Sent once; the caller stops publishing for the unit until it
+/// answers again, which re-creates everything. Held back while a broker is down, when the
+/// silence might not be the unit's fault.
+pub fn absent_removal(config: &AppConfig, state: &mut PollState, brokers_up: bool) -> Option<Vec<PublishMessage>> {
+    let mins = config.remove_after_mins?;
+    if state.last_read_ok {
+        state.removed = false;
+        return None;
+    }
+    if state.removed || !brokers_up {
+        return None;
+    }
+    let silent = state.last_success.map(|t| t.elapsed()).unwrap_or_else(host_metrics::uptime);
+    if silent < Duration::from_secs(mins * 60) {
+        return None;
+    }
+    let serial = state.identity.as_ref().map(|i| i.serial.clone()).unwrap_or_default();
+    warn!("Unit {serial} hasn't answered for {mins} minutes, removing its entities from Home Assistant.");
+    state.removed = true;
+    Some(
+        state
+            .config_topics
+            .drain()
+            .map(|topic| PublishMessage {
+                topic,
+                payload: Payload::None,
+                retain: true,
+            })
+            .collect(),
+    )
+}
+
+/// Empty retained configs for every discovery topic sent this session, for
+/// `purge_discovery_on_exit`. Topics already removed for an absent or swapped unit have
+/// been dropped from the set by then.
+pub fn purge_discovery(state: &mut PollState) -> Vec<PublishMessage> {
+    state
+        .config_topics
+        .drain()
+        .map(|topic| PublishMessage {
+            topic,
+            payload: Payload::None,
+            retain: true,
+        })
+        .collect()
+}
+
+/// One availability message per sensor seen so far. A sensor stays online while its last
+/// good reading is within its `expires_after` (or just for the cycle that read it, for
+/// sensors that never expire), so one failing read takes only its own entity offline.
+pub fn sensor_availability(config: &AppConfig, state: &PollState) -> Vec<PublishMessage> {
+    if !config.sensor_availability.unwrap_or(false) || state.removed {
+        return vec![];
+    }
+    state
+        .sensor_last_ok
+        .iter()
+        .map(|(topic, (last_ok, expires_after))| {
+            let fresh = Duration::from_secs(*expires_after).max(config.poll_interval());
+            PublishMessage {
+                topic: format!("{topic}/availability"),
+                payload: Payload::Availability(last_ok.elapsed() < fresh),
+                retain: true,
             }
-            *cpm
+        })
+        .collect()
+}
+
+/// Readings folded into the CPM sensor's attributes by `primary_sensor_with_attributes`.
+const MERGED_SENSORS: [&str; 10] = ["cps", "usv", "voltage", "cph", "cpm_avg", "cpm_corrected", "cpm_uncertainty", "cpm_stddev", "temperature", "wifi_rssi"];
+
+/// Moves the other readings' states onto the CPM sensor as attributes, named after their
+/// sensor keys, and drops their own entities. Nothing happens on a failed poll, where
+/// there's no CPM sensor to carry them.
+fn merge_into_primary(payloads: &mut Vec<CompoundPayload>) {
+    if !payloads.iter().any(|p| p.sensor == "cpm") {
+        return;
+    }
+    let mut merged = HashMap::new();
+    payloads.retain(|p| {
+        if !MERGED_SENSORS.contains(&p.sensor) {
+            return true;
+        }
+        if let Ok(value) = serde_json::to_value(&p.state.value) {
+            merged.insert(p.sensor.to_string(), value);
+        }
+        false
+    });
+    let Some(cpm) = payloads.iter_mut().find(|p| p.sensor == "cpm").filter(|_| !merged.is_empty()) else {
+        return;
+    };
+    cpm.config.json_attributes_topic = Some(cpm.state_topic.clone());
+    cpm.config.json_attributes_template = Some("{{ value_json.attributes | tojson }}".to_string());
+    cpm.state.attributes.get_or_insert_with(HashMap::new).extend(merged);
+}
+
+/// Whether `combined_state` carries this entity: sensors and binary sensors. Switches and
+/// numbers keep the topics their commands publish back to, and `last_seen` its own topic,
+/// since its key would clash with the combined message's own `last_seen`.
+fn is_combined(payload: &CompoundPayload) -> bool {
+    (payload.config_topic.starts_with("homeassistant/sensor/") || payload.config_topic.starts_with("homeassistant/binary_sensor/"))
+        && payload.sensor != "last_seen"
+}
+
+/// Points every combined entity at the shared state topic, reading its own key out of it.
+fn point_at_combined(serial: &str, payloads: &mut [CompoundPayload]) {
+    let topic = format!("gqgmcmqtt/{serial}/state");
+    for p in payloads.iter_mut().filter(|p| is_combined(p)) {
+        if p.config.json_attributes_topic.as_ref() == Some(&p.state_topic) {
+            p.config.json_attributes_topic = Some(topic.clone());
+            p.config.json_attributes_template = Some(format!("{{{{ value_json.attributes.{} | tojson }}}}", p.sensor));
+        }
+        p.config.state_topic = topic.clone();
+        p.config.value_template = Some(format!("{{{{ value_json.{} }}}}", p.sensor));
+    }
+}
+
+/// The one state message standing in for every combined entity's own.
+pub fn combined_state_message(serial: &str, payloads: &[CompoundPayload]) -> PublishMessage {
+    let mut combined = CombinedStatePayload { last_seen: Some(Utc::now()), ..Default::default() };
+    for p in payloads.iter().filter(|p| is_combined(p)) {
+        if let Ok(value) = serde_json::to_value(&p.state.value) {
+            combined.values.insert(p.sensor.to_string(), value);
+        }
+        if let Some(attributes) = &p.state.attributes {
+            combined.attributes.insert(p.sensor.to_string(), attributes.clone());
+        }
+    }
+    PublishMessage {
+        topic: format!("gqgmcmqtt/{serial}/state"),
+        payload: Payload::CombinedState(combined),
+        retain: false,
+    }
+}
+
+/// Reads the whole config block and returns it for `device_config` if it changed since
+/// the last one sent. None when the unit can't be read or nothing changed.
+pub async fn device_config_message<D: GeigerDevice>(
+    gmc: &mut D,
+    features: &HashSet<DeviceFeature>,
+    state: &mut PollState,
+) -> Option<PublishMessage> {
+    if !features.contains(&DeviceFeature::Config) {
+        return None;
+    }
+    let identity = state.identity.clone()?;
+    let block = match gmc.get_config().await {
+        Ok(block) => block,
+        Err(e) => {
+            debug!("Can't read the config block for device_config: {e}");
+            return None;
+        }
+    };
+    if state.device_config.as_ref() == Some(&block) {
+        return None;
+    }
+    let payload = decode_config(&identity.model, &block);
+    state.device_config = Some(block);
+    Some(PublishMessage {
+        topic: format!("gqgmcmqtt/{}/device_config", identity.serial),
+        payload: Payload::DeviceConfig(payload),
+        retain: true,
+    })
+}
+
+fn decode_config(model: &str, block: &[u8]) -> DeviceConfigPayload {
+    let mut settings = BTreeMap::new();
+    let mut named = vec![false; block.len()];
+    for field in CONFIG_FIELDS.iter().filter(|f| f.applies_to(model)) {
+        let Some(bytes) = block.get(field.offset..field.offset + field.len) else {
+            continue;
+        };
+        settings.insert(field.name.to_string(), bytes.iter().fold(0_u32, |acc, b| (acc << 8) | *b as u32));
+        named[field.offset..field.offset + field.len].fill(true);
+    }
+    let mut unknown: Vec<ConfigRegion> = vec![];
+    for (offset, byte) in block.iter().enumerate().filter(|(i, _)| !named[*i]) {
+        match unknown.last_mut() {
+            Some(region) if region.offset + region.hex.len() / 2 == offset => region.hex.push_str(&format!("{byte:02x}")),
+            _ => unknown.push(ConfigRegion { offset, hex: format!("{byte:02x}") }),
+        }
+    }
+    DeviceConfigPayload { model: model.to_string(), settings, unknown }
+}
+
+/// The CPM reading restored from `state_file`, stamped with when it was taken, so HA has
+/// a value from startup until the first poll rather than a gap.
+pub fn restored_reading(state: &PollState) -> Option<PublishMessage> {
+    let identity = state.identity.as_ref()?;
+    let (cpm, at) = state.last_reading?;
+    Some(PublishMessage {
+        topic: format!("gqgmcmqtt/{}/geiger_counter_cpm", identity.serial),
+        payload: Payload::CurrentState(StatePayload {
+            value: PayloadValueType::Int(cpm as i64),
+            last_seen: Some(at),
+            ..Default::default()
+        }),
+        retain: false,
+    })
+}
+
+/// Every sensor marked offline, for the end of a bounded run.
+pub fn offline_states(state: &PollState) -> Vec<PublishMessage> {
+    state
+        .sensor_last_ok
+        .keys()
+        .map(|topic| PublishMessage {
+            topic: format!("{topic}/availability"),
+            payload: Payload::Availability(false),
+            retain: true,
+        })
+        .collect()
+}
+
+pub async fn generate_payloads<D: GeigerDevice>(
+    gmc: &mut D,
+    config: &AppConfig,
+    features: &HashSet<DeviceFeature>,
+    state: &mut PollState,
+) -> Vec<CompoundPayload> {
+    let gmc = &mut TimedRead(gmc, config.serial_read_timeout());
+    if config.wakeup_command.unwrap_or(false) {
+        // units dozing in a low-power state botch the first reply, so spend it on GETVER
+        debug!("Sending wake-up command before polling.");
+        let _ = gmc.get_version().await;
+    }
+    let capture = config.capture_raw.unwrap_or(false);
+    gmc.set_capture(capture);
+    let mut payloads = if config.flush_before_read() {
+        build_payloads(&mut FlushBeforeRead(&mut *gmc), config, features, state).await
+    } else {
+        build_payloads(gmc, config, features, state).await
+    };
+    if config.primary_sensor_with_attributes.unwrap_or(false) {
+        merge_into_primary(&mut payloads);
+    }
+    state.captured = if capture { Some(gmc.take_captured()) } else { None };
+    for p in payloads.iter_mut() {
+        state.config_topics.insert(p.config_topic.clone());
+        if let Some(prefix) = &config.unique_id_prefix {
+            prefixed_ids(p, prefix);
+        }
+        // every entity_id is already a serial-based slug, so pin the object_id to it
+        p.config.object_id = p.config.entity_id.split_once('.').map(|(_, id)| id.to_string());
+        let sensor = config.sensor(p.sensor);
+        if let Some(class) = sensor.device_class {
+            p.config.device_class = Some(class);
+        }
+        if let Some(precision) = sensor.precision {
+            p.config.suggested_display_precision = Some(precision);
+        }
+        if let Some(enabled) = sensor.enabled_by_default {
+            p.config.enabled_by_default = Some(enabled);
+        }
+        // binary sensors, switches and numbers have no state_class
+        if let Some(class) = sensor.state_class.filter(|_| p.config_topic.starts_with("homeassistant/sensor/")) {
+            p.config.state_class = Some(class).filter(|c| !c.is_empty());
+        }
+        // sensors that never expire keep doing so unless configured individually
+        if sensor.expires_after.is_some() || p.config.expires_after != 0 {
+            p.config.expires_after = config.expires_after(sensor.expires_after);
+        }
+        if config.sensor_availability.unwrap_or(false) {
+            p.config.availability_topic = Some(format!("{}/availability", p.state_topic));
+            state.sensor_last_ok.insert(p.state_topic.clone(), (Instant::now(), p.config.expires_after));
+        }
+    }
+    if let (true, Some(identity)) = (config.combined_state.unwrap_or(false), &state.identity) {
+        point_at_combined(&identity.serial, &mut payloads);
+    }
+    payloads
+}
+
+/// Rebuilds the ids as `{prefix}_{serial}_{point}`, taking the point from the discovery
+/// topic, which is already one per entity, so no two of a unit's entities can share one.
+fn prefixed_ids(payload: &mut CompoundPayload, prefix: &str) {
+    // homeassistant/{component}/{serial}/{point}/config
+    let mut parts = payload.config_topic.split('/').skip(1);
+    if let (Some(component), Some(serial), Some(point)) = (parts.next(), parts.next(), parts.next()) {
+        let id = format!("{prefix}_{serial}_{point}");
+        payload.config.entity_id = format!("{component}.{id}");
+        payload.config.unique_id = id;
+    }
+}
+
+/// What sets one numeric reading's entity apart from the others; `reading_payload`
+/// fills in the rest the same way for all of them.
+struct ReadingSpec {
+    /// Key for the reading under `sensors` and `display_precision`.
+    sensor: &'static str,
+    /// Last segment of the discovery and state topics.
+    point: &'static str,
+    /// Appended to `{model}-{serial}` for the unique_id. The CPM sensor's predates the
+    /// suffixes, and the others keep theirs as first published so HA keeps the history.
+    unique_suffix: &'static str,
+    /// The entity_id after `sensor.{serial}_`.
+    entity: &'static str,
+    name: &'static str,
+    /// The unit when the reading doesn't pick one at publish time.
+    uom: &'static str,
+    device_class: Option<&'static str>,
+    state_class: &'static str,
+    precision: Option<u8>,
+    diagnostic: bool,
+    icon: &'static str,
+}
+
+const CPM_READING: ReadingSpec = ReadingSpec { sensor: "cpm", point: "geiger_counter_cpm", unique_suffix: "", entity: "geiger_tube_cpm", name: "CPM", uom: "cpm", device_class: None, state_class: "measurement", precision: Some(0), diagnostic: false, icon: "mdi:radioactive" };
+const CPS_READING: ReadingSpec = ReadingSpec { sensor: "cps", point: "geiger_counter_cps", unique_suffix: "-geiger_counter_cps", entity: "geiger_tube_cps", name: "CPS", uom: "cps", device_class: None, state_class: "measurement", precision: Some(0), diagnostic: false, icon: "mdi:radioactive" };
+const USV_READING: ReadingSpec = ReadingSpec { sensor: "usv", point: "geiger_counter_usv", unique_suffix: "-geiger_counter_usv", entity: "geiger_tube_usv", name: "Dose Rate", uom: "µSv/h", device_class: None, state_class: "measurement", precision: Some(3), diagnostic: false, icon: "mdi:radioactive" };
+const CPH_READING: ReadingSpec = ReadingSpec { sensor: "cph", point: "geiger_counter_cph", unique_suffix: "-cph", entity: "geiger_tube_cph", name: "CPH", uom: "cph", device_class: None, state_class: "measurement", precision: Some(0), diagnostic: false, icon: "mdi:radioactive" };
+const CPM_CORRECTED_READING: ReadingSpec = ReadingSpec { sensor: "cpm_corrected", point: "cpm_corrected", unique_suffix: "-cpm_corrected", entity: "cpm_corrected", name: "CPM (Dead-Time Corrected)", uom: "cpm", device_class: None, state_class: "measurement", precision: Some(0), diagnostic: false, icon: "mdi:radioactive" };
+const CPM_UNCERTAINTY_READING: ReadingSpec = ReadingSpec { sensor: "cpm_uncertainty", point: "cpm_uncertainty", unique_suffix: "-cpm_uncertainty", entity: "cpm_uncertainty", name: "CPM Uncertainty", uom: "cpm", device_class: None, state_class: "measurement", precision: Some(1), diagnostic: true, icon: "mdi:plus-minus-variant" };
+const CPM_STDDEV_READING: ReadingSpec = ReadingSpec { sensor: "cpm_stddev", point: "cpm_stddev", unique_suffix: "-cpm_stddev", entity: "cpm_stddev", name: "CPM Std Dev", uom: "cpm", device_class: None, state_class: "measurement", precision: Some(1), diagnostic: true, icon: "mdi:sigma" };
+const CPM_PEAK_READING: ReadingSpec = ReadingSpec { sensor: "cpm_peak", point: "geiger_counter_cpm_peak", unique_suffix: "-geiger_counter_cpm_peak", entity: "geiger_counter_cpm_peak", name: "CPM Peak", uom: "cpm", device_class: None, state_class: "measurement", precision: None, diagnostic: false, icon: "mdi:chart-line-variant" };
+const CPM_AVG_READING: ReadingSpec = ReadingSpec { sensor: "cpm_avg", point: "geiger_counter_cpm_avg", unique_suffix: "-geiger_counter_cpm_avg", entity: "geiger_counter_cpm_avg", name: "CPM Average", uom: "cpm", device_class: None, state_class: "measurement", precision: Some(1), diagnostic: false, icon: "mdi:chart-bell-curve-cumulative" };
+const TEMPERATURE_READING: ReadingSpec = ReadingSpec { sensor: "temperature", point: "temperature", unique_suffix: "-temperature", entity: "temperature", name: "Temperature", uom: "°C", device_class: Some("temperature"), state_class: "measurement", precision: Some(1), diagnostic: false, icon: "mdi:thermometer" };
+const VOLTAGE_READING: ReadingSpec = ReadingSpec { sensor: "voltage", point: "geiger_counter_voltage", unique_suffix: "-geiger_counter_voltage", entity: "geiger_tube_voltage", name: "Battery Voltage", uom: "V", device_class: Some("voltage"), state_class: "measurement", precision: Some(1), diagnostic: true, icon: "mdi:battery" };
+const WIFI_RSSI_READING: ReadingSpec = ReadingSpec { sensor: "wifi_rssi", point: "wifi_rssi", unique_suffix: "-wifi_rssi", entity: "wifi_rssi", name: "WiFi Signal", uom: "dBm", device_class: Some("signal_strength"), state_class: "measurement", precision: None, diagnostic: true, icon: "mdi:wifi" };
+// uptime only grows until a restart, which HA's total_increasing reads as a reset
+const GATEWAY_UPTIME_READING: ReadingSpec = ReadingSpec { sensor: "gateway_uptime", point: "gateway_uptime", unique_suffix: "-gateway_uptime", entity: "gateway_uptime", name: "Gateway Uptime", uom: "s", device_class: Some("duration"), state_class: "total_increasing", precision: None, diagnostic: true, icon: "mdi:timer-outline" };
+const GATEWAY_MEMORY_READING: ReadingSpec = ReadingSpec { sensor: "gateway_memory", point: "gateway_memory", unique_suffix: "-gateway_memory", entity: "gateway_memory", name: "Gateway Memory", uom: "MiB", device_class: Some("data_size"), state_class: "measurement", precision: None, diagnostic: true, icon: "mdi:memory" };
+
+/// The sensor entity for one reading of `spec`. Anything else it needs, such as a unit
+/// chosen at publish time or attributes, goes onto the result.
+fn reading_payload(config: &AppConfig, identity: &DeviceIdentity, device: &DeviceInfo, spec: &ReadingSpec, value: PayloadValueType) -> CompoundPayload {
+    let serial = &identity.serial;
+    let config_topic = format!("homeassistant/sensor/{serial}/{}/config", spec.point);
+    let state_topic = format!("gqgmcmqtt/{serial}/{}", spec.point);
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.state_topic = state_topic.clone();
+    config_payload.name = config.sensor_name(spec.sensor, spec.name);
+    config_payload.has_entity_name = Some(true);
+    config_payload.device_class = spec.device_class.map(str::to_string);
+    config_payload.state_class = Some(spec.state_class.to_string());
+    config_payload.expires_after = 300;
+    config_payload.value_template = value_template(config);
+    config_payload.unique_id = format!("{}-{serial}{}", identity.model, spec.unique_suffix);
+    config_payload.entity_id = format!("sensor.{serial}_{}", spec.entity);
+    if spec.diagnostic {
+        config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    }
+    config_payload.suggested_display_precision = spec.precision;
+    config_payload.native_uom = Some(spec.uom.to_string());
+    config_payload.device = device.clone();
+    config_payload.icon = Some(spec.icon.to_string());
+    CompoundPayload {
+        sensor: spec.sensor,
+        config: config_payload,
+        state: StatePayload {
+            value,
+            ..Default::default()
         },
+        config_topic,
+        state_topic,
+    }
+}
+
+async fn build_payloads<D: GeigerDevice>(
+    gmc: &mut D,
+    config: &AppConfig,
+    features: &HashSet<DeviceFeature>,
+    state: &mut PollState,
+) -> Vec<CompoundPayload> {
+    state.grafana = None;
+    state.last_read_ok = false;
+    let identity = match current_identity(gmc, config, state).await {
+        Ok(id) => id,
+        Err(e) => return read_failed(config, state, &e),
+    };
+    state.identity = Some(identity.clone());
+    let DeviceIdentity { serial, model } = identity.clone();
+    let device_info = device_info(config, &identity);
+
+    let cpm = match &gmc.get_cpm().await {
+        // 0 is a real reading in a quiet spot; a dead link shows up as an Err instead
+        Ok(cpm) => cpm_byte_order(*cpm, &model, config.cpm_endianness.unwrap_or_default()),
         Err(e) => {
-            error!{"Can't get cpm from device: {e}"};
-            return vec![];
+            let e = GQGMCMQTTError::Device(format!("Can't get cpm from device: {e}"));
+            return read_failed(config, state, &e);
         }
     };
+    state.error_log.clear();
+    state.last_success = Some(Instant::now());
+    state.last_read_ok = true;
+    state.valid_streak = state.valid_streak.saturating_add(1);
+    state.failed_streak = 0;
+    // on the raw reading, since the median filter repeats values by design
+    let frozen = config.freeze_detect_samples.map(|window| state.check_frozen(cpm, window));
+    let window = config.median_filter_window.unwrap_or(0);
+    let cpm = if window > 1 { state.median_cpm(cpm, window) } else { cpm };
+    // stamped now, right after the read, so the last_seen sensor isn't a publish time
+    state.last_reading = Some((cpm, Utc::now()));
+    metrics::record_reading(cpm, cpm as f32 / config.usv_conversion_factor.unwrap_or(DEFAULT_USV_CONVERSION_FACTOR));
+    state.grafana = Some(GrafanaPayload {
+        cpm,
+        ts: Utc::now().timestamp(),
+        ..Default::default()
+    });
 
     let unit_name = format!("{model}-{serial}");
 
-    let mut config_payload: HAConfigPayload = HAConfigPayload::default();
-    let mut state_payload: StatePayload = StatePayload::default();
+    let mut resp = reading_payload(config, &identity, &device_info, &CPM_READING, PayloadValueType::Int(cpm as i64));
+    if config.publish_config_attributes.unwrap_or(false) {
+        resp.config.extra_state_attributes = Some(processing_attributes(config, &model));
+    }
+    let mut attributes = HashMap::new();
+    if config.settle_time_secs.unwrap_or(0) > 0 {
+        attributes.insert("settling".to_string(), serde_json::Value::from(state.settling(config)));
+    }
+    if config.cpm_uncertainty_attribute.unwrap_or(false) {
+        attributes.insert("uncertainty".to_string(), serde_json::Value::from(cpm_uncertainty(cpm)));
+    }
+    if config.location_attributes.unwrap_or(false) {
+        if let Some((lat, lon)) = gps::position(config) {
+            attributes.insert("latitude".to_string(), serde_json::Value::from(lat));
+            attributes.insert("longitude".to_string(), serde_json::Value::from(lon));
+        }
+    }
+    if !attributes.is_empty() {
+        resp.config.json_attributes_topic = Some(resp.state_topic.clone());
+        resp.config.json_attributes_template = Some("{{ value_json.attributes | tojson }}".to_string());
+        resp.state.attributes = Some(attributes);
+    }
+    let mut payloads = vec![resp, last_error_payload(config, &identity, None), connection_state_payload(config, &identity, state)];
+    if config.sensor_enabled("seconds_since_last_read", true) {
+        payloads.push(since_last_read_payload(config, &identity, state));
+    }
+    if config.sensor_enabled("last_seen", true) {
+        payloads.extend(last_seen_payload(config, &identity, state));
+    }
+
+    // readings built on the history hold off until the link has proven itself again
+    let needed = config.min_valid_samples.unwrap_or(1);
+    let trusted = state.valid_streak >= needed;
+    if !trusted {
+        debug!("{} of {needed} good readings since the last failure, holding back derived sensors", state.valid_streak);
+    }
 
-    let config_topic: String = format!("homeassistant/sensor/{serial}/geiger_counter_cpm/config");
-    let state_topic = format!("gqgmcmqtt/{serial}/geiger_counter_cpm");
-    config_payload.state_topic = state_topic.clone();
+    if config.sensor_enabled("cps", true) {
+        // units that don't answer GETCPS (see probe_features) get the minute's average
+        let measured = match features.contains(&DeviceFeature::Cps) {
+            true => gmc.get_cps().await.ok().map(|cps| cpm_byte_order(cps, &model, config.cpm_endianness.unwrap_or_default())),
+            false => None,
+        };
+        if let Some(reading) = state.grafana.as_mut() {
+            reading.cps = measured;
+        }
+        let cps = match measured {
+            Some(cps) => reading_payload(config, &identity, &device_info, &CPS_READING, PayloadValueType::Int(cps as i64)),
+            None => {
+                let mut cps = reading_payload(config, &identity, &device_info, &CPS_READING, PayloadValueType::Float(cpm as f32 / 60.0));
+                cps.config.suggested_display_precision = Some(2);
+                cps.state.notes = Some("computed from CPM, not measured".to_string());
+                cps
+            }
+        };
+        payloads.push(cps);
+    }
 
-    config_payload.name = unit_name.clone();
-    config_payload.device_class = None;
-    config_payload.state_class = Some("measurement".to_string());
-    config_payload.expires_after = 300;
-    config_payload.value_template = Some("{{ value_json.value }}".to_string());
-    config_payload.unique_id = unit_name.clone();
-    config_payload.entity_id = format!("sensor.{serial}_geiger_tube_cpm");
-    config_payload.suggested_display_precision = Some(0);
-    config_payload.native_uom = Some("cpm".to_string());
-    config_payload.device = device_info;
-    config_payload.icon = Some("mdi:radioactive".to_string());
+    if config.sensor_enabled("usv", true) {
+        let factor = config.usv_conversion_factor.unwrap_or(DEFAULT_USV_CONVERSION_FACTOR);
+        let usv = cpm as f32 / factor;
+        if let Some(reading) = state.grafana.as_mut() {
+            reading.usv = Some(usv);
+        }
+        let unit = config.dose_unit();
+        let (dose, uom) = match config.dose_quantity {
+            Some(quantity) => quantity.from_usv(usv, unit),
+            None => unit.from_usv(usv),
+        };
+        let mut dose_rate = reading_payload(config, &identity, &device_info, &USV_READING, PayloadValueType::Float(dose));
+        dose_rate.config.native_uom = Some(uom.to_string());
+        if let Some(quantity) = config.dose_quantity {
+            dose_rate.config.name = config.sensor_name("usv", quantity.sensor_name());
+        }
+        if unit == DoseUnit::Microroentgen {
+            dose_rate.config.suggested_display_precision = Some(1);
+        }
+        payloads.push(dose_rate);
+    }
+
+    // counts-per-hour is just the cpm reading scaled, for people who find the small
+    // numbers at low background rates hard to read
+    if config.sensor_enabled("cph", false) {
+        payloads.push(reading_payload(config, &identity, &device_info, &CPH_READING, PayloadValueType::Int(cpm as i64 * 60)));
+    }
+
+    if let Some(dead_time_us) = config.dead_time_us.filter(|_| trusted) {
+        match dead_time_corrected(cpm, dead_time_us) {
+            Some(corrected) => {
+                payloads.push(reading_payload(config, &identity, &device_info, &CPM_CORRECTED_READING, PayloadValueType::Float(corrected)));
+            }
+            None => {
+                warn!("{cpm} cpm saturates a {dead_time_us}us dead time, can't correct it");
+            }
+        }
+    }
+
+    if config.sensor_enabled("cpm_uncertainty", false) {
+        payloads.push(reading_payload(config, &identity, &device_info, &CPM_UNCERTAINTY_READING, PayloadValueType::Float(cpm_uncertainty(cpm))));
+    }
+
+    // compare against the average before this reading joins it
+    let baseline = state.baseline_cpm();
+    if trusted {
+        state.record_cpm(cpm);
+    }
+    if trusted && config.sensor_enabled("cpm_stddev", false) {
+        if let Some(stddev) = state.cpm_stddev() {
+            payloads.push(reading_payload(config, &identity, &device_info, &CPM_STDDEV_READING, PayloadValueType::Float(stddev)));
+        }
+    }
+    if trusted && config.sensor_enabled("cpm_peak", false) {
+        let peak = *state.peak_cpm.insert(state.peak_cpm.map_or(cpm, |p| p.max(cpm)));
+        payloads.push(reading_payload(config, &identity, &device_info, &CPM_PEAK_READING, PayloadValueType::Int(peak as i64)));
+
+        // a button has no state of its own; the topic is only there to fill the payload
+        let config_topic = format!("homeassistant/button/{serial}/{RESET_PEAK_POINT}/config");
+        let state_topic = format!("gqgmcmqtt/{serial}/{RESET_PEAK_POINT}");
+        let mut config_payload = HAConfigPayload::default();
+        config_payload.state_topic = state_topic.clone();
+        config_payload.command_topic = Some(format!("{state_topic}/set"));
+        config_payload.payload_press = Some(BUTTON_PRESS.to_string());
+        config_payload.name = config.sensor_name(RESET_PEAK_POINT, "Reset CPM Peak");
+        config_payload.has_entity_name = Some(true);
+        config_payload.unique_id = format!("{unit_name}-{RESET_PEAK_POINT}");
+        config_payload.entity_id = format!("button.{serial}_{RESET_PEAK_POINT}");
+        config_payload.entity_category = Some(EntityCategory::Config);
+        config_payload.device = device_info.clone();
+        config_payload.icon = Some("mdi:restart".to_string());
+        payloads.push(CompoundPayload {
+            sensor: RESET_PEAK_POINT,
+            config: config_payload,
+            state: StatePayload::default(),
+            config_topic,
+            state_topic,
+        });
+    }
+    if let Some(window) = config.cpm_average_window.filter(|_| trusted) {
+        let average = state.average_cpm(cpm, window);
+        let mut avg = reading_payload(config, &identity, &device_info, &CPM_AVG_READING, PayloadValueType::Float(average));
+        avg.state.notes = Some(format!("mean of the last {} readings", state.average_window.len()));
+        payloads.push(avg);
+    }
+    if let Some(ratio) = config.alert_ratio.filter(|_| trusted) {
+        let quiet = config.in_quiet_hours();
+        let off_ratio = config.alert_off_ratio.unwrap_or(ratio);
+        let rising = !quiet && baseline.is_some_and(|b| rapid_increase(state.alerting, cpm, b, ratio, off_ratio));
+        state.alerting = rising;
+        let config_topic = format!("homeassistant/binary_sensor/{serial}/rapid_increase/config");
+        let state_topic = format!("gqgmcmqtt/{serial}/rapid_increase");
+        let mut config_payload = HAConfigPayload::default();
+        config_payload.name = config.sensor_name("rapid_increase", "Rapid Increase");
+        config_payload.has_entity_name = Some(true);
+        config_payload.state_topic = state_topic.clone();
+        config_payload.payload_on = Some(SWITCH_ON.to_string());
+        config_payload.payload_off = Some(SWITCH_OFF.to_string());
+        config_payload.expires_after = 300;
+        config_payload.value_template = value_template(config);
+        config_payload.json_attributes_topic = Some(state_topic.clone());
+        config_payload.json_attributes_template = Some("{{ value_json.attributes | tojson }}".to_string());
+        config_payload.unique_id = format!("{unit_name}-rapid_increase");
+        config_payload.entity_id = format!("binary_sensor.{serial}_rapid_increase");
+        config_payload.device = device_info.clone();
+        config_payload.icon = Some("mdi:alert".to_string());
+        let mut attributes = HashMap::new();
+        attributes.insert("quiet_hours".to_string(), serde_json::Value::from(quiet));
+        if let Some(b) = baseline {
+            attributes.insert("baseline_cpm".to_string(), serde_json::Value::from(b));
+        }
+        let mut state_payload = switch_state(rising);
+        state_payload.attributes = Some(attributes);
+        payloads.push(CompoundPayload {
+            sensor: "rapid_increase",
+            config: config_payload,
+            state: state_payload,
+            config_topic,
+            state_topic,
+        });
+    }
+    if let Some(threshold) = config.alarm_threshold_cpm.filter(|_| trusted) {
+        let over = cpm > threshold;
+        if over == state.alarm_on {
+            state.alarm_pending = 0;
+        } else {
+            state.alarm_pending += 1;
+            if state.alarm_pending >= config.alarm_debounce_polls.unwrap_or(DEFAULT_ALARM_DEBOUNCE_POLLS) {
+                state.alarm_on = over;
+                state.alarm_pending = 0;
+                warn!("Radiation alarm {} at {cpm} CPM (threshold {threshold})", if over { "on" } else { "off" });
+            }
+        }
+        let config_topic = format!("homeassistant/binary_sensor/{serial}/radiation_alarm/config");
+        let state_topic = format!("gqgmcmqtt/{serial}/radiation_alarm");
+        let mut config_payload = HAConfigPayload::default();
+        config_payload.name = config.sensor_name("radiation_alarm", "Radiation Alarm");
+        config_payload.has_entity_name = Some(true);
+        config_payload.state_topic = state_topic.clone();
+        config_payload.device_class = Some("safety".to_string());
+        config_payload.payload_on = Some(SWITCH_ON.to_string());
+        config_payload.payload_off = Some(SWITCH_OFF.to_string());
+        config_payload.expires_after = 300;
+        config_payload.value_template = value_template(config);
+        config_payload.json_attributes_topic = Some(state_topic.clone());
+        config_payload.json_attributes_template = Some("{{ value_json.attributes | tojson }}".to_string());
+        config_payload.unique_id = format!("{unit_name}-radiation_alarm");
+        config_payload.entity_id = format!("binary_sensor.{serial}_radiation_alarm");
+        config_payload.device = device_info.clone();
+        config_payload.icon = Some("mdi:radioactive-circle".to_string());
+        let mut state_payload = switch_state(state.alarm_on);
+        state_payload.attributes = Some(HashMap::from([(
+            "threshold_cpm".to_string(),
+            serde_json::Value::from(threshold),
+        )]));
+        payloads.push(CompoundPayload {
+            sensor: "radiation_alarm",
+            config: config_payload,
+            state: state_payload,
+            config_topic,
+            state_topic,
+        });
+    }
+
+    if let Some(frozen) = frozen {
+        let config_topic = format!("homeassistant/binary_sensor/{serial}/geiger_counter_frozen/config");
+        let state_topic = format!("gqgmcmqtt/{serial}/geiger_counter_frozen");
+        let mut config_payload = HAConfigPayload::default();
+        config_payload.name = config.sensor_name("frozen", "Readings Frozen");
+        config_payload.has_entity_name = Some(true);
+        config_payload.state_topic = state_topic.clone();
+        config_payload.device_class = Some("problem".to_string());
+        config_payload.payload_on = Some(SWITCH_ON.to_string());
+        config_payload.payload_off = Some(SWITCH_OFF.to_string());
+        config_payload.expires_after = 300;
+        config_payload.value_template = value_template(config);
+        config_payload.unique_id = format!("{unit_name}-geiger_counter_frozen");
+        config_payload.entity_id = format!("binary_sensor.{serial}_geiger_counter_frozen");
+        config_payload.entity_category = Some(EntityCategory::Diagnostic);
+        config_payload.device = device_info.clone();
+        config_payload.icon = Some("mdi:snowflake-alert".to_string());
+        let mut state_payload = switch_state(frozen);
+        if frozen {
+            state_payload.notes = Some(format!("last {} readings identical", state.freeze_window.len()));
+        }
+        payloads.push(CompoundPayload {
+            sensor: "frozen",
+            config: config_payload,
+            state: state_payload,
+            config_topic,
+            state_topic,
+        });
+    }
+
+    if config.publish_host_metrics.unwrap_or(false) {
+        let rss_mib = host_metrics::rss_bytes().map(|b| b as f32 / (1024.0 * 1024.0));
+        let metrics = [
+            (&GATEWAY_UPTIME_READING, Some(PayloadValueType::Int(host_metrics::uptime().as_secs() as i64))),
+            (&GATEWAY_MEMORY_READING, rss_mib.map(PayloadValueType::Float)),
+        ];
+        for (spec, value) in metrics {
+            if let Some(value) = value {
+                payloads.push(reading_payload(config, &identity, &device_info, spec, value));
+            }
+        }
+    }
 
+    if config.temperature_enabled() && !model_has_temperature(&model) {
+        debug!("{model} has no temperature sensor, skipping GETTEMP.");
+    } else if config.temperature_enabled() {
+        match gmc.get_temperature().await {
+            Ok(celsius) => {
+                let (value, uom) = config.temperature_unit().from_celsius(celsius);
+                let mut temperature = reading_payload(config, &identity, &device_info, &TEMPERATURE_READING, PayloadValueType::Float(value));
+                temperature.config.native_uom = Some(uom.to_string());
+                payloads.push(temperature);
+            }
+            Err(e) => {
+                debug!("Can't read temperature, skipping sensor: {e}");
+            }
+        }
+    }
+
+    if features.contains(&DeviceFeature::Voltage) && config.sensor_enabled("voltage", true) {
+        match gmc.get_voltage().await {
+            Ok(volts) => {
+                if let Some(reading) = state.grafana.as_mut() {
+                    reading.voltage = Some(volts);
+                }
+                payloads.push(reading_payload(config, &identity, &device_info, &VOLTAGE_READING, PayloadValueType::Float(volts)));
+            }
+            Err(e) => {
+                debug!("Can't read voltage, skipping sensor: {e}");
+            }
+        }
+    }
+
+    let extended = config.enable_extended_sensors.unwrap_or(false);
+    if extended && features.contains(&DeviceFeature::WifiRssi) {
+        match gmc.get_wifi_rssi().await {
+            Ok(rssi) => {
+                payloads.push(reading_payload(config, &identity, &device_info, &WIFI_RSSI_READING, PayloadValueType::Int(rssi as i64)));
+            }
+            Err(e) => {
+                debug!("Can't read WiFi RSSI, skipping sensor: {e}");
+            }
+        }
+    }
 
-    state_payload.value = PayloadValueType::Int(cpm as i64);
+    if extended {
+        let position = if features.contains(&DeviceFeature::Gps) {
+            match gmc.get_gps().await {
+                Ok(fix) => Some(fix),
+                Err(e) => {
+                    debug!("Can't read the unit's GPS, trying gps_device: {e}");
+                    gps::position(config)
+                }
+            }
+        } else {
+            gps::position(config)
+        };
+        match position {
+            Some((lat, lon)) => {
+                let mut config_payload = HAConfigPayload::default();
+                let config_topic = format!("homeassistant/device_tracker/{serial}/location/config");
+                let state_topic = format!("gqgmcmqtt/{serial}/location");
+                config_payload.state_topic = state_topic.clone();
+                config_payload.name = config.sensor_name("location", "Location");
+                config_payload.has_entity_name = Some(true);
+                config_payload.source_type = Some("gps".to_string());
+                config_payload.value_template = value_template(config);
+                config_payload.json_attributes_topic = Some(state_topic.clone());
+                config_payload.json_attributes_template = Some("{{ value_json.attributes | tojson }}".to_string());
+                config_payload.extra_state_attributes = Some(HashMap::from([
+                    ("latitude".to_string(), lat.to_string()),
+                    ("longitude".to_string(), lon.to_string()),
+                ]));
+                config_payload.unique_id = format!("{unit_name}-location");
+                config_payload.entity_id = format!("device_tracker.{serial}_location");
+                config_payload.entity_category = Some(EntityCategory::Diagnostic);
+                config_payload.device = device_info.clone();
+                config_payload.icon = Some("mdi:map-marker-radius".to_string());
+                payloads.push(CompoundPayload {
+                    sensor: "location",
+                    config: config_payload,
+                    state: StatePayload {
+                        // HA's payload_reset, so the tracker places itself by the attributes
+                        value: PayloadValueType::String("None".to_string()),
+                        attributes: Some(HashMap::from([
+                            ("latitude".to_string(), serde_json::Value::from(lat)),
+                            ("longitude".to_string(), serde_json::Value::from(lon)),
+                        ])),
+                        ..Default::default()
+                    },
+                    config_topic,
+                    state_topic,
+                });
+            }
+            None => debug!("No position for the unit, skipping the device tracker."),
+        }
+    }
 
+    if !features.contains(&DeviceFeature::Config) {
+        return payloads;
+    }
+    if model_has_log_interval(&model) {
+        match read_config_byte(gmc, CFG_SAVE_DATA_TYPE).await {
+            Ok(raw) => match log_interval_secs(raw) {
+                Some(secs) => payloads.push(log_interval_payload(config, &serial, &unit_name, &device_info, secs)),
+                None => debug!("Unknown SaveDataType {raw}, skipping log interval"),
+            },
+            Err(e) => {
+                debug!("Can't read log interval, skipping sensor: {e}");
+            }
+        }
+    }
+    for (switch, default_name, icon) in [
+        (&DATA_LOGGING_SWITCH, "Data Logging", "mdi:database-clock"),
+        (&CLICK_SOUND_SWITCH, "Click Sound", "mdi:volume-high"),
+    ] {
+        match switch.get(gmc).await {
+            Ok(on) => {
+                let point = switch.point;
+                let config_topic = format!("homeassistant/switch/{serial}/{point}/config");
+                let state_topic = format!("gqgmcmqtt/{serial}/{point}");
+                let mut config_payload = HAConfigPayload::default();
+                config_payload.name = config.sensor_name(point, default_name);
+                config_payload.has_entity_name = Some(true);
+                config_payload.state_topic = state_topic.clone();
+                config_payload.command_topic = Some(format!("{state_topic}/set"));
+                config_payload.payload_on = Some(SWITCH_ON.to_string());
+                config_payload.payload_off = Some(SWITCH_OFF.to_string());
+                config_payload.expires_after = 300;
+                config_payload.value_template = value_template(config);
+                config_payload.unique_id = format!("{unit_name}-{point}");
+                config_payload.entity_id = format!("switch.{serial}_{point}");
+                config_payload.entity_category = Some(EntityCategory::Config);
+                config_payload.device = device_info.clone();
+                config_payload.icon = Some(icon.to_string());
+                payloads.push(CompoundPayload {
+                    sensor: switch.point,
+                    config: config_payload,
+                    state: switch_state(on),
+                    config_topic,
+                    state_topic,
+                });
+            }
+            Err(e) => {
+                debug!("Can't read {} state, skipping switch: {e}", switch.point);
+            }
+        }
+    }
+    for (number, default_name, uom, icon) in [
+        (&BACKLIGHT_TIMEOUT_NUMBER, "Backlight Timeout", Some("s"), "mdi:timer-cog-outline"),
+        (&DISPLAY_BRIGHTNESS_NUMBER, "Display Brightness", None, "mdi:brightness-6"),
+    ] {
+        if !number.supported(&model) {
+            continue;
+        }
+        match number.get(gmc).await {
+            Ok(value) => {
+                let point = number.point;
+                let config_topic = format!("homeassistant/number/{serial}/{point}/config");
+                let state_topic = format!("gqgmcmqtt/{serial}/{point}");
+                let mut config_payload = HAConfigPayload::default();
+                config_payload.name = config.sensor_name(point, default_name);
+                config_payload.has_entity_name = Some(true);
+                config_payload.state_topic = state_topic.clone();
+                config_payload.command_topic = Some(format!("{state_topic}/set"));
+                config_payload.native_uom = uom.map(str::to_string);
+                config_payload.min = Some(number.min as i32);
+                config_payload.max = Some(number.max as i32);
+                config_payload.step = Some(1);
+                config_payload.mode = Some("box".to_string());
+                config_payload.expires_after = 300;
+                config_payload.value_template = value_template(config);
+                config_payload.unique_id = format!("{unit_name}-{point}");
+                config_payload.entity_id = format!("number.{serial}_{point}");
+                config_payload.entity_category = Some(EntityCategory::Config);
+                config_payload.device = device_info.clone();
+                config_payload.icon = Some(icon.to_string());
+                payloads.push(CompoundPayload {
+                    sensor: point,
+                    config: config_payload,
+                    state: number_state(value),
+                    config_topic,
+                    state_topic,
+                });
+            }
+            Err(e) => {
+                debug!("Can't read {}, skipping number: {e}", number.point);
+            }
+        }
+    }
+    match get_alarm_cpm(gmc).await {
+        Ok(cpm) => {
+            let config_topic = format!("homeassistant/number/{serial}/{ALARM_CPM_POINT}/config");
+            let state_topic = format!("gqgmcmqtt/{serial}/{ALARM_CPM_POINT}");
+            let mut config_payload = HAConfigPayload::default();
+            config_payload.name = config.sensor_name(ALARM_CPM_POINT, "Alarm Threshold");
+            config_payload.has_entity_name = Some(true);
+            config_payload.state_topic = state_topic.clone();
+            config_payload.command_topic = Some(format!("{state_topic}/set"));
+            config_payload.native_uom = Some("CPM".to_string());
+            config_payload.min = Some(ALARM_CPM_MIN as i32);
+            config_payload.max = Some(ALARM_CPM_MAX as i32);
+            config_payload.step = Some(1);
+            config_payload.mode = Some("box".to_string());
+            config_payload.expires_after = 300;
+            config_payload.value_template = value_template(config);
+            config_payload.unique_id = format!("{unit_name}-{ALARM_CPM_POINT}");
+            config_payload.entity_id = format!("number.{serial}_{ALARM_CPM_POINT}");
+            config_payload.entity_category = Some(EntityCategory::Config);
+            config_payload.device = device_info.clone();
+            config_payload.icon = Some("mdi:alarm-light-outline".to_string());
+            payloads.push(CompoundPayload {
+                sensor: ALARM_CPM_POINT,
+                config: config_payload,
+                state: number_state(cpm),
+                config_topic,
+                state_topic,
+            });
+        }
+        Err(e) => {
+            debug!("Can't read the alarm threshold, skipping number: {e}");
+        }
+    }
+    payloads
+}
 
+/// Whether the rapid-increase alert is on after this reading. An active alert holds until
+/// the reading falls under `off_ratio` times the baseline, so it doesn't flap around `on_ratio`.
+fn rapid_increase(alerting: bool, cpm: u32, baseline: f32, on_ratio: f32, off_ratio: f32) -> bool {
+    let threshold = if alerting { off_ratio } else { on_ratio };
+    cpm as f32 >= baseline.max(1.0) * threshold
+}
 
-    let resp = CompoundPayload {
+/// One standard deviation of a CPM reading, taking the count as Poisson over the unit's
+/// one-minute window: sqrt(N) counts over 1 minute. Ignores dead time and the median
+/// filter, so it's the uncertainty of a single raw reading.
+pub fn cpm_uncertainty(cpm: u32) -> f32 {
+    (cpm as f32).sqrt()
+}
+
+/// Non-paralyzable dead-time correction, N = M / (1 - M*tau) with both rates per second.
+/// None once the measured rate is high enough that the denominator isn't positive, where
+/// the reading says nothing about the true rate.
+pub fn dead_time_corrected(cpm: u32, dead_time_us: f64) -> Option<f32> {
+    let measured_cps = cpm as f64 / 60.0;
+    let denominator = 1.0 - measured_cps * dead_time_us / 1_000_000.0;
+    if denominator <= 0.0 {
+        return None;
+    }
+    Some((measured_cps / denominator * 60.0) as f32)
+}
+
+/// The settings that shape the CPM reading, for telling a wrong reading from a wrong setting.
+fn processing_attributes(config: &AppConfig, model: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    attributes.insert("poll_interval_secs".to_string(), config.poll_interval().as_secs().to_string());
+    let factor = config.usv_conversion_factor.unwrap_or(DEFAULT_USV_CONVERSION_FACTOR);
+    attributes.insert("usv_conversion_factor".to_string(), factor.to_string());
+    if let Some(quantity) = config.dose_quantity {
+        attributes.insert("dose_quantity".to_string(), format!("{quantity:?}"));
+    }
+    let tube = config.tube_type.clone().unwrap_or_else(|| model_tube(model).to_string());
+    attributes.insert("tube_type".to_string(), tube);
+    attributes.insert("median_filter_window".to_string(), config.median_filter_window.unwrap_or(0).to_string());
+    if let Some(dead_time) = config.dead_time_us {
+        attributes.insert("dead_time_us".to_string(), dead_time.to_string());
+    }
+    attributes
+}
+
+fn value_template(config: &AppConfig) -> Option<String> {
+    match config.state_schema.clone().unwrap_or_default() {
+        StateSchema::Nested => Some("{{ value_json.value }}".to_string()),
+        StateSchema::Flat => None,
+    }
+}
+
+/// Picks the unit's serial, falling back to the configured `device_id` when the unit
+/// reports a blank one. Returns None rather than ever producing an empty topic segment.
+pub fn resolve_serial(reported: &str, device_id: Option<&str>) -> Option<String> {
+    let reported = reported.trim();
+    if !reported.is_empty() {
+        return Some(reported.to_string());
+    }
+    match device_id.map(str::trim) {
+        Some(id) if !id.is_empty() => {
+            debug!("Unit reported an empty serial, using configured device_id {id}");
+            Some(id.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// A diagnostic sensor, or with `log_interval_control` a number entity written back
+/// through the command topic.
+fn log_interval_payload(
+    config: &AppConfig,
+    serial: &str,
+    unit_name: &str,
+    device_info: &DeviceInfo,
+    secs: u64,
+) -> CompoundPayload {
+    let control = config.log_interval_control.unwrap_or(false);
+    let component = if control { "number" } else { "sensor" };
+    let config_topic = format!("homeassistant/{component}/{serial}/log_interval/config");
+    let state_topic = format!("gqgmcmqtt/{serial}/log_interval");
+    let mut config_payload = HAConfigPayload::default();
+    config_payload.name = config.sensor_name("log_interval", "Log Interval");
+    config_payload.has_entity_name = Some(true);
+    config_payload.state_topic = state_topic.clone();
+    config_payload.device_class = Some("duration".to_string());
+    config_payload.native_uom = Some("s".to_string());
+    config_payload.expires_after = 300;
+    config_payload.value_template = value_template(config);
+    config_payload.unique_id = format!("{unit_name}-log_interval");
+    config_payload.entity_id = format!("{component}.{serial}_log_interval");
+    config_payload.device = device_info.clone();
+    config_payload.icon = Some("mdi:timer-cog-outline".to_string());
+    if control {
+        config_payload.command_topic = Some(format!("{state_topic}/set"));
+        config_payload.entity_category = Some(EntityCategory::Config);
+        config_payload.min = Some(0);
+        config_payload.max = Some(3600);
+        config_payload.step = Some(1);
+        config_payload.mode = Some("box".to_string());
+    } else {
+        config_payload.entity_category = Some(EntityCategory::Diagnostic);
+    }
+    CompoundPayload {
+        sensor: "log_interval",
         config: config_payload,
-        state: state_payload,
+        state: log_interval_state(secs),
         config_topic,
         state_topic,
-    };
-    vec![resp]
+    }
+}
+
+pub fn log_interval_state(secs: u64) -> StatePayload {
+    StatePayload {
+        value: PayloadValueType::Int(secs as i64),
+        ..Default::default()
+    }
+}
+
+pub fn number_state(value: impl Into<i64>) -> StatePayload {
+    StatePayload {
+        value: PayloadValueType::Int(value.into()),
+        ..Default::default()
+    }
+}
+
+pub fn switch_state(on: bool) -> StatePayload {
+    StatePayload {
+        value: PayloadValueType::String(if on { SWITCH_ON } else { SWITCH_OFF }.to_string()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DoseQuantity, SensorConfig};
+
+    const READINGS: [&ReadingSpec; 14] = [
+        &CPM_READING, &CPS_READING, &USV_READING, &CPH_READING, &CPM_CORRECTED_READING, &CPM_UNCERTAINTY_READING,
+        &CPM_STDDEV_READING, &CPM_PEAK_READING, &CPM_AVG_READING, &TEMPERATURE_READING, &VOLTAGE_READING,
+        &WIFI_RSSI_READING, &GATEWAY_UPTIME_READING, &GATEWAY_MEMORY_READING,
+    ];
+
+    #[test]
+    fn readings_dont_share_ids() {
+        let sensors: HashSet<_> = READINGS.iter().map(|r| r.sensor).collect();
+        let points: HashSet<_> = READINGS.iter().map(|r| r.point).collect();
+        let uniques: HashSet<_> = READINGS.iter().map(|r| r.unique_suffix).collect();
+        let entities: HashSet<_> = READINGS.iter().map(|r| r.entity).collect();
+        for set in [sensors.len(), points.len(), uniques.len(), entities.len()] {
+            assert_eq!(set, READINGS.len());
+        }
+    }
+
+    #[test]
+    fn reading_payload_keeps_the_published_ids() {
+        let config = AppConfig::default();
+        let identity = DeviceIdentity {
+            serial: "F488E1234".to_string(),
+            model: "GMC-500+Re 2.42".to_string(),
+        };
+        let device = device_info(&config, &identity);
+        let cpm = reading_payload(&config, &identity, &device, &CPM_READING, PayloadValueType::Int(20));
+        assert_eq!(cpm.config.unique_id, "GMC-500+Re 2.42-F488E1234");
+        assert_eq!(cpm.config.entity_id, "sensor.F488E1234_geiger_tube_cpm");
+        assert_eq!(cpm.config_topic, "homeassistant/sensor/F488E1234/geiger_counter_cpm/config");
+        assert_eq!(cpm.state_topic, "gqgmcmqtt/F488E1234/geiger_counter_cpm");
+
+        let voltage = reading_payload(&config, &identity, &device, &VOLTAGE_READING, PayloadValueType::Float(4.1));
+        assert_eq!(voltage.config.unique_id, "GMC-500+Re 2.42-F488E1234-geiger_counter_voltage");
+        assert_eq!(voltage.config.entity_id, "sensor.F488E1234_geiger_tube_voltage");
+        assert_eq!(voltage.config.device_class.as_deref(), Some("voltage"));
+        assert!(matches!(voltage.config.entity_category, Some(EntityCategory::Diagnostic)));
+    }
+
+    /// Answers from fixed values; `None` makes that read fail.
+    struct FakeGmc {
+        version: Option<&'static str>,
+        serial: Option<&'static str>,
+        cpm: Option<u32>,
+        voltage: Option<f32>,
+        temperature: Option<f32>,
+    }
+
+    impl Default for FakeGmc {
+        fn default() -> Self {
+            FakeGmc {
+                version: Some("GMC-500+Re 2.42"),
+                serial: Some("F488E1234"),
+                cpm: Some(20),
+                voltage: None,
+                temperature: None,
+            }
+        }
+    }
+
+    fn unanswered(what: &str) -> GQGMCMQTTError {
+        GQGMCMQTTError::Device(format!("no {what}"))
+    }
+
+    impl GeigerDevice for FakeGmc {
+        async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+            self.version.map(str::to_string).ok_or_else(|| unanswered("version"))
+        }
+
+        async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+            self.serial.map(str::to_string).ok_or_else(|| unanswered("serial"))
+        }
+
+        async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+            self.cpm.ok_or_else(|| unanswered("cpm"))
+        }
+
+        async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+            self.voltage.ok_or_else(|| unanswered("voltage"))
+        }
+
+        async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+            Err(unanswered("config"))
+        }
+
+        async fn write_config(&mut self, _offset: usize, _value: u8) -> Result<(), GQGMCMQTTError> {
+            Err(unanswered("config"))
+        }
+
+        async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+            Err(unanswered("config"))
+        }
+
+        async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+            self.temperature.ok_or_else(|| unanswered("temperature"))
+        }
+    }
+
+    async fn poll(gmc: &mut FakeGmc, config: &AppConfig, state: &mut PollState) -> Vec<CompoundPayload> {
+        generate_payloads(gmc, config, &HashSet::new(), state).await
+    }
+
+    #[tokio::test]
+    async fn cpm_topics_and_ids() {
+        let payloads = poll(&mut FakeGmc::default(), &AppConfig::default(), &mut PollState::default()).await;
+        let cpm = payloads.iter().find(|p| p.sensor == "cpm").expect("no cpm sensor");
+        assert_eq!(cpm.config_topic, "homeassistant/sensor/F488E1234/geiger_counter_cpm/config");
+        assert_eq!(cpm.state_topic, "gqgmcmqtt/F488E1234/geiger_counter_cpm");
+        assert_eq!(cpm.config.state_topic, cpm.state_topic);
+        assert_eq!(cpm.config.unique_id, "GMC-500+Re 2.42-F488E1234");
+        assert!(matches!(cpm.state.value, PayloadValueType::Int(20)));
+
+        let usv = payloads.iter().find(|p| p.sensor == "usv").expect("no usv sensor");
+        assert_eq!(usv.config_topic, "homeassistant/sensor/F488E1234/geiger_counter_usv/config");
+        assert_eq!(usv.config.unique_id, "GMC-500+Re 2.42-F488E1234-geiger_counter_usv");
+
+        let uniques: HashSet<_> = payloads.iter().map(|p| &p.config.unique_id).collect();
+        assert_eq!(uniques.len(), payloads.len());
+    }
+
+    #[tokio::test]
+    async fn dose_quantity_relabels_the_dose_rate() {
+        // 20 cpm over the default 151 cpm per µSv/h
+        let usv = 20.0 / 151.0;
+        for (quantity, name, uom, expected) in [
+            (None, "Dose Rate", "µSv/h", usv),
+            (Some(DoseQuantity::AmbientEquivalent), "Dose Rate H*(10)", "µSv/h", usv),
+            (Some(DoseQuantity::Absorbed), "Absorbed Dose Rate", "µGy/h", usv / 1.2),
+        ] {
+            let config = AppConfig {
+                dose_quantity: quantity,
+                ..Default::default()
+            };
+            let payloads = poll(&mut FakeGmc::default(), &config, &mut PollState::default()).await;
+            let dose = payloads.iter().find(|p| p.sensor == "usv").expect("no usv sensor");
+            assert_eq!(dose.config.name, name);
+            assert_eq!(dose.config.native_uom.as_deref(), Some(uom));
+            assert!(matches!(dose.state.value, PayloadValueType::Float(v) if (v - expected).abs() < 1e-6), "{quantity:?}");
+            // the topics and ids stay put, so switching quantity keeps the entity's history
+            assert_eq!(dose.config_topic, "homeassistant/sensor/F488E1234/geiger_counter_usv/config");
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_unit_publishes_nothing() {
+        // with no serial there is no device to attach a sensor to
+        let mut gmc = FakeGmc {
+            serial: None,
+            ..Default::default()
+        };
+        assert!(poll(&mut gmc, &AppConfig::default(), &mut PollState::default()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cpm_error_publishes_no_readings() {
+        let config = AppConfig::default();
+        let mut state = PollState::default();
+        let mut gmc = FakeGmc::default();
+        assert!(!poll(&mut gmc, &config, &mut state).await.is_empty());
+
+        gmc.cpm = None;
+        let payloads = poll(&mut gmc, &config, &mut state).await;
+        assert!(payloads.iter().all(|p| !matches!(p.sensor, "cpm" | "cps" | "usv")));
+        let last_error = payloads.iter().find(|p| p.sensor == "last_error").expect("no last_error sensor");
+        assert_eq!(last_error.config_topic, "homeassistant/sensor/F488E1234/last_error/config");
+    }
+
+    #[test]
+    fn blank_serials_fall_back_to_device_id() {
+        assert_eq!(resolve_serial(" F488E1234\n", None).as_deref(), Some("F488E1234"));
+        assert_eq!(resolve_serial("F488E1234", Some("garage")).as_deref(), Some("F488E1234"));
+        assert_eq!(resolve_serial("", Some(" garage ")).as_deref(), Some("garage"));
+        assert_eq!(resolve_serial(" \t\r\n", Some("garage")).as_deref(), Some("garage"));
+        assert_eq!(resolve_serial("", None), None);
+        assert_eq!(resolve_serial("   ", Some("  ")), None);
+    }
+
+    #[tokio::test]
+    async fn blank_version_and_serial() {
+        let mut gmc = FakeGmc {
+            version: Some("  "),
+            serial: Some(" \r\n"),
+            ..Default::default()
+        };
+        assert!(read_identity(&mut gmc, &AppConfig::default()).await.is_err());
+
+        let config = AppConfig {
+            device_id: Some("garage".to_string()),
+            ..Default::default()
+        };
+        let identity = read_identity(&mut gmc, &config).await.unwrap();
+        assert_eq!(identity.serial, "garage");
+        assert_eq!(identity.model, DEFAULT_MODEL);
+    }
+
+    #[tokio::test]
+    async fn expires_after_overrides_one_sensor() {
+        let cpm = SensorConfig {
+            expires_after: Some(900),
+            ..Default::default()
+        };
+        let cph = SensorConfig {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let config = AppConfig {
+            expires_after: Some(600),
+            sensors: Some(HashMap::from([("cpm".to_string(), cpm), ("cph".to_string(), cph)])),
+            ..Default::default()
+        };
+        let payloads = poll(&mut FakeGmc::default(), &config, &mut PollState::default()).await;
+        let expiry = |sensor: &str| payloads.iter().find(|p| p.sensor == sensor).map(|p| p.config.expires_after);
+        assert_eq!(expiry("cpm"), Some(900));
+        assert_eq!(expiry("cph"), Some(600));
+        // last_error never expires, and the global setting doesn't change that
+        assert_eq!(expiry("last_error"), Some(0));
+    }
+
+    #[test]
+    fn dead_time_correction() {
+        assert_eq!(dead_time_corrected(1200, 0.0), Some(1200.0));
+        // 100 cps through a 100us dead time is 1% of each second lost: 100 / 0.99
+        let corrected = dead_time_corrected(6000, 100.0).unwrap();
+        assert!((corrected - 6060.606).abs() < 0.01, "{corrected}");
+        // at 10000 cps the tube would be dead the whole second
+        assert_eq!(dead_time_corrected(600_000, 100.0), None);
+        assert_eq!(dead_time_corrected(900_000, 100.0), None);
+    }
+
+    #[tokio::test]
+    async fn one_failing_sensor_goes_offline_alone() {
+        let config = AppConfig {
+            sensor_availability: Some(true),
+            ..Default::default()
+        };
+        let features = HashSet::from([DeviceFeature::Voltage]);
+        let mut state = PollState::default();
+        let mut gmc = FakeGmc {
+            voltage: Some(4.1),
+            ..Default::default()
+        };
+        let payloads = generate_payloads(&mut gmc, &config, &features, &mut state).await;
+        let voltage = payloads.iter().find(|p| p.sensor == "voltage").expect("no voltage sensor");
+        assert_eq!(voltage.config.availability_topic.as_deref(), Some("gqgmcmqtt/F488E1234/geiger_counter_voltage/availability"));
+
+        // every sensor last read long ago, then a poll where only the voltage fails
+        for (last_ok, expires_after) in state.sensor_last_ok.values_mut() {
+            *last_ok = Instant::now() - Duration::from_secs(*expires_after + 1);
+        }
+        gmc.voltage = None;
+        generate_payloads(&mut gmc, &config, &features, &mut state).await;
+        let online: HashMap<_, _> = sensor_availability(&config, &state)
+            .into_iter()
+            .map(|m| (m.topic, matches!(m.payload, Payload::Availability(true))))
+            .collect();
+        assert_eq!(online.get("gqgmcmqtt/F488E1234/geiger_counter_voltage/availability"), Some(&false));
+        assert_eq!(online.get("gqgmcmqtt/F488E1234/geiger_counter_cpm/availability"), Some(&true));
+        assert_eq!(online.get("gqgmcmqtt/F488E1234/geiger_counter_usv/availability"), Some(&true));
+    }
+
+    fn alerts(readings: &[u32], on_ratio: f32, off_ratio: f32) -> Vec<bool> {
+        let mut alerting = false;
+        readings
+            .iter()
+            .map(|cpm| {
+                alerting = rapid_increase(alerting, *cpm, 20.0, on_ratio, off_ratio);
+                alerting
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alert_hysteresis_stops_flapping() {
+        let readings = [41, 39, 35, 31, 29, 41, 39];
+        assert_eq!(alerts(&readings, 2.0, 2.0), vec![true, false, false, false, false, true, false]);
+        assert_eq!(alerts(&readings, 2.0, 1.5), vec![true, true, true, true, false, true, true]);
+    }
+
+    #[test]
+    fn cpm_uncertainty_is_poisson() {
+        assert_eq!(cpm_uncertainty(0), 0.0);
+        assert_eq!(cpm_uncertainty(100), 10.0);
+        assert!((cpm_uncertainty(20) - 4.472).abs() < 0.001);
+    }
+
+    #[tokio::test]
+    async fn cpm_uncertainty_sensor_and_attribute() {
+        let sensor = SensorConfig {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let config = AppConfig {
+            cpm_uncertainty_attribute: Some(true),
+            sensors: Some(HashMap::from([("cpm_uncertainty".to_string(), sensor)])),
+            ..Default::default()
+        };
+        let mut gmc = FakeGmc {
+            cpm: Some(100),
+            ..Default::default()
+        };
+        let payloads = poll(&mut gmc, &config, &mut PollState::default()).await;
+        let uncertainty = payloads.iter().find(|p| p.sensor == "cpm_uncertainty").expect("no cpm_uncertainty sensor");
+        assert!(matches!(uncertainty.state.value, PayloadValueType::Float(v) if v == 10.0));
+        assert!(matches!(uncertainty.config.entity_category, Some(EntityCategory::Diagnostic)));
+        let cpm = payloads.iter().find(|p| p.sensor == "cpm").expect("no cpm sensor");
+        let attributes = cpm.state.attributes.as_ref().expect("no cpm attributes");
+        assert_eq!(attributes.get("uncertainty"), Some(&serde_json::Value::from(10.0_f32)));
+    }
+
+    #[tokio::test]
+    async fn prefixed_ids_are_unique() {
+        let enabled = SensorConfig {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let sensors = ["cph", "cpm_uncertainty", "cpm_stddev", "cpm_peak"].map(|name| (name.to_string(), enabled.clone()));
+        let config = AppConfig {
+            unique_id_prefix: Some("lab".to_string()),
+            sensors: Some(HashMap::from(sensors)),
+            cpm_average_window: Some(5),
+            dead_time_us: Some(90.0),
+            alert_ratio: Some(2.0),
+            ..Default::default()
+        };
+        let features = HashSet::from([DeviceFeature::Voltage]);
+        let mut gmc = FakeGmc {
+            voltage: Some(4.1),
+            ..Default::default()
+        };
+        let payloads = generate_payloads(&mut gmc, &config, &features, &mut PollState::default()).await;
+        assert!(payloads.len() > 10, "only {} payloads", payloads.len());
+        for p in &payloads {
+            assert!(p.config.unique_id.starts_with("lab_F488E1234_"), "{}", p.config.unique_id);
+            assert_eq!(p.config.entity_id.split_once('.').map(|(_, id)| id), Some(p.config.unique_id.as_str()));
+        }
+        let uniques: HashSet<_> = payloads.iter().map(|p| &p.config.unique_id).collect();
+        let entities: HashSet<_> = payloads.iter().map(|p| &p.config.entity_id).collect();
+        assert_eq!(uniques.len(), payloads.len());
+        assert_eq!(entities.len(), payloads.len());
+        let cpm = payloads.iter().find(|p| p.sensor == "cpm").expect("no cpm sensor");
+        assert_eq!(cpm.config.unique_id, "lab_F488E1234_geiger_counter_cpm");
+        assert_eq!(cpm.config.entity_id, "sensor.lab_F488E1234_geiger_counter_cpm");
+    }
 }
@@ -0,0 +1,347 @@
+use crate::config::AppConfig;
+use crate::consts::{ALERT_BASELINE_SAMPLES, STATE_SAVE_INTERVAL_SECS};
+use crate::device::RawResponse;
+use crate::log_limit::LogLimiter;
+use crate::errors::GQGMCMQTTError;
+use crate::payload::{DeviceIdentity, GrafanaPayload};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use tokio::time::{Duration, Instant};
+
+const ALERT_MIN_BASELINE_SAMPLES: usize = 10;
+
+/// What `state_file` keeps across restarts: the reading histories behind the baseline,
+/// the median filter and the rolling average, and the last reading, tagged with the unit
+/// they came from, plus the serials already announced. Fields added since the first
+/// version default when missing.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    serial: String,
+    cpm_history: Vec<u32>,
+    raw_cpm: Vec<u32>,
+    #[serde(default)]
+    average_window: Vec<u32>,
+    #[serde(default)]
+    last_reading: Option<(u32, DateTime<Utc>)>,
+    #[serde(default)]
+    announced: Vec<String>,
+}
+
+/// Readings carried from one poll cycle to the next.
+#[derive(Debug, Default)]
+pub struct PollState {
+    pub cpm_history: VecDeque<u32>,
+    /// Unfiltered readings feeding the median filter, at most `median_filter_window` long.
+    pub raw_cpm: VecDeque<u32>,
+    /// Readings behind the rolling average, at most `cpm_average_window` long.
+    pub average_window: VecDeque<u32>,
+    /// The last `freeze_detect_samples` raw readings, for spotting a stuck unit.
+    pub freeze_window: VecDeque<u32>,
+    /// Every reading in a full `freeze_window` was the same.
+    pub frozen: bool,
+    /// Highest CPM since startup or the last press of the reset-peak button.
+    pub peak_cpm: Option<u32>,
+    /// The last good CPM and when it was read; survives restarts with `state_file`.
+    pub last_reading: Option<(u32, DateTime<Utc>)>,
+    /// When `state_file` was last written.
+    pub last_saved: Option<Instant>,
+    /// The unit as of the last successful identity read.
+    pub identity: Option<DeviceIdentity>,
+    /// When `identity` was last read from the unit, None to force a re-read.
+    pub identity_checked: Option<Instant>,
+    /// Discovery topics of a swapped-out unit, waiting to be emptied.
+    pub stale_config_topics: Vec<String>,
+    /// This cycle's reading in Grafana form, None if the cycle's reads failed.
+    pub grafana: Option<GrafanaPayload>,
+    /// Raw replies from this cycle, when `capture_raw` is on.
+    pub captured: Option<Vec<RawResponse>>,
+    /// The config block last sent to `device_config`, so it's only republished on change.
+    pub device_config: Option<Vec<u8>>,
+    /// Keeps a dead device from logging the same read error every poll.
+    pub error_log: LogLimiter,
+    pub last_success: Option<Instant>,
+    pub last_read_ok: bool,
+    /// Successful reads in a row since the last failure.
+    pub valid_streak: u32,
+    /// Failed reads in a row, for reopening the unit after `serial_reconnect_after`.
+    pub failed_streak: u32,
+    /// Unknown states went out for the current outage.
+    pub marked_unavailable: bool,
+    /// The rapid-increase alert is on.
+    pub alerting: bool,
+    /// The threshold alarm is on.
+    pub alarm_on: bool,
+    /// Readings in a row disagreeing with `alarm_on`, for the debounce.
+    pub alarm_pending: u32,
+    /// A spike alert went out and CPS hasn't dropped back under the threshold yet.
+    pub spiking: bool,
+    /// Discovery topics announced so far, for removing the unit's entities again.
+    pub config_topics: HashSet<String>,
+    /// Set once the entities were removed for absence, until the unit answers again.
+    pub removed: bool,
+    /// When the first reading came in, for the settle period.
+    pub first_reading: Option<Instant>,
+    pub settled: bool,
+    /// Serials already sent to `gqgmcmqtt/discovered`; kept in `state_file`.
+    pub announced: HashSet<String>,
+    /// Per state topic, when it last got a reading and its `expires_after`.
+    pub sensor_last_ok: HashMap<String, (Instant, u64)>,
+}
+
+impl PollState {
+    /// True while still inside `settle_time_secs` of the first reading; tubes can read
+    /// erratically for a while after power-up.
+    pub fn settling(&mut self, config: &AppConfig) -> bool {
+        if self.settled {
+            return false;
+        }
+        let first = *self.first_reading.get_or_insert_with(Instant::now);
+        let settle_time = Duration::from_secs(config.settle_time_secs.unwrap_or(0));
+        if first.elapsed() < settle_time {
+            return true;
+        }
+        info!("Settle time of {}s is over, readings are now considered stable.", settle_time.as_secs());
+        self.settled = true;
+        false
+    }
+
+    /// Median of the last `window` raw readings including this one. Until the window has
+    /// filled it's the median of what's there, so the first reading passes straight through.
+    pub fn median_cpm(&mut self, cpm: u32, window: usize) -> u32 {
+        self.raw_cpm.push_back(cpm);
+        while self.raw_cpm.len() > window {
+            self.raw_cpm.pop_front();
+        }
+        let mut sorted = self.raw_cpm.iter().copied().collect::<Vec<u32>>();
+        sorted.sort_unstable();
+        // lower middle for even counts, so the result is always a value actually read
+        sorted[(sorted.len() - 1) / 2]
+    }
+
+    /// Records a raw reading and whether the last `window` of them, once there are that
+    /// many, were all the same. Logs when that starts and stops being true.
+    pub fn check_frozen(&mut self, cpm: u32, window: usize) -> bool {
+        self.freeze_window.push_back(cpm);
+        while self.freeze_window.len() > window {
+            self.freeze_window.pop_front();
+        }
+        let frozen = self.freeze_window.len() >= window && self.freeze_window.iter().all(|c| *c == cpm);
+        if frozen && !self.frozen {
+            warn!("The unit has read {cpm} CPM for {window} polls in a row; it may have frozen.");
+        } else if !frozen && self.frozen {
+            info!("The unit's readings are changing again at {cpm} CPM.");
+        }
+        self.frozen = frozen;
+        frozen
+    }
+
+    /// Mean of the last `window` readings including this one, or of all of them while
+    /// there are fewer.
+    pub fn average_cpm(&mut self, cpm: u32, window: usize) -> f32 {
+        self.average_window.push_back(cpm);
+        while self.average_window.len() > window {
+            self.average_window.pop_front();
+        }
+        let total: u64 = self.average_window.iter().map(|c| *c as u64).sum();
+        total as f32 / self.average_window.len() as f32
+    }
+
+    pub fn record_cpm(&mut self, cpm: u32) {
+        self.cpm_history.push_back(cpm);
+        while self.cpm_history.len() > ALERT_BASELINE_SAMPLES {
+            self.cpm_history.pop_front();
+        }
+    }
+
+    /// Mean of the recorded readings, or None until there are enough of them to be a
+    /// meaningful baseline.
+    pub fn baseline_cpm(&self) -> Option<f32> {
+        if self.cpm_history.len() < ALERT_MIN_BASELINE_SAMPLES {
+            return None;
+        }
+        let total: u64 = self.cpm_history.iter().map(|c| *c as u64).sum();
+        Some(total as f32 / self.cpm_history.len() as f32)
+    }
+
+    /// Population standard deviation of the recorded readings, None with fewer than two.
+    /// For Poisson counts this sits near sqrt(mean); much wider points at a real change.
+    pub fn cpm_stddev(&self) -> Option<f32> {
+        let n = self.cpm_history.len();
+        if n < 2 {
+            return None;
+        }
+        let mean = self.cpm_history.iter().map(|c| *c as f64).sum::<f64>() / n as f64;
+        let variance = self
+            .cpm_history
+            .iter()
+            .map(|c| (*c as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n as f64;
+        Some(variance.sqrt() as f32)
+    }
+
+    /// Saves to `path` if STATE_SAVE_INTERVAL_SECS have passed since the last save, so a
+    /// crash loses at most that much history.
+    pub fn save_due(&mut self, path: &str) {
+        if self.last_saved.is_some_and(|t| t.elapsed() < Duration::from_secs(STATE_SAVE_INTERVAL_SECS)) {
+            return;
+        }
+        self.last_saved = Some(Instant::now());
+        if let Err(e) = self.save(path) {
+            error!("Couldn't save reading history: {e}");
+        }
+    }
+
+    /// Writes the histories to `path`, via a temporary file so a crash mid-write can't
+    /// leave a truncated one behind.
+    pub fn save(&self, path: &str) -> Result<(), GQGMCMQTTError> {
+        let saved = SavedState {
+            serial: self.identity.as_ref().map(|i| i.serial.clone()).unwrap_or_default(),
+            cpm_history: self.cpm_history.iter().copied().collect(),
+            raw_cpm: self.raw_cpm.iter().copied().collect(),
+            average_window: self.average_window.iter().copied().collect(),
+            last_reading: self.last_reading,
+            announced: self.announced.iter().cloned().collect(),
+        };
+        let json = serde_json::to_vec(&saved).map_err(|e| GQGMCMQTTError::Default(e.to_string()))?;
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, json).map_err(|e| GQGMCMQTTError::Default(format!("Can't write {tmp}: {e}")))?;
+        fs::rename(&tmp, path).map_err(|e| GQGMCMQTTError::Default(format!("Can't replace {path}: {e}")))
+    }
+
+    /// Loads histories saved for `serial`, keeping the newest readings when the window
+    /// has shrunk since; a grown window just fills up as usual.
+    pub fn restore(&mut self, path: &str, serial: &str, config: &AppConfig) {
+        let saved = match fs::read(path).map(|b| serde_json::from_slice::<SavedState>(&b)) {
+            Ok(Ok(saved)) => saved,
+            Ok(Err(e)) => {
+                warn!("Ignoring unreadable state file {path}, starting fresh: {e}");
+                return;
+            }
+            Err(e) => {
+                warn!("No saved state restored from {path}, starting fresh: {e}");
+                return;
+            }
+        };
+        // announcements cover every unit seen, so they carry over even if this one is new
+        self.announced.extend(saved.announced);
+        if saved.serial != serial {
+            info!("State file {path} is for unit {}, not restoring it.", saved.serial);
+            return;
+        }
+        let keep_newest = |mut v: Vec<u32>, n: usize| v.split_off(v.len().saturating_sub(n)).into_iter().collect();
+        self.cpm_history = keep_newest(saved.cpm_history, ALERT_BASELINE_SAMPLES);
+        self.raw_cpm = keep_newest(saved.raw_cpm, config.median_filter_window.unwrap_or(0));
+        self.average_window = keep_newest(saved.average_window, config.cpm_average_window.unwrap_or(0));
+        self.last_reading = saved.last_reading;
+        info!("Restored {} readings from {path}.", self.cpm_history.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stddev_of_a_known_sequence() {
+        let mut state = PollState::default();
+        assert_eq!(state.cpm_stddev(), None);
+        state.record_cpm(2);
+        assert_eq!(state.cpm_stddev(), None);
+        for cpm in [4, 4, 4, 5, 5, 7, 9] {
+            state.record_cpm(cpm);
+        }
+        assert!((state.cpm_stddev().unwrap() - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn steady_readings_have_no_spread() {
+        let mut state = PollState::default();
+        for _ in 0..5 {
+            state.record_cpm(20);
+        }
+        assert_eq!(state.cpm_stddev(), Some(0.0));
+    }
+
+    #[test]
+    fn median_ignores_an_outlier() {
+        let mut state = PollState::default();
+        let filtered: Vec<u32> = [20, 22, 65535, 21, 19].iter().map(|cpm| state.median_cpm(*cpm, 3)).collect();
+        // the first reading passes straight through, and the spike never comes out
+        assert_eq!(filtered, vec![20, 20, 22, 22, 21]);
+        assert_eq!(state.raw_cpm.len(), 3);
+    }
+
+    fn state_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("gqgmcmqtt-{name}-{}.json", std::process::id())).to_string_lossy().to_string()
+    }
+
+    fn saved_unit() -> PollState {
+        let mut state = PollState {
+            identity: Some(DeviceIdentity {
+                serial: "F488E1234".to_string(),
+                model: "GMC-500+Re 2.42".to_string(),
+            }),
+            last_reading: Some((24, Utc::now())),
+            ..Default::default()
+        };
+        for cpm in 10..20 {
+            state.record_cpm(cpm);
+            state.median_cpm(cpm, 5);
+            state.average_cpm(cpm, 8);
+        }
+        state.announced.insert("F488E1234".to_string());
+        state
+    }
+
+    #[test]
+    fn save_restore_round_trip() {
+        let path = state_path("round-trip");
+        let saved = saved_unit();
+        saved.save(&path).unwrap();
+        let config = AppConfig {
+            median_filter_window: Some(5),
+            cpm_average_window: Some(8),
+            ..Default::default()
+        };
+        let mut restored = PollState::default();
+        restored.restore(&path, "F488E1234", &config);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.cpm_history, saved.cpm_history);
+        assert_eq!(restored.raw_cpm, saved.raw_cpm);
+        assert_eq!(restored.average_window, saved.average_window);
+        assert_eq!(restored.last_reading, saved.last_reading);
+        assert_eq!(restored.announced, saved.announced);
+    }
+
+    #[test]
+    fn restore_into_smaller_windows_keeps_the_newest() {
+        let path = state_path("shrunk");
+        saved_unit().save(&path).unwrap();
+        let config = AppConfig {
+            median_filter_window: Some(3),
+            cpm_average_window: Some(2),
+            ..Default::default()
+        };
+        let mut restored = PollState::default();
+        restored.restore(&path, "F488E1234", &config);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.raw_cpm, [17, 18, 19]);
+        assert_eq!(restored.average_window, [18, 19]);
+    }
+
+    #[test]
+    fn another_units_history_is_not_restored() {
+        let path = state_path("other-unit");
+        saved_unit().save(&path).unwrap();
+        let mut restored = PollState::default();
+        restored.restore(&path, "0000000", &AppConfig::default());
+        fs::remove_file(&path).unwrap();
+        assert!(restored.cpm_history.is_empty());
+        assert!(restored.last_reading.is_none());
+        // announcements cover every unit seen, so they still come back
+        assert!(restored.announced.contains("F488E1234"));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::device::{model_has_wide_cpm, split_version};
+use crate::errors::GQGMCMQTTError;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+
+/// Acknowledges writes, and ends some fixed-length replies.
+pub const ACK: u8 = 0xAA;
+
+/// Bytes in a GETCPM or GETCPS reply: 2 on older models, 4 on the 500/600 series.
+pub fn count_width(version: &str) -> usize {
+    if model_has_wide_cpm(version) {
+        4
+    } else {
+        2
+    }
+}
+
+/// GETCPM and GETCPS counts are big-endian binary, whatever their width.
+pub fn decode_count(reply: &[u8]) -> u32 {
+    reply.iter().fold(0_u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// Bytes in a GETVOLT reply: one, tenths of a volt, except from the 2.x firmware of the
+/// 500/600 series, which answers with five ASCII bytes such as `4.9v`. Goes by the
+/// firmware revision rather than the model alone, as the early 1.x 500 firmware still
+/// sends the single byte.
+pub fn voltage_width(version: &str) -> usize {
+    let (model, firmware) = split_version(version);
+    let major = firmware.and_then(|f| f.split('.').next()).and_then(|m| m.parse::<u32>().ok());
+    if model_has_wide_cpm(model) && major.is_some_and(|m| m >= 2) {
+        5
+    } else {
+        1
+    }
+}
+
+pub fn decode_voltage(reply: &[u8]) -> Result<f32, GQGMCMQTTError> {
+    if let [tenths] = reply {
+        return Ok(*tenths as f32 / 10.0);
+    }
+    let text = String::from_utf8_lossy(reply);
+    let volts = text.trim_matches(|c: char| !c.is_ascii_digit() && c != '.');
+    volts
+        .parse::<f32>()
+        .map_err(|_| GQGMCMQTTError::Device(format!("Unparseable GETVOLT reply {text:?}")))
+}
+
+/// The one-byte reply to a write, which must be an ack.
+pub fn expect_ack(cmd: &[u8], reply: &[u8]) -> Result<(), GQGMCMQTTError> {
+    match reply {
+        [ACK] => Ok(()),
+        _ => Err(GQGMCMQTTError::Device(format!(
+            "{} answered {:02x?} instead of an ack",
+            String::from_utf8_lossy(cmd),
+            reply
+        ))),
+    }
+}
+
+/// GETDATETIME's reply: year since 2000, month, day, hour, minute and second, one binary
+/// byte each, then a trailing 0xAA.
+pub fn decode_datetime(reply: &[u8]) -> Result<NaiveDateTime, GQGMCMQTTError> {
+    let [year, month, day, hour, minute, second, end] = reply else {
+        return Err(GQGMCMQTTError::Device(format!("GETDATETIME reply is {} bytes, not 7", reply.len())));
+    };
+    if *end != ACK {
+        return Err(GQGMCMQTTError::Device(format!("GETDATETIME reply ended with {end:#04x} instead of {ACK:#04x}")));
+    }
+    NaiveDate::from_ymd_opt(2000 + *year as i32, *month as u32, *day as u32)
+        .and_then(|d| d.and_hms_opt(*hour as u32, *minute as u32, *second as u32))
+        .ok_or_else(|| GQGMCMQTTError::Device(format!("Unexpected GETDATETIME reply {:02x?}", &reply[..6])))
+}
+
+/// SETDATETIME with the same six bytes GETDATETIME answers with, so only years
+/// 2000-2255 fit.
+pub fn set_datetime_command(at: NaiveDateTime) -> Result<Vec<u8>, GQGMCMQTTError> {
+    let year = u8::try_from(at.year() - 2000)
+        .map_err(|_| GQGMCMQTTError::Device(format!("The unit's clock can't hold the year {}", at.year())))?;
+    let mut cmd = b"<SETDATETIME".to_vec();
+    cmd.extend_from_slice(&[year, at.month() as u8, at.day() as u8, at.hour() as u8, at.minute() as u8, at.second() as u8]);
+    cmd.extend_from_slice(b">>");
+    Ok(cmd)
+}
+
+/// SPIR takes a 24-bit address and a 16-bit length, both big-endian binary, and answers
+/// with that many bytes of the history log.
+pub fn history_command(addr: u32, len: u16) -> Vec<u8> {
+    let mut cmd = b"<SPIR".to_vec();
+    cmd.extend_from_slice(&addr.to_be_bytes()[1..]);
+    cmd.extend_from_slice(&len.to_be_bytes());
+    cmd.extend_from_slice(b">>");
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_widths_by_model() {
+        assert_eq!(count_width("GMC-320Re 4.26"), 2);
+        assert_eq!(count_width("GMC-500+Re 2.42"), 4);
+        assert_eq!(decode_count(&[0x00, 0x1c]), 28);
+        assert_eq!(decode_count(&[0x00, 0x01, 0x00, 0x1c]), 0x1_001c);
+    }
+
+    #[test]
+    fn voltage_reply_follows_the_firmware() {
+        assert_eq!(voltage_width("GMC-500+Re 2.42"), 5);
+        assert_eq!(voltage_width("GMC-600+Re 2.52"), 5);
+        assert_eq!(voltage_width("GMC-500Re 1.08"), 1);
+        assert_eq!(voltage_width("GMC-320Re 4.26"), 1);
+        // no revision to go by, so the byte every model understands
+        assert_eq!(voltage_width("GMC-500+"), 1);
+        assert_eq!(decode_voltage(&[49]).unwrap(), 4.9);
+        assert_eq!(decode_voltage(b"4.9v\0").unwrap(), 4.9);
+        assert!(decode_voltage(b"v").is_err());
+    }
+
+    #[test]
+    fn history_reads_address_in_three_bytes() {
+        assert_eq!(history_command(0x01_2345, 4096), b"<SPIR\x01\x23\x45\x10\x00>>");
+    }
+
+    #[test]
+    fn datetime_round_trips_through_the_six_bytes() {
+        let at = NaiveDate::from_ymd_opt(2026, 10, 14).unwrap().and_hms_opt(3, 51, 7).unwrap();
+        let cmd = set_datetime_command(at).unwrap();
+        assert_eq!(cmd, b"<SETDATETIME\x1a\x0a\x0e\x03\x33\x07>>");
+        let mut reply = cmd[12..18].to_vec();
+        reply.push(ACK);
+        assert_eq!(decode_datetime(&reply).unwrap(), at);
+
+        reply[6] = 0x00;
+        assert!(decode_datetime(&reply).is_err());
+        assert!(decode_datetime(&[0x1a, 0x0d, 0x01, 0x00, 0x00, 0x00, ACK]).is_err(), "month 13");
+        let too_late = NaiveDate::from_ymd_opt(2300, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert!(set_datetime_command(too_late).is_err());
+    }
+}
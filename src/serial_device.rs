@@ -0,0 +1,96 @@
+use crate::device::GeigerDevice;
+use crate::errors::GQGMCMQTTError;
+use crate::protocol::{
+    count_width, decode_count, decode_datetime, decode_voltage, expect_ack, history_command, set_datetime_command,
+    voltage_width,
+};
+use chrono::NaiveDateTime;
+use gqgmclib::GMC;
+
+/// A unit on a serial port. gqgmclib runs the commands it wraps itself; the rest go
+/// through its `GMC::command` passthrough, which writes a command and reads a reply of
+/// the given length, and are decoded the same way `StreamDevice` decodes them.
+pub struct SerialDevice {
+    gmc: GMC,
+    /// GETCPM's and GETVOLT's reply lengths, learned from the version string like
+    /// `StreamDevice`'s.
+    cpm_width: usize,
+    voltage_width: usize,
+}
+
+impl SerialDevice {
+    pub fn new(gmc: GMC) -> Self {
+        SerialDevice { gmc, cpm_width: 2, voltage_width: 1 }
+    }
+
+    async fn command(&mut self, cmd: &[u8], response_len: usize) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.gmc.command(cmd, response_len).await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+}
+
+impl GeigerDevice for SerialDevice {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        let version = self.gmc.get_version().await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))?;
+        self.cpm_width = count_width(&version);
+        self.voltage_width = voltage_width(&version);
+        Ok(version)
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        self.gmc.get_serial_number().await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        match self.gmc.get_cpm().await {
+            Ok(cpm) => Ok(cpm as u32),
+            Err(e) => Err(GQGMCMQTTError::Device(e.to_string())),
+        }
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.gmc.get_config().await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        self.gmc.write_config(offset, value).await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.gmc.update_config().await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        self.gmc.get_temperature().await.map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+
+    /// Same width as GETCPM on every model that answers it.
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETCPS>>", self.cpm_width).await?;
+        Ok(decode_count(&reply))
+    }
+
+    /// One byte or five ASCII ones, depending on the firmware; see `voltage_width`.
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETVOLT>>", self.voltage_width).await?;
+        decode_voltage(&reply)
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        let reply = self.command(b"<GETDATETIME>>", 7).await?;
+        decode_datetime(&reply)
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        let cmd = set_datetime_command(at)?;
+        let reply = self.command(&cmd, 1).await?;
+        expect_ack(&cmd, &reply)
+    }
+
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.command(&history_command(addr, len), len as usize).await
+    }
+
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.gmc.clear_input().map_err(|e| GQGMCMQTTError::Device(e.to_string()))
+    }
+}
@@ -0,0 +1,52 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Adds a `sig` field to a JSON state payload: hex HMAC-SHA256, keyed by `hmac_secret`,
+/// over the payload's compact JSON with keys sorted and `sig` itself left out. To verify,
+/// drop `sig`, re-serialize the same way and compare.
+///
+/// This only proves the sender knew the secret. Everyone who can verify can also sign, so
+/// hand the secret out only to consumers you'd trust to publish.
+pub fn sign_state(body: &[u8], secret: &str) -> serde_json::Result<Vec<u8>> {
+    // serde_json's Value map is a BTreeMap, which gives the sorted key order for free
+    let mut value: serde_json::Value = serde_json::from_slice(body)?;
+    let canonical = serde_json::to_vec(&value)?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(&canonical);
+    let sig = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert("sig".to_string(), serde_json::Value::String(sig));
+    }
+    serde_json::to_vec(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_verifies_after_stripping_sig() {
+        let body = br#"{"value":20,"attributes":{"usv":0.13,"cps":1}}"#;
+        let signed: serde_json::Value = serde_json::from_slice(&sign_state(body, "s3cret").unwrap()).unwrap();
+        let mut unsigned = signed.clone();
+        let sig = unsigned.as_object_mut().unwrap().remove("sig").unwrap();
+
+        // what a consumer does: re-serialize without sig, keys sorted, and recompute
+        let canonical = serde_json::to_string(&unsigned).unwrap();
+        assert_eq!(canonical, r#"{"attributes":{"cps":1,"usv":0.13},"value":20}"#);
+        let mut mac = HmacSha256::new_from_slice(b"s3cret").unwrap();
+        mac.update(canonical.as_bytes());
+        let expected: String = mac.finalize().into_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(sig, serde_json::Value::String(expected));
+
+        let other: serde_json::Value = serde_json::from_slice(&sign_state(body, "other").unwrap()).unwrap();
+        assert_ne!(other["sig"], signed["sig"], "the key goes into the signature");
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::ResponseFraming;
+use crate::consts::{STREAM_FLUSH_MILLIS, STREAM_READ_TIMEOUT_MILLIS};
+use crate::device::{GeigerDevice, RawResponse};
+use crate::errors::GQGMCMQTTError;
+use crate::protocol::{
+    count_width, decode_count, decode_datetime, decode_voltage, expect_ack, history_command, set_datetime_command,
+    voltage_width, ACK,
+};
+use chrono::NaiveDateTime;
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+const VERSION_LEN: usize = 14;
+const SERIAL_LEN: usize = 7;
+const CONFIG_LEN: usize = 256;
+
+/// Speaks the GQ RFC1201 protocol directly over any byte stream, for transports
+/// gqgmclib can't open itself (Unix sockets and TCP).
+pub struct StreamDevice<S> {
+    stream: S,
+    /// GETCPM answers with 2 bytes on older models and 4 on the 500/600 series; learned
+    /// from the version string.
+    cpm_width: usize,
+    /// GETVOLT's reply length, which goes by the firmware revision in the version string.
+    voltage_width: usize,
+    /// Set once the peer has hung up, so later commands fail straight away instead of
+    /// each waiting out the read timeout.
+    closed: bool,
+    /// Raw replies, recording only while Some.
+    captured: Option<Vec<RawResponse>>,
+    /// Auto until the first reply shows whether a trailing marker follows.
+    framing: ResponseFraming,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> StreamDevice<S> {
+    pub fn new(stream: S, framing: ResponseFraming) -> Self {
+        StreamDevice { stream, cpm_width: 2, voltage_width: 1, closed: false, captured: None, framing }
+    }
+
+    async fn command(&mut self, cmd: &[u8], response_len: usize) -> Result<Vec<u8>, GQGMCMQTTError> {
+        if self.closed {
+            return Err(GQGMCMQTTError::Device("Connection to unit is closed".to_string()));
+        }
+        let stream = &mut self.stream;
+        let marked = self.framing == ResponseFraming::TrailingMarker;
+        let exchange = async move {
+            stream.write_all(cmd).await?;
+            stream.flush().await?;
+            let mut buf = vec![0_u8; response_len + marked as usize];
+            stream.read_exact(&mut buf).await?;
+            Ok::<Vec<u8>, std::io::Error>(buf)
+        };
+        match timeout(Duration::from_millis(STREAM_READ_TIMEOUT_MILLIS), exchange).await {
+            Ok(Ok(mut buf)) => {
+                if marked {
+                    let marker = buf.pop();
+                    if marker != Some(ACK) {
+                        return Err(GQGMCMQTTError::Device(format!(
+                            "Unexpected trailing byte {:#04x} after reply to {}",
+                            marker.unwrap_or_default(),
+                            String::from_utf8_lossy(cmd)
+                        )));
+                    }
+                } else if self.framing == ResponseFraming::Auto {
+                    self.detect_framing().await;
+                }
+                if let Some(captured) = &mut self.captured {
+                    captured.push(RawResponse {
+                        command: String::from_utf8_lossy(cmd).to_string(),
+                        hex: buf.iter().map(|b| format!("{b:02x}")).collect(),
+                    });
+                }
+                Ok(buf)
+            }
+            Ok(Err(e)) if is_disconnect(&e) => {
+                self.closed = true;
+                Err(GQGMCMQTTError::Device(format!("Connection to unit closed: {e}")))
+            }
+            Ok(Err(e)) => Err(GQGMCMQTTError::Device(format!("I/O error: {e}"))),
+            Err(_) => Err(GQGMCMQTTError::Device(format!(
+                "Timed out waiting for reply to {}",
+                String::from_utf8_lossy(cmd)
+            ))),
+        }
+    }
+
+    /// A marker arrives right behind the reply, so one 0xAA within the flush window means
+    /// the firmware frames replies with it. Decided once, on the first reply.
+    async fn detect_framing(&mut self) {
+        let mut byte = [0_u8; 1];
+        self.framing = match timeout(Duration::from_millis(STREAM_FLUSH_MILLIS), self.stream.read(&mut byte)).await {
+            Ok(Ok(1)) if byte[0] == ACK => {
+                info!("Unit appends {ACK:#04x} to its replies, stripping it from now on.");
+                ResponseFraming::TrailingMarker
+            }
+            Ok(Ok(1)) => {
+                debug!("Discarded stray byte {:#04x} after the first reply", byte[0]);
+                ResponseFraming::Standard
+            }
+            _ => ResponseFraming::Standard,
+        };
+    }
+
+    async fn acked(&mut self, cmd: &[u8]) -> Result<(), GQGMCMQTTError> {
+        let reply = self.command(cmd, 1).await?;
+        expect_ack(cmd, &reply)
+    }
+}
+
+/// EOF mid-reply or a reset both mean the other end is gone (a WiFi unit rebooting or
+/// dropping off the network), not a garbled reply worth retrying on the same stream.
+fn is_disconnect(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> GeigerDevice for StreamDevice<S> {
+    async fn get_version(&mut self) -> Result<String, GQGMCMQTTError> {
+        let reply = self.command(b"<GETVER>>", VERSION_LEN).await?;
+        let version = String::from_utf8_lossy(&reply).trim_end_matches('\0').to_string();
+        self.cpm_width = count_width(&version);
+        self.voltage_width = voltage_width(&version);
+        Ok(version)
+    }
+
+    async fn get_serial_number(&mut self) -> Result<String, GQGMCMQTTError> {
+        let reply = self.command(b"<GETSERIAL>>", SERIAL_LEN).await?;
+        Ok(reply.iter().map(|b| format!("{b:02X}")).collect())
+    }
+
+    async fn get_cpm(&mut self) -> Result<u32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETCPM>>", self.cpm_width).await?;
+        Ok(decode_count(&reply))
+    }
+
+    /// One byte or five ASCII ones, depending on the firmware; see `voltage_width`.
+    async fn get_voltage(&mut self) -> Result<f32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETVOLT>>", self.voltage_width).await?;
+        decode_voltage(&reply)
+    }
+
+    /// Same width as GETCPM on every model that answers it.
+    async fn get_cps(&mut self) -> Result<u32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETCPS>>", self.cpm_width).await?;
+        Ok(decode_count(&reply))
+    }
+
+    async fn get_config(&mut self) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.command(b"<GETCFG>>", CONFIG_LEN).await
+    }
+
+    /// The config sector can only be written after an erase, so this rewrites the whole
+    /// block with the one byte changed.
+    async fn write_config(&mut self, offset: usize, value: u8) -> Result<(), GQGMCMQTTError> {
+        let mut cfg = self.get_config().await?;
+        match cfg.get_mut(offset) {
+            Some(b) => *b = value,
+            None => return Err(GQGMCMQTTError::Device(format!("No config offset {offset}"))),
+        }
+        self.acked(b"<ECFG>>").await?;
+        for (addr, data) in cfg.iter().enumerate() {
+            let mut cmd = b"<WCFG".to_vec();
+            cmd.push(addr as u8);
+            cmd.push(*data);
+            cmd.extend_from_slice(b">>");
+            self.acked(&cmd).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_config(&mut self) -> Result<(), GQGMCMQTTError> {
+        self.acked(b"<CFGUPDATE>>").await
+    }
+
+    async fn flush_input(&mut self) -> Result<(), GQGMCMQTTError> {
+        let mut buf = [0_u8; 64];
+        // anything already buffered comes back at once; stop at the first read that would wait
+        while let Ok(result) = timeout(Duration::from_millis(STREAM_FLUSH_MILLIS), self.stream.read(&mut buf)).await {
+            match result {
+                Ok(0) => {
+                    self.closed = true;
+                    return Err(GQGMCMQTTError::Device("Connection to unit closed".to_string()));
+                }
+                Ok(n) => debug!("Discarded {n} stale bytes from the unit"),
+                Err(e) => return Err(GQGMCMQTTError::Device(format!("I/O error: {e}"))),
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_history(&mut self, addr: u32, len: u16) -> Result<Vec<u8>, GQGMCMQTTError> {
+        self.command(&history_command(addr, len), len as usize).await
+    }
+
+    /// The reply length isn't known, so this waits out the usual timeout for the first
+    /// byte and then reads until the line goes quiet.
+    async fn raw_command(&mut self, cmd: &[u8]) -> Result<Vec<u8>, GQGMCMQTTError> {
+        if self.closed {
+            return Err(GQGMCMQTTError::Device("Connection to unit is closed".to_string()));
+        }
+        let io_err = |e: std::io::Error| GQGMCMQTTError::Device(format!("I/O error: {e}"));
+        self.stream.write_all(cmd).await.map_err(io_err)?;
+        self.stream.flush().await.map_err(io_err)?;
+        let mut reply = vec![];
+        let mut buf = [0_u8; 256];
+        let mut wait = Duration::from_millis(STREAM_READ_TIMEOUT_MILLIS);
+        while let Ok(result) = timeout(wait, self.stream.read(&mut buf)).await {
+            match result {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => reply.extend_from_slice(&buf[..n]),
+                Err(e) => return Err(io_err(e)),
+            }
+            wait = Duration::from_millis(STREAM_FLUSH_MILLIS);
+        }
+        Ok(reply)
+    }
+
+    fn set_capture(&mut self, on: bool) {
+        match (on, self.captured.is_some()) {
+            (true, false) => self.captured = Some(vec![]),
+            (false, true) => self.captured = None,
+            _ => {}
+        }
+    }
+
+    fn take_captured(&mut self) -> Vec<RawResponse> {
+        self.captured.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    async fn get_datetime(&mut self) -> Result<NaiveDateTime, GQGMCMQTTError> {
+        let reply = self.command(b"<GETDATETIME>>", 7).await?;
+        decode_datetime(&reply)
+    }
+
+    async fn set_datetime(&mut self, at: NaiveDateTime) -> Result<(), GQGMCMQTTError> {
+        let cmd = set_datetime_command(at)?;
+        self.acked(&cmd).await
+    }
+
+    /// Integer part, tenths, a sign byte (non-zero for below zero) and a trailing 0xAA.
+    async fn get_temperature(&mut self) -> Result<f32, GQGMCMQTTError> {
+        let reply = self.command(b"<GETTEMP>>", 4).await?;
+        if reply[3] != ACK {
+            return Err(GQGMCMQTTError::Device(format!(
+                "GETTEMP reply ended with {:#04x} instead of {ACK:#04x}",
+                reply[3]
+            )));
+        }
+        let degrees = reply[0] as f32 + reply[1] as f32 / 10.0;
+        Ok(if reply[2] != 0 { -degrees } else { degrees })
+    }
+
+    /// The WiFi firmware of the 500+/600+ answers in ASCII, e.g. `-67`. The command isn't
+    /// in RFC1201, so anything but a believable dBm value counts as unsupported.
+    async fn get_wifi_rssi(&mut self) -> Result<i32, GQGMCMQTTError> {
+        let reply = self.raw_command(b"<GETWIFIRSSI>>").await?;
+        let text = String::from_utf8_lossy(&reply);
+        match text.trim_matches(|c: char| c.is_whitespace() || c == '\0').parse::<i32>() {
+            Ok(rssi) if (-120..=0).contains(&rssi) => Ok(rssi),
+            _ => Err(GQGMCMQTTError::Device(format!("Unexpected WiFi RSSI reply {text:?}"))),
+        }
+    }
+
+    /// Latitude and longitude in ASCII decimal degrees, e.g. `51.5072,-0.1276`. Like the
+    /// RSSI this isn't in RFC1201; a reply without a fix (`0,0`) or out of range is an error.
+    async fn get_gps(&mut self) -> Result<(f64, f64), GQGMCMQTTError> {
+        let reply = self.raw_command(b"<GETGPS>>").await?;
+        let text = String::from_utf8_lossy(&reply);
+        let fix = text
+            .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+            .split_once(',')
+            .and_then(|(lat, lon)| Some((lat.trim().parse::<f64>().ok()?, lon.trim().parse::<f64>().ok()?)));
+        match fix {
+            Some((lat, lon)) if (lat, lon) != (0.0, 0.0) && lat.abs() <= 90.0 && lon.abs() <= 180.0 => Ok((lat, lon)),
+            _ => Err(GQGMCMQTTError::Device(format!("No GPS fix in reply {text:?}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::DeviceErrorKind;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn closed_socket_mid_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let unit = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut cmd = [0_u8; 9];
+            socket.read_exact(&mut cmd).await.unwrap();
+            assert_eq!(&cmd, b"<GETCPM>>");
+            // half of the 2-byte reply, then the unit drops off the network
+            socket.write_all(&[0x00]).await.unwrap();
+        });
+        let mut gmc = StreamDevice::new(TcpStream::connect(addr).await.unwrap(), ResponseFraming::Standard);
+        let e = gmc.get_cpm().await.unwrap_err();
+        unit.await.unwrap();
+        assert!(e.to_string().contains("closed"), "{e}");
+        assert!(matches!(DeviceErrorKind::classify(&e), DeviceErrorKind::Communication));
+
+        // later commands give up straight away rather than waiting out the read timeout
+        let started = tokio::time::Instant::now();
+        assert!(gmc.get_version().await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(STREAM_READ_TIMEOUT_MILLIS));
+    }
+
+    /// A unit answering each expected command with a captured reply, in order.
+    fn replaying(exchanges: &[(&[u8], &[u8])], framing: ResponseFraming) -> StreamDevice<DuplexStream> {
+        let exchanges: Vec<(Vec<u8>, Vec<u8>)> = exchanges.iter().map(|(cmd, reply)| (cmd.to_vec(), reply.to_vec())).collect();
+        let (gateway, mut unit) = duplex(512);
+        tokio::spawn(async move {
+            for (cmd, reply) in exchanges {
+                let mut got = vec![0_u8; cmd.len()];
+                unit.read_exact(&mut got).await.unwrap();
+                assert_eq!(got, cmd);
+                unit.write_all(&reply).await.unwrap();
+            }
+            // hold the line open so a missing reply times out instead of reading as a hang-up
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        StreamDevice::new(gateway, framing)
+    }
+
+    #[tokio::test]
+    async fn standard_framing() {
+        let mut gmc = replaying(&[(b"<GETCPM>>", &[0x00, 0x1c]), (b"<GETCPM>>", &[0x01, 0x02])], ResponseFraming::Standard);
+        assert_eq!(gmc.get_cpm().await.unwrap(), 28);
+        assert_eq!(gmc.get_cpm().await.unwrap(), 0x0102);
+    }
+
+    #[tokio::test]
+    async fn trailing_marker_framing() {
+        let mut gmc = replaying(
+            &[(b"<GETCPM>>", &[0x00, 0x1c, 0xaa]), (b"<GETCPM>>", &[0x00, 0x1d, 0x00])],
+            ResponseFraming::TrailingMarker,
+        );
+        assert_eq!(gmc.get_cpm().await.unwrap(), 28);
+        let e = gmc.get_cpm().await.unwrap_err();
+        assert!(e.to_string().contains("Unexpected trailing byte 0x00"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn auto_framing_spots_a_trailing_marker() {
+        let mut gmc = replaying(&[(b"<GETCPM>>", &[0x00, 0x1c, 0xaa]), (b"<GETCPM>>", &[0x00, 0x1d, 0xaa])], ResponseFraming::Auto);
+        assert_eq!(gmc.get_cpm().await.unwrap(), 28);
+        assert_eq!(gmc.framing, ResponseFraming::TrailingMarker);
+        assert_eq!(gmc.get_cpm().await.unwrap(), 29);
+    }
+
+    #[tokio::test]
+    async fn auto_framing_settles_on_standard() {
+        let mut gmc = replaying(&[(b"<GETCPM>>", &[0x00, 0x1c]), (b"<GETCPM>>", &[0x00, 0xaa])], ResponseFraming::Auto);
+        assert_eq!(gmc.get_cpm().await.unwrap(), 28);
+        assert_eq!(gmc.framing, ResponseFraming::Standard);
+        // 0xaa is then just a low byte like any other
+        assert_eq!(gmc.get_cpm().await.unwrap(), 0xaa);
+    }
+
+    #[tokio::test]
+    async fn wifi_and_gps_replies() {
+        let mut gmc = replaying(
+            &[
+                (b"<GETWIFIRSSI>>", b"-67\0"),
+                (b"<GETGPS>>", b"51.5072,-0.1276"),
+                (b"<GETGPS>>", b"0,0"),
+                (b"<GETWIFIRSSI>>", b"<GETWIFIRSSI>>"),
+            ],
+            ResponseFraming::Standard,
+        );
+        assert_eq!(gmc.get_wifi_rssi().await.unwrap(), -67);
+        assert_eq!(gmc.get_gps().await.unwrap(), (51.5072, -0.1276));
+        assert!(gmc.get_gps().await.is_err());
+        // an echo of the command is what firmware without it answers
+        assert!(gmc.get_wifi_rssi().await.is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::AppConfig;
+use crate::consts::{DEFAULT_DISCOVERY_PREFIX, DEFAULT_STATE_PREFIX};
+
+/// Puts `environment` into a topic so gateways for dev, staging and prod can share one
+/// broker. Gateway topics (`gqgmcmqtt/...`, `gqgmc/...`) gain it as their second level;
+/// discovery topics, where HA allows no extra level, get it as a node id prefix, e.g.
+/// `homeassistant/sensor/prod_{serial}/{point}/config`. Unchanged without an environment.
+pub fn environment_topic(topic: &str, environment: Option<&str>) -> String {
+    let Some(env) = environment.filter(|e| !e.is_empty()) else {
+        return topic.to_string();
+    };
+    match topic.split_once('/') {
+        Some((DEFAULT_DISCOVERY_PREFIX, rest)) => match rest.split_once('/') {
+            Some((component, node)) => format!("{DEFAULT_DISCOVERY_PREFIX}/{component}/{env}_{node}"),
+            None => topic.to_string(),
+        },
+        Some((root, rest)) => format!("{root}/{env}/{rest}"),
+        None => topic.to_string(),
+    }
+}
+
+/// Where a broker connection's last will marks the gateway offline. Keyed on the MQTT
+/// client id rather than the unit's serial, since the will is registered on connect,
+/// before the unit has been read.
+pub fn gateway_availability_topic(client_id: &str) -> String {
+    format!("{DEFAULT_STATE_PREFIX}/{client_id}/availability")
+}
+
+/// A topic as it goes on the wire: the environment added, then the `homeassistant` and
+/// `gqgmcmqtt` roots it was built with swapped for `discovery_prefix` and `state_prefix`.
+/// The shared `gqgmc/fleet` topics keep their root so every gateway's land together.
+pub fn outbound_topic(topic: &str, config: &AppConfig) -> String {
+    let topic = environment_topic(topic, config.environment.as_deref());
+    match topic.split_once('/') {
+        Some((DEFAULT_DISCOVERY_PREFIX, rest)) => format!("{}/{rest}", config.discovery_prefix()),
+        Some((DEFAULT_STATE_PREFIX, rest)) => format!("{}/{rest}", config.state_prefix()),
+        _ => topic,
+    }
+}
+
+/// The reverse for an inbound gateway topic: None if it isn't under `state_prefix` or
+/// belongs to another environment.
+pub fn inbound_topic(topic: &str, config: &AppConfig) -> Option<String> {
+    let rest = topic.strip_prefix(config.state_prefix().as_str())?.strip_prefix('/')?;
+    strip_environment(&format!("{DEFAULT_STATE_PREFIX}/{rest}"), config.environment.as_deref())
+}
+
+/// The reverse for an inbound gateway topic: None if it belongs to another environment.
+fn strip_environment(topic: &str, environment: Option<&str>) -> Option<String> {
+    let Some(env) = environment.filter(|e| !e.is_empty()) else {
+        return Some(topic.to_string());
+    };
+    let (root, rest) = topic.split_once('/')?;
+    let rest = rest.strip_prefix(env)?.strip_prefix('/')?;
+    Some(format!("{root}/{rest}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_environment_leaves_topics_alone() {
+        for topic in ["gqgmcmqtt/F488E1234/geiger_counter_cpm", "homeassistant/sensor/F488E1234/geiger_counter_cpm/config"] {
+            assert_eq!(environment_topic(topic, None), topic);
+            assert_eq!(environment_topic(topic, Some("")), topic);
+        }
+    }
+
+    #[test]
+    fn environment_goes_into_both_roots() {
+        assert_eq!(
+            environment_topic("gqgmcmqtt/F488E1234/geiger_counter_cpm", Some("prod")),
+            "gqgmcmqtt/prod/F488E1234/geiger_counter_cpm"
+        );
+        assert_eq!(
+            environment_topic("homeassistant/sensor/F488E1234/geiger_counter_cpm/config", Some("prod")),
+            "homeassistant/sensor/prod_F488E1234/geiger_counter_cpm/config"
+        );
+        assert_eq!(environment_topic("gqgmc/fleet", Some("prod")), "gqgmc/prod/fleet");
+    }
+
+    #[test]
+    fn outbound_and_inbound_agree() {
+        let config = AppConfig {
+            environment: Some("staging".to_string()),
+            discovery_prefix: Some("ha".to_string()),
+            state_prefix: Some("geiger".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            outbound_topic("homeassistant/sensor/F488E1234/geiger_counter_cpm/config", &config),
+            "ha/sensor/staging_F488E1234/geiger_counter_cpm/config"
+        );
+        let command = outbound_topic("gqgmcmqtt/F488E1234/click_sound/set", &config);
+        assert_eq!(command, "geiger/staging/F488E1234/click_sound/set");
+        assert_eq!(inbound_topic(&command, &config).as_deref(), Some("gqgmcmqtt/F488E1234/click_sound/set"));
+        assert_eq!(inbound_topic("geiger/prod/F488E1234/click_sound/set", &config), None);
+    }
+}
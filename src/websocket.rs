@@ -0,0 +1,89 @@
+use crate::ipc::IPCMessage;
+use crate::payload::Payload;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// One reading as sent to WebSocket clients; a frame carries a cycle's worth of these.
+#[derive(Serialize)]
+struct Reading<'a> {
+    topic: &'a str,
+    state: &'a Payload,
+}
+
+/// Accepts WebSocket clients on `port` and streams every poll cycle's state batch from
+/// the broadcast channel to each of them as one JSON array.
+pub async fn serve(port: u16, bcast_tx: broadcast::Sender<IPCMessage>) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Can't listen for WebSocket clients on port {port}: {e}");
+            return;
+        }
+    };
+    info!("Streaming readings to WebSocket clients on port {port}.");
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::task::spawn(client(stream, peer, bcast_tx.subscribe()));
+            }
+            Err(e) => {
+                warn!("Couldn't accept WebSocket client: {e}");
+            }
+        }
+    }
+}
+
+async fn client(stream: TcpStream, peer: SocketAddr, mut bcast_rx: broadcast::Receiver<IPCMessage>) {
+    let ws = match accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            debug!("WebSocket handshake with {peer} failed: {e}");
+            return;
+        }
+    };
+    debug!("WebSocket client {peer} connected.");
+    let (mut sink, mut source) = ws.split();
+    loop {
+        select! {
+            msg = bcast_rx.recv() => match msg {
+                Ok(IPCMessage::Batch(batch)) => {
+                    let readings = batch
+                        .messages
+                        .iter()
+                        .map(|m| Reading { topic: &m.topic, state: &m.payload })
+                        .collect::<Vec<Reading>>();
+                    let frame = match serde_json::to_string(&readings) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            error!("Couldn't serialize readings for WebSocket clients: {e}");
+                            continue;
+                        }
+                    };
+                    if sink.send(Message::Text(frame)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(IPCMessage::Shutdown) | Err(RecvError::Closed) => {
+                    let _ = sink.send(Message::Close(None)).await;
+                    break;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client {peer} fell behind, {skipped} cycles skipped.");
+                }
+            },
+            incoming = source.next() => match incoming {
+                // clients only listen, so anything short of a close is ignored
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("WebSocket client {peer} disconnected.");
+}
@@ -0,0 +1,160 @@
+//! Shared pieces for the broker tests: an embedded rumqttd broker, a subscriber that
+//! records everything it's sent, and a way to run the gateway binary against them.
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// One message as a subscriber saw it.
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl Received {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.payload).unwrap_or_else(|e| panic!("{} isn't JSON: {e}", self.topic))
+    }
+}
+
+/// Starts a plain MQTT 3.1.1 broker on a free local port and returns the port once it's
+/// accepting connections. It runs until the test process exits.
+pub fn start_broker() -> u16 {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = format!(
+        r#"
+id: 0
+router:
+  max_connections: 10
+  max_outgoing_packet_count: 200
+  max_segment_size: 104857600
+  max_segment_count: 10
+v4:
+  "1":
+    name: v4-1
+    listen: 127.0.0.1:{port}
+    next_connection_delay_ms: 1
+    connections:
+      connection_timeout_ms: 5000
+      max_payload_size: 262144
+      max_inflight_count: 100
+"#
+    );
+    let config: rumqttd::Config = serde_yaml::from_str(&config).expect("bad broker config");
+    std::thread::spawn(move || {
+        if let Err(e) = rumqttd::Broker::new(config).start() {
+            panic!("broker stopped: {e}");
+        }
+    });
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("broker never started listening on {port}");
+}
+
+/// Subscribes to a filter and records every publish that arrives on it.
+pub struct Collector {
+    received: Arc<Mutex<Vec<Received>>>,
+    task: JoinHandle<()>,
+}
+
+impl Collector {
+    /// Returns once the broker has acknowledged the subscription, so retained messages
+    /// and anything published afterwards are sure to be recorded.
+    pub async fn subscribe(port: u16, filter: &str) -> Collector {
+        let id = format!("collector-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new(id, "127.0.0.1", port), 100);
+        client.subscribe(filter, QoS::AtLeastOnce).await.unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let (subscribed_tx, subscribed_rx) = oneshot::channel();
+        let task = tokio::spawn({
+            let received = received.clone();
+            async move {
+                // the client has to live as long as its event loop is polled
+                let _client = client;
+                let mut subscribed_tx = Some(subscribed_tx);
+                while let Ok(event) = eventloop.poll().await {
+                    match event {
+                        Event::Incoming(Packet::SubAck(_)) => {
+                            if let Some(tx) = subscribed_tx.take() {
+                                let _ = tx.send(());
+                            }
+                        }
+                        Event::Incoming(Packet::Publish(p)) => received.lock().unwrap().push(Received {
+                            topic: p.topic,
+                            payload: p.payload.to_vec(),
+                            retain: p.retain,
+                        }),
+                        _ => {}
+                    }
+                }
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), subscribed_rx)
+            .await
+            .expect("no suback from the broker")
+            .unwrap();
+        Collector { received, task }
+    }
+
+    /// Everything recorded so far.
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The newest message on `topic`, waiting up to `within` for one to arrive.
+    pub async fn wait_for(&self, topic: &str, within: Duration) -> Option<Received> {
+        let deadline = tokio::time::Instant::now() + within;
+        loop {
+            if let Some(msg) = self.received().into_iter().rev().find(|m| m.topic == topic) {
+                return Some(msg);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs the gateway with `--once` against the mock unit and the broker on `port`, with
+/// `extra` appended to its config file.
+pub async fn run_gateway_once(port: u16, extra: &str) -> Output {
+    let dir = std::env::temp_dir().join(format!("gqgmcmqtt-it-{}-{}", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config: PathBuf = dir.join("config.yaml");
+    std::fs::write(&config, format!("mqtt_server_addr: 127.0.0.1\nmqtt_server_port: {port}\nserial_port: mock\n{extra}")).unwrap();
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new(env!("CARGO_BIN_EXE_gqgmcmqtt"))
+            .arg("--config")
+            .arg(&config)
+            .arg("--once")
+            .env_remove("RUST_LOG")
+            .output()
+            .expect("couldn't run the gateway")
+    })
+    .await
+    .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(output.status.success(), "gateway failed: {}", String::from_utf8_lossy(&output.stderr));
+    output
+}
@@ -0,0 +1,68 @@
+//! The gateway against a real broker, with the mock unit. Ignored by default since they
+//! run the built binary and open local ports; run them with `cargo test -- --ignored`.
+
+mod common;
+
+use common::{run_gateway_once, start_broker, Collector};
+use std::time::Duration;
+
+const CPM_CONFIG_TOPIC: &str = "homeassistant/sensor/MOCK0001/geiger_counter_cpm/config";
+const CPM_STATE_TOPIC: &str = "gqgmcmqtt/MOCK0001/geiger_counter_cpm";
+
+#[tokio::test]
+#[ignore]
+async fn discovery_and_state_are_published() {
+    let port = start_broker();
+    let collector = Collector::subscribe(port, "#").await;
+    run_gateway_once(port, "").await;
+
+    let config = collector.wait_for(CPM_CONFIG_TOPIC, Duration::from_secs(5)).await.expect("no cpm discovery");
+    let config = config.json();
+    assert_eq!(config["unique_id"], "GMC-MOCK 1.00-MOCK0001");
+    assert_eq!(config["object_id"], "MOCK0001_geiger_tube_cpm");
+    assert_eq!(config["state_topic"], CPM_STATE_TOPIC);
+    assert_eq!(config["unit_of_measurement"], "cpm");
+    assert_eq!(config["state_class"], "measurement");
+    assert_eq!(config["device"]["identifiers"][0], "MOCK0001");
+
+    let state = collector.wait_for(CPM_STATE_TOPIC, Duration::from_secs(5)).await.expect("no cpm state");
+    let cpm = state.json()["value"].as_i64().expect("cpm isn't an integer");
+    assert!((10..=40).contains(&cpm), "mock cpm {cpm} out of range");
+
+    let usv = collector
+        .wait_for("homeassistant/sensor/MOCK0001/geiger_counter_usv/config", Duration::from_secs(5))
+        .await
+        .expect("no dose rate discovery");
+    assert_eq!(usv.json()["unit_of_measurement"], "µSv/h");
+}
+
+#[tokio::test]
+#[ignore]
+async fn discovery_is_retained_and_states_are_not() {
+    let port = start_broker();
+    run_gateway_once(port, "").await;
+
+    // a subscriber arriving after the gateway has gone only sees what the broker kept
+    let collector = Collector::subscribe(port, "#").await;
+    let config = collector.wait_for(CPM_CONFIG_TOPIC, Duration::from_secs(5)).await.expect("cpm discovery wasn't retained");
+    assert!(config.retain);
+    let received = collector.received();
+    assert!(received.iter().filter(|m| m.topic.starts_with("homeassistant/")).all(|m| m.retain));
+    assert!(received.iter().all(|m| m.topic != CPM_STATE_TOPIC), "the cpm state was retained");
+}
+
+#[tokio::test]
+#[ignore]
+async fn environment_tags_every_topic() {
+    let port = start_broker();
+    let collector = Collector::subscribe(port, "#").await;
+    run_gateway_once(port, "environment: staging\n").await;
+
+    let config = collector
+        .wait_for("homeassistant/sensor/staging_MOCK0001/geiger_counter_cpm/config", Duration::from_secs(5))
+        .await
+        .expect("no cpm discovery under the environment");
+    assert_eq!(config.json()["state_topic"], "gqgmcmqtt/staging/MOCK0001/geiger_counter_cpm");
+    assert!(collector.wait_for("gqgmcmqtt/staging/MOCK0001/geiger_counter_cpm", Duration::from_secs(5)).await.is_some());
+    assert!(collector.received().iter().all(|m| m.topic != CPM_STATE_TOPIC));
+}